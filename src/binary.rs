@@ -0,0 +1,422 @@
+//! spexをそのまま照合に使える二進形式(version 1)
+//!
+//! 数値はすべてリトルエンディアンのu32で、各部分は4バイト境界に並ぶ
+//!
+//! ```text
+//! ヘッダ   magic "SPEXDFA\0" | version | 状態数 n | 文字クラス数 k | 本体のCRC-32
+//! 本体     文字クラスの先頭の文字 k個(昇順、最初は0)
+//!          遷移表 n*k個(状態iでクラスcの文字を読んだときの行き先は i*k+c 番目)
+//!          受理状態のビット集合 (n+31)/32個(状態iはi/32番目の語のi%32ビット目)
+//! ```
+//!
+//! 状態0が開始状態。文字クラスは次のクラスの先頭の文字の直前までを表す
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::iter::FromIterator;
+
+use crate::chex::{next_char, prev_char, Chex, Range};
+use crate::spex::{Spex, Transition};
+
+const MAGIC: [u8; 8] = *b"SPEXDFA\0";
+/// 形式の版
+pub const VERSION: u32 = 1;
+const HEADER_LEN: usize = 24;
+
+/// 二進形式を読み込むときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinaryError {
+    Magic,
+    Version {
+        found: u32,
+    },
+    /// 長さがヘッダの状態数・文字クラス数と合わない
+    Length {
+        expected: usize,
+        found: usize,
+    },
+    Checksum {
+        expected: u32,
+        found: u32,
+    },
+    /// 文字クラスや遷移先が正しくない
+    Malformed {
+        message: String,
+    },
+}
+
+impl Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinaryError::Magic => write!(f, "not a spex DFA"),
+            BinaryError::Version { found } => {
+                write!(f, "unsupported version {} (expected {})", found, VERSION)
+            }
+            BinaryError::Length { expected, found } => {
+                write!(
+                    f,
+                    "length {} does not match the header ({})",
+                    found, expected
+                )
+            }
+            BinaryError::Checksum { expected, found } => write!(
+                f,
+                "checksum {:08x} does not match the header ({:08x})",
+                found, expected
+            ),
+            BinaryError::Malformed { message } => write!(f, "malformed DFA: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+impl Spex {
+    /// 二進形式に書き出す。状態はsidの順に並べ、sid 0 を先頭にする
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sids: Vec<isize> = self.tns_dict.keys().copied().collect();
+        sids.sort_unstable_by_key(|sid| (*sid != 0, *sid));
+        let index: HashMap<isize, u32> = sids
+            .iter()
+            .enumerate()
+            .map(|(i, sid)| (*sid, i as u32))
+            .collect();
+
+        // どの遷移でも同じ行き先になる文字の区間に分ける
+        let mut starts: Vec<char> = vec!['\0'];
+        for tn in self.tns_dict.values().flatten() {
+            for (start, end) in tn.chex.include_ranges() {
+                starts.push(start);
+                starts.extend(next_char(end));
+            }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+
+        let mut body: Vec<u8> = vec![];
+        for start in &starts {
+            push_u32(&mut body, *start as u32);
+        }
+        for sid in &sids {
+            for start in &starts {
+                let tn = self.tns_dict[sid]
+                    .iter()
+                    .find(|tn| tn.chex.contains(*start))
+                    .unwrap();
+                push_u32(&mut body, index[&tn.to_sid]);
+            }
+        }
+        let mut words = vec![0u32; sids.len().div_ceil(32)];
+        for sid in &self.accepts_sids {
+            let i = index[sid] as usize;
+            words[i / 32] |= 1 << (i % 32);
+        }
+        for word in words {
+            push_u32(&mut body, word);
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        push_u32(&mut bytes, VERSION);
+        push_u32(&mut bytes, sids.len() as u32);
+        push_u32(&mut bytes, starts.len() as u32);
+        push_u32(&mut bytes, crc32(&body));
+        bytes.extend(body);
+
+        bytes
+    }
+
+    /// 二進形式から読み込む。照合だけならDfaViewでそのまま使える
+    pub fn from_bytes(bytes: &[u8]) -> Result<Spex, BinaryError> {
+        Ok(DfaView::new(bytes)?.to_spex())
+    }
+}
+
+/// 二進形式を写さずに読む照合器
+#[derive(Debug, Clone, Copy)]
+pub struct DfaView<'a> {
+    bytes: &'a [u8],
+    state_count: usize,
+    class_count: usize,
+}
+
+impl<'a> DfaView<'a> {
+    /// ヘッダとチェックサムを確かめ、文字クラスと遷移先が範囲内かを確かめる
+    pub fn new(bytes: &'a [u8]) -> Result<Self, BinaryError> {
+        if bytes.len() < HEADER_LEN || bytes[..8] != MAGIC {
+            return Err(BinaryError::Magic);
+        }
+        let version = read_u32(bytes, 8);
+        if version != VERSION {
+            return Err(BinaryError::Version { found: version });
+        }
+        let state_count = read_u32(bytes, 12) as usize;
+        let class_count = read_u32(bytes, 16) as usize;
+        let expected = class_count
+            .checked_mul(state_count)
+            .and_then(|len| len.checked_add(class_count + state_count.div_ceil(32)))
+            .and_then(|len| len.checked_mul(4))
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .ok_or(BinaryError::Length {
+                expected: usize::MAX,
+                found: bytes.len(),
+            })?;
+        if bytes.len() != expected {
+            return Err(BinaryError::Length {
+                expected,
+                found: bytes.len(),
+            });
+        }
+        let checksum = read_u32(bytes, 20);
+        let found = crc32(&bytes[HEADER_LEN..]);
+        if found != checksum {
+            return Err(BinaryError::Checksum {
+                expected: checksum,
+                found,
+            });
+        }
+
+        let view = Self {
+            bytes,
+            state_count,
+            class_count,
+        };
+        let malformed = |message: &str| BinaryError::Malformed {
+            message: message.to_string(),
+        };
+        if state_count == 0 || class_count == 0 || view.class_start(0) != 0 {
+            return Err(malformed("no start state or no char classes"));
+        }
+        for class in 1..class_count {
+            let start = view.class_start(class);
+            if start <= view.class_start(class - 1) || std::char::from_u32(start).is_none() {
+                return Err(malformed("char classes are not ascending chars"));
+            }
+        }
+        for i in 0..state_count * class_count {
+            if read_u32(bytes, view.table_offset() + i * 4) as usize >= state_count {
+                return Err(malformed("transition to an unknown state"));
+            }
+        }
+
+        Ok(view)
+    }
+
+    pub fn state_count(&self) -> usize {
+        self.state_count
+    }
+
+    pub fn class_count(&self) -> usize {
+        self.class_count
+    }
+
+    pub fn is_match(&self, input: &str) -> bool {
+        let state = input
+            .chars()
+            .fold(0, |state, char| self.next(state, self.class(char)));
+
+        self.accepts(state)
+    }
+
+    /// 遷移表から組み立て直す。同じ行き先の文字クラスは一つの遷移にまとめる
+    pub fn to_spex(&self) -> Spex {
+        let mut tn_list = vec![];
+        for state in 0..self.state_count {
+            let mut targets: Vec<(usize, Vec<Range>)> = vec![];
+            for class in 0..self.class_count {
+                let to_state = self.next(state, class);
+                match targets.iter_mut().find(|(target, _)| *target == to_state) {
+                    Some((_, ranges)) => ranges.push(self.class_range(class)),
+                    None => targets.push((to_state, vec![self.class_range(class)])),
+                }
+            }
+            for (to_state, ranges) in targets {
+                tn_list.push(Transition::new(
+                    state as isize,
+                    to_state as isize,
+                    Chex::from_ranges(ranges, true),
+                ));
+            }
+        }
+        let accepts = (0..self.state_count).filter(|state| self.accepts(*state));
+
+        // 到達できない状態が残るとkindが正しく決まらない
+        Spex::new(
+            tn_list,
+            HashSet::from_iter(accepts.map(|state| state as isize)),
+        )
+        .trim()
+    }
+
+    fn table_offset(&self) -> usize {
+        HEADER_LEN + self.class_count * 4
+    }
+
+    fn class_start(&self, class: usize) -> u32 {
+        read_u32(self.bytes, HEADER_LEN + class * 4)
+    }
+
+    /// クラスの先頭の文字を二分探索する
+    fn class(&self, char: char) -> usize {
+        let (mut lo, mut hi) = (0, self.class_count);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.class_start(mid) <= char as u32 {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo
+    }
+
+    fn class_range(&self, class: usize) -> Range {
+        let start = std::char::from_u32(self.class_start(class)).unwrap();
+        let end = if class + 1 < self.class_count {
+            let next = std::char::from_u32(self.class_start(class + 1)).unwrap();
+            prev_char(next).unwrap()
+        } else {
+            char::MAX
+        };
+
+        (start, end)
+    }
+
+    fn next(&self, state: usize, class: usize) -> usize {
+        read_u32(
+            self.bytes,
+            self.table_offset() + (state * self.class_count + class) * 4,
+        ) as usize
+    }
+
+    fn accepts(&self, state: usize) -> bool {
+        let offset = self.table_offset() + self.state_count * self.class_count * 4;
+        read_u32(self.bytes, offset + state / 32 * 4) & (1 << (state % 32)) != 0
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, n: u32) {
+    bytes.extend_from_slice(&n.to_le_bytes());
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// CRC-32 (IEEE 802.3)
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::spex;
+
+    #[test]
+    fn binary_round_trip() {
+        for src in [
+            "(a+|ab)&!a",
+            "[^a-z]*\\d",
+            "!(.*foo.*)",
+            "[\u{d7ff}\u{e000}]+",
+            "!.+",
+            "a&b",
+            ".*",
+        ] {
+            let spex1 = spex(src);
+            let bytes = spex1.to_bytes();
+            assert_eq!(Spex::from_bytes(&bytes).unwrap(), spex1, "{}", src);
+
+            let view = DfaView::new(&bytes).unwrap();
+            for input in [
+                "",
+                "a",
+                "ab",
+                "aab",
+                "xfooy",
+                "12",
+                "\u{d7ff}\u{e000}",
+                "z9",
+            ] {
+                assert_eq!(
+                    view.is_match(input),
+                    spex1.is_match(input),
+                    "{} {}",
+                    src,
+                    input
+                );
+            }
+        }
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn binary_layout() {
+        let bytes = spex("a").minimize().to_bytes();
+        let view = DfaView::new(&bytes).unwrap();
+        assert_eq!(&bytes[..8], b"SPEXDFA\0");
+        assert_eq!(view.state_count(), 3);
+        assert_eq!(view.class_count(), 3);
+        assert_eq!(bytes.len(), 24 + (3 + 3 * 3 + 1) * 4);
+    }
+
+    #[test]
+    fn binary_errors() {
+        let bytes = spex("ab|c").to_bytes();
+        assert_eq!(Spex::from_bytes(b"PNG"), Err(BinaryError::Magic));
+
+        let mut version = bytes.clone();
+        version[8] = 2;
+        assert_eq!(
+            Spex::from_bytes(&version),
+            Err(BinaryError::Version { found: 2 })
+        );
+
+        let truncated = &bytes[..bytes.len() - 4];
+        assert_eq!(
+            Spex::from_bytes(truncated),
+            Err(BinaryError::Length {
+                expected: bytes.len(),
+                found: bytes.len() - 4
+            })
+        );
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Spex::from_bytes(&flipped),
+            Err(BinaryError::Checksum { .. })
+        ));
+
+        // チェックサムを合わせても、存在しない状態への遷移は読み込まない
+        let mut unknown = bytes.clone();
+        let offset = HEADER_LEN + read_u32(&bytes, 16) as usize * 4;
+        unknown[offset..offset + 4].copy_from_slice(&99u32.to_le_bytes());
+        let checksum = crc32(&unknown[HEADER_LEN..]);
+        unknown[20..24].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            Spex::from_bytes(&unknown).unwrap_err().to_string(),
+            "malformed DFA: transition to an unknown state"
+        );
+
+        // 到達できない状態は読み込むときに取り除く
+        let unreachable = Spex::new(
+            vec![
+                Transition::new(0, 0, Chex::new_whole()),
+                Transition::new(1, 1, Chex::new_whole()),
+            ],
+            HashSet::from([0]),
+        );
+        assert!(Spex::from_bytes(&unreachable.to_bytes()).unwrap() == spex(".*"));
+    }
+}
@@ -1,30 +1,237 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
 use crate::chex::Chex;
+use crate::lookaround::build_lookaround;
+use crate::optimizer::optimize;
+use crate::parser::{parse, tokenize, tokenize_with, try_parse, Node, ParseError, Span};
 use crate::spex::Spex;
-use crate::token;
-use crate::{parser::parse, parser::tokenize, parser::Node};
+use crate::token::Dialect;
+
+/// spexの組み立て方の指定
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// 空白を無視し、`#` から行末までをコメントとして扱う
+    pub verbose: bool,
+    /// 大文字・小文字を区別しない。式の中では `(?i)` `(?i:X)` で指定する
+    pub case_insensitive: bool,
+    /// 構文に使う文字の表
+    pub dialect: Dialect,
+}
+
+/// 信頼できない入力から組み立てるときの上限
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// 括弧の入れ子と演算子の連なりの深さ
+    pub max_depth: usize,
+    /// トークンの数
+    pub max_tokens: usize,
+    /// 一回の構成で作るオートマトンの状態数
+    pub max_states: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_tokens: 4096,
+            max_states: 100_000,
+        }
+    }
+}
+
+/// 上限を超えたときと、構文エラーのときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitError {
+    Syntax { message: String },
+    Depth { limit: usize, span: Span },
+    Tokens { limit: usize, count: usize },
+    States { limit: usize },
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::Syntax { message } => f.write_str(message),
+            LimitError::Depth { limit, span } => write!(
+                f,
+                "{}..{}: nesting deeper than {}",
+                span.start, span.end, limit
+            ),
+            LimitError::Tokens { limit, count } => {
+                write!(f, "{} tokens exceed the limit {}", count, limit)
+            }
+            LimitError::States { limit } => write!(f, "automaton exceeds {} states", limit),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl From<ParseError> for LimitError {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::Syntax { message } => LimitError::Syntax { message },
+            ParseError::Depth { limit, span } => LimitError::Depth { limit, span },
+        }
+    }
+}
 
 pub fn spex(spex_str: impl Into<String>) -> Spex {
-    build_spex(parse(&tokenize(spex_str)))
+    build_spex(optimize(&parse(&tokenize(spex_str))).0)
+}
+
+pub fn spex_with(spex_str: impl Into<String>, options: &Options) -> Spex {
+    let tokens = tokenize_with(spex_str, options.verbose, &options.dialect);
+
+    let node = parse(&tokens);
+    let node = if options.case_insensitive {
+        node.case_fold()
+    } else {
+        node
+    };
+    build_spex(optimize(&node).0)
+}
+
+/// spex_withと同じだが、構文エラーとlimitsを超える入力はパースや組み立ての途中でやめてエラーにする
+pub fn try_spex_with(
+    spex_str: impl Into<String>,
+    options: &Options,
+    limits: &Limits,
+) -> Result<Spex, LimitError> {
+    let tokens = tokenize_with(spex_str, options.verbose, &options.dialect);
+    if tokens.len() > limits.max_tokens {
+        return Err(LimitError::Tokens {
+            limit: limits.max_tokens,
+            count: tokens.len(),
+        });
+    }
+
+    let node = try_parse(&tokens, limits.max_depth)?;
+    let node = if options.case_insensitive {
+        node.case_fold()
+    } else {
+        node
+    };
+    build_within(optimize(&node).0, &HashMap::new(), Some(limits.max_states))
 }
 
 pub fn build_spex(parsed: Node) -> Spex {
-    match parsed {
-        Node::IncChex { ref tokens } => Spex::build_by_chex(&Chex::new(tokens.clone(), true)),
-        Node::ExcChex { ref tokens } => Spex::build_by_chex(&Chex::new(tokens.clone(), false)),
+    build_spex_with_env(parsed, &HashMap::new())
+}
+
+/// envの定義を参照しながら組み立てる
+pub fn build_spex_with_env(parsed: Node, env: &HashMap<String, Spex>) -> Spex {
+    build_within(parsed, env, None).unwrap_or_else(|error| panic!("SyntaxError {}", error))
+}
+
+/// build_spex_with_envと同じだが、一回の構成で作る状態数がlimitに達したらやめてエラーにする
+/// 定義されていない参照もエラーにする
+pub(crate) fn build_within(
+    parsed: Node,
+    env: &HashMap<String, Spex>,
+    limit: Option<usize>,
+) -> Result<Spex, LimitError> {
+    let within = |spex: Option<Spex>| {
+        spex.ok_or(LimitError::States {
+            limit: limit.unwrap_or(usize::MAX),
+        })
+    };
+    let build = |node: &Node| build_within(node.clone(), env, limit);
+
+    Ok(match parsed {
+        Node::Lookaround { .. } => build_lookaround(&parsed, env, limit)?,
+        Node::Or { .. }
+        | Node::Concat { .. }
+        | Node::Repeat { .. }
+        | Node::Optional { .. }
+        | Node::Star { .. }
+        | Node::Bounded { .. }
+            if parsed.has_lookaround() =>
+        {
+            build_lookaround(&parsed, env, limit)?
+        }
+        Node::IncChex { ref ranges, .. } => {
+            Spex::build_by_chex(&Chex::from_ranges(ranges.clone(), true))
+        }
+        Node::ExcChex { ref ranges, .. } => {
+            Spex::build_by_chex(&Chex::from_ranges(ranges.clone(), false))
+        }
         Node::Or {
             ref left,
             ref right,
-        } => &build_spex(left.as_ref().clone()) | &build_spex(right.as_ref().clone()),
+            ..
+        } => {
+            let (left, right) = (build(left)?, build(right)?);
+            shrink(within(left.or_within(&right, limit))?, &[&left, &right])
+        }
         Node::And {
             ref left,
             ref right,
-        } => &build_spex(left.as_ref().clone()) & &build_spex(right.as_ref().clone()),
-        Node::Invert { ref node } => !&build_spex(node.as_ref().clone()),
-        Node::Repeat { ref node } => build_spex(node.as_ref().clone()).repeat(),
-        Node::Concat { ref nodes } => nodes
-            .into_iter()
-            .map(|node| build_spex(node.clone()))
-            .reduce(|a, b| a.concat(&b))
-            .unwrap(),
+            ..
+        } => {
+            let (left, right) = (build(left)?, build(right)?);
+            shrink(within(left.and_within(&right, limit))?, &[&left, &right])
+        }
+        Node::Invert { ref node, .. } => !&build(node)?,
+        Node::Containing { ref node, .. } => within(Spex::containing_within(&build(node)?, limit))?,
+        Node::StartingWith { ref node, .. } => Spex::starting_with(&build(node)?),
+        Node::EndingWith { ref node, .. } => {
+            within(Spex::ending_with_within(&build(node)?, limit))?
+        }
+        Node::Repeat { ref node, .. } => {
+            let spex = build(node)?;
+            shrink(within(spex.repeat_within(limit))?, &[&spex])
+        }
+        Node::Optional { ref node, .. } => {
+            let spex = build(node)?;
+            shrink(
+                within(Spex::empty_string().or_within(&spex, limit))?,
+                &[&spex],
+            )
+        }
+        Node::Star { ref node, .. } => {
+            let spex = build(node)?;
+            shrink(within(spex.star_within(limit))?, &[&spex])
+        }
+        Node::Bounded {
+            ref node, min, max, ..
+        } => within(build(node)?.bounded_within(min, max, limit))?,
+        Node::Concat { ref nodes, .. } => {
+            let mut spexes = nodes.iter().map(build);
+            let mut ret = spexes.next().unwrap()?;
+            for spex in spexes {
+                let spex = spex?;
+                ret = shrink(within(ret.concat_within(&spex, limit))?, &[&ret, &spex]);
+            }
+            ret
+        }
+        Node::Let { defs, body, .. } => {
+            // 各定義は一度だけ組み立てて使い回す
+            let mut env = env.clone();
+            for (name, node) in defs {
+                let spex = build_within(node, &env, limit)?;
+                env.insert(name, spex);
+            }
+            build_within(*body, &env, limit)?
+        }
+        Node::Ref { ref name, .. } => env
+            .get(name)
+            .ok_or_else(|| LimitError::Syntax {
+                message: format!("{} undefined", name),
+            })?
+            .clone(),
+    })
+}
+
+/// 材料より状態が増えたときだけ最小化する
+/// 決定化で膨らんだ状態を次の構成に持ち越さないためで、
+/// 回数指定のように一直線に長いだけのものは最小化が遅いのでそのまま使う
+pub(crate) fn shrink(spex: Spex, parts: &[&Spex]) -> Spex {
+    let len: usize = parts.iter().map(|part| part.tns_dict.len()).sum();
+    if spex.tns_dict.len() > len + 1 {
+        spex.minimize()
+    } else {
+        spex
     }
 }
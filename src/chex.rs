@@ -1,9 +1,13 @@
-use std::collections::HashSet;
-use std::fmt::{self, Display, Formatter};
+use std::fmt::{self, Display};
 use std::ops::{BitAnd, BitOr, Not};
 
+use regex_syntax::hir::{Class, ClassUnicode, ClassUnicodeRange, HirKind};
+
 use crate::token::Token;
 
+pub type Range = (char, char);
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Kind {
     Blank,
@@ -15,15 +19,38 @@ pub enum Kind {
 pub struct Chex {
     pub kind: Kind,
     pub len: usize,
-    pub include_flg: bool, // falseなら補集合
-    pub char_set: HashSet<String>,
+    pub include_flg: bool,  // falseなら補集合
+    pub ranges: Vec<Range>, // 昇順・重複なし・隣接なし
     pub str: String,
 }
 
 impl Chex {
-    pub fn new(chars: Vec<String>, include_flg: bool) -> Self {
-        let char_set = HashSet::from(chars.into_iter().collect());
-        let len = char_set.len();
+    pub fn new(chars: Vec<char>, include_flg: bool) -> Self {
+        Self::from_ranges(
+            chars.into_iter().map(|char| (char, char)).collect(),
+            include_flg,
+        )
+    }
+
+    pub fn from_ranges(ranges: Vec<Range>, include_flg: bool) -> Self {
+        let mut ranges = normalize(ranges);
+        let mut include_flg = include_flg;
+        if ranges == [('\0', char::MAX)] {
+            // 全ての文字を含む集合は全集合・空集合として扱う
+            ranges = vec![];
+            include_flg = !include_flg;
+        }
+        let len = ranges
+            .iter()
+            .map(|&(start, end)| {
+                let len = end as usize - start as usize + 1;
+                if start <= '\u{d7ff}' && '\u{e000}' <= end {
+                    len - 0x800 // サロゲート領域
+                } else {
+                    len
+                }
+            })
+            .sum();
         let kind = if len == 0 {
             if include_flg {
                 Kind::Blank // 空集合
@@ -36,31 +63,42 @@ impl Chex {
 
         let str = match kind {
             Kind::Blank => format!("{}{}", Token::CH_S.value(), Token::CH_E.value()),
-            Kind::Whole => format!("{}", Token::WHOL.value()),
+            Kind::Whole => Token::WHOL.value().to_string(),
             Kind::Other => {
-                let mut vec_char = char_set.clone().into_iter().collect::<Vec<String>>();
-                vec_char.sort();
-                let joind_chars: String = vec_char.into_iter().collect();
+                let joind_chars: String = ranges
+                    .iter()
+                    .map(|&(start, end)| {
+                        if end as u32 - start as u32 <= 3 {
+                            // 4文字以下の範囲は列挙する
+                            (start..=end).map(Token::escape).collect()
+                        } else {
+                            format!(
+                                "{}{}{}",
+                                Token::escape(start),
+                                Token::RANGE.value(),
+                                Token::escape(end)
+                            )
+                        }
+                    })
+                    .collect();
 
                 if include_flg && len == 1 {
-                    format!("{}", joind_chars)
+                    joind_chars
+                } else if include_flg {
+                    format!(
+                        "{}{}{}",
+                        Token::CH_S.value(),
+                        joind_chars,
+                        Token::CH_E.value()
+                    )
                 } else {
-                    if include_flg {
-                        format!(
-                            "{}{}{}",
-                            Token::CH_S.value(),
-                            joind_chars,
-                            Token::CH_E.value()
-                        )
-                    } else {
-                        format!(
-                            "{}{}{}{}",
-                            Token::CH_S.value(),
-                            Token::DENY.value(),
-                            joind_chars,
-                            Token::CH_E.value()
-                        )
-                    }
+                    format!(
+                        "{}{}{}{}",
+                        Token::CH_S.value(),
+                        Token::DENY.value(),
+                        joind_chars,
+                        Token::CH_E.value()
+                    )
                 }
             }
         };
@@ -68,8 +106,8 @@ impl Chex {
         Self {
             kind,
             len,
-            char_set,
             include_flg,
+            ranges,
             str,
         }
     }
@@ -82,17 +120,130 @@ impl Chex {
         Self::new(vec![], false)
     }
 
-    pub fn blank(&self) -> bool {
-        match self.kind {
-            Kind::Blank => true,
-            _ => false,
+    /// `\d` `\w` `\s` とその否定 `\D` `\W` `\S`
+    pub fn shorthand(name: char) -> Option<Self> {
+        let ranges = match name.to_ascii_lowercase() {
+            'd' => vec![('0', '9')],
+            'w' => vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+            's' => vec![('\t', '\r'), (' ', ' ')],
+            _ => return None,
+        };
+
+        Some(Self::from_ranges(ranges, name.is_ascii_lowercase()))
+    }
+
+    /// `[:alpha:]` などのPOSIX文字クラス
+    pub fn posix(name: &str) -> Option<Self> {
+        let ranges = match name {
+            "alnum" => vec![('0', '9'), ('A', 'Z'), ('a', 'z')],
+            "alpha" => vec![('A', 'Z'), ('a', 'z')],
+            "ascii" => vec![('\0', '\x7f')],
+            "blank" => vec![('\t', '\t'), (' ', ' ')],
+            "cntrl" => vec![('\0', '\x1f'), ('\x7f', '\x7f')],
+            "digit" => vec![('0', '9')],
+            "graph" => vec![('!', '~')],
+            "lower" => vec![('a', 'z')],
+            "print" => vec![(' ', '~')],
+            "punct" => vec![('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+            "space" => vec![('\t', '\r'), (' ', ' ')],
+            "upper" => vec![('A', 'Z')],
+            "word" => vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+            "xdigit" => vec![('0', '9'), ('A', 'F'), ('a', 'f')],
+            _ => return None,
+        };
+
+        Some(Self::from_ranges(ranges, true))
+    }
+
+    /// `\p{L}` などのUnicode一般カテゴリ・スクリプト
+    pub fn unicode(name: &str) -> Option<Self> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || "_-= ".contains(char))
+        {
+            return None;
+        }
+        let hir = regex_syntax::Parser::new()
+            .parse(&format!("\\p{{{}}}", name))
+            .ok()?;
+
+        match hir.kind() {
+            HirKind::Class(Class::Unicode(class)) => Some(Self::from_ranges(
+                class
+                    .ranges()
+                    .iter()
+                    .map(|range| (range.start(), range.end()))
+                    .collect(),
+                true,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Unicodeの単純ケースフォールディングで大文字・小文字を同一視した集合
+    /// 補集合は除く文字の側を広げるので `[^a]` は `a` も `A` も含まない
+    pub fn case_fold(&self) -> Self {
+        if self.kind != Kind::Other {
+            return self.clone();
         }
+        let mut class = ClassUnicode::new(
+            self.ranges
+                .iter()
+                .map(|&(start, end)| ClassUnicodeRange::new(start, end)),
+        );
+        class.case_fold_simple();
+
+        Self::from_ranges(
+            class
+                .ranges()
+                .iter()
+                .map(|range| (range.start(), range.end()))
+                .collect(),
+            self.include_flg,
+        )
+    }
+
+    pub fn blank(&self) -> bool {
+        matches!(self.kind, Kind::Blank)
     }
 
     pub fn whole(&self) -> bool {
-        match self.kind {
-            Kind::Whole => true,
-            _ => false,
+        matches!(self.kind, Kind::Whole)
+    }
+
+    pub fn contains(&self, char: char) -> bool {
+        let found = self
+            .ranges
+            .binary_search_by(|&(start, end)| {
+                if end < char {
+                    std::cmp::Ordering::Less
+                } else if char < start {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok();
+
+        found == self.include_flg
+    }
+
+    /// 含まれる文字を一つ返す。表示できるASCII文字があればそれを選ぶ
+    pub fn sample(&self) -> Option<char> {
+        let ranges = self.include_ranges();
+        intersection(&ranges, &[('!', '~')])
+            .first()
+            .or_else(|| ranges.first())
+            .map(|&(start, _)| start)
+    }
+
+    /// 含まれる文字を範囲で返す
+    pub fn include_ranges(&self) -> Vec<Range> {
+        if self.include_flg {
+            self.ranges.clone()
+        } else {
+            complement(&self.ranges)
         }
     }
 
@@ -103,13 +254,7 @@ impl Chex {
             Kind::Other => match other.kind {
                 Kind::Blank => true,
                 Kind::Whole => false,
-                Kind::Other => {
-                    if self.include_flg && !other.include_flg {
-                        false
-                    } else {
-                        (&!self & other).blank()
-                    }
-                }
+                Kind::Other => (&!self & other).blank(),
             },
         }
     }
@@ -117,7 +262,7 @@ impl Chex {
 
 impl Display for Chex {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(&format!("{}", self.str))?;
+        f.write_str(&self.str)?;
         Ok(())
     }
 }
@@ -128,11 +273,8 @@ impl Not for &Chex {
     fn not(self) -> Self::Output {
         match self.kind {
             Kind::Blank => Chex::new_whole(),
-            Kind::Whole => Chex::new_whole(),
-            Kind::Other => Chex::new(
-                self.char_set.clone().into_iter().collect(),
-                !self.include_flg,
-            ),
+            Kind::Whole => Chex::new_blank(),
+            Kind::Other => Chex::from_ranges(self.ranges.clone(), !self.include_flg),
         }
     }
 }
@@ -141,12 +283,14 @@ impl PartialEq for Chex {
     fn eq(&self, other: &Self) -> bool {
         if self.kind == other.kind {
             if self.kind != Kind::Other {
-                return true;
+                true
+            } else if self.include_flg == other.include_flg {
+                self.ranges == other.ranges
             } else {
-                return self.str == other.str;
+                self.include_ranges() == other.include_ranges()
             }
         } else {
-            return false;
+            false
         }
     }
 }
@@ -164,26 +308,14 @@ impl BitOr for &Chex {
                 Kind::Other => {
                     if self.include_flg {
                         if other.include_flg {
-                            let chars = self.char_set.union(&other.char_set).cloned().collect();
-                            return Chex::new(chars, true);
+                            Chex::from_ranges(union(&self.ranges, &other.ranges), true)
                         } else {
-                            let chars =
-                                other.char_set.difference(&self.char_set).cloned().collect();
-                            return Chex::new(chars, false);
+                            Chex::from_ranges(difference(&other.ranges, &self.ranges), false)
                         }
+                    } else if other.include_flg {
+                        Chex::from_ranges(difference(&self.ranges, &other.ranges), false)
                     } else {
-                        if other.include_flg {
-                            let chars =
-                                self.char_set.difference(&other.char_set).cloned().collect();
-                            return Chex::new(chars, false);
-                        } else {
-                            let chars = self
-                                .char_set
-                                .intersection(&other.char_set)
-                                .cloned()
-                                .collect();
-                            return Chex::new(chars, true);
-                        }
+                        Chex::from_ranges(intersection(&self.ranges, &other.ranges), false)
                     }
                 }
             },
@@ -204,26 +336,14 @@ impl BitAnd for &Chex {
                 Kind::Other => {
                     if self.include_flg {
                         if other.include_flg {
-                            let chars = self
-                                .char_set
-                                .intersection(&other.char_set)
-                                .cloned()
-                                .collect();
-                            return Chex::new(chars, true);
+                            Chex::from_ranges(intersection(&self.ranges, &other.ranges), true)
                         } else {
-                            let chars =
-                                self.char_set.difference(&other.char_set).cloned().collect();
-                            return Chex::new(chars, true);
+                            Chex::from_ranges(difference(&self.ranges, &other.ranges), true)
                         }
+                    } else if other.include_flg {
+                        Chex::from_ranges(difference(&other.ranges, &self.ranges), true)
                     } else {
-                        if other.include_flg {
-                            let chars =
-                                other.char_set.difference(&self.char_set).cloned().collect();
-                            return Chex::new(chars, true);
-                        } else {
-                            let chars = other.char_set.union(&self.char_set).cloned().collect();
-                            return Chex::new(chars, false);
-                        }
+                        Chex::from_ranges(union(&self.ranges, &other.ranges), false)
                     }
                 }
             },
@@ -231,7 +351,89 @@ impl BitAnd for &Chex {
     }
 }
 
+/// サロゲート領域を飛ばした次の文字
+pub fn next_char(char: char) -> Option<char> {
+    match char {
+        '\u{d7ff}' => Some('\u{e000}'),
+        _ => std::char::from_u32(char as u32 + 1),
+    }
+}
+
+/// サロゲート領域を飛ばした前の文字
+pub fn prev_char(char: char) -> Option<char> {
+    match char {
+        '\0' => None,
+        '\u{e000}' => Some('\u{d7ff}'),
+        _ => std::char::from_u32(char as u32 - 1),
+    }
+}
+
+fn normalize(mut ranges: Vec<Range>) -> Vec<Range> {
+    ranges.retain(|(start, end)| start <= end);
+    ranges.sort_unstable();
+
+    let mut ret: Vec<Range> = vec![];
+    for (start, end) in ranges {
+        match ret.last_mut() {
+            Some(last) if next_char(last.1).is_none_or(|next| start <= next) => {
+                if last.1 < end {
+                    last.1 = end;
+                }
+            }
+            _ => ret.push((start, end)),
+        }
+    }
+
+    ret
+}
+
+fn union(a: &[Range], b: &[Range]) -> Vec<Range> {
+    normalize(a.iter().chain(b.iter()).cloned().collect())
+}
+
+fn intersection(a: &[Range], b: &[Range]) -> Vec<Range> {
+    let mut ret = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = a[i].0.max(b[j].0);
+        let end = a[i].1.min(b[j].1);
+        if start <= end {
+            ret.push((start, end));
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    ret
+}
+
+fn difference(a: &[Range], b: &[Range]) -> Vec<Range> {
+    intersection(a, &complement(b))
+}
+
+fn complement(ranges: &[Range]) -> Vec<Range> {
+    let mut ret = vec![];
+    let mut start = Some('\0');
+    for &(range_start, range_end) in ranges {
+        if let Some(char) = start {
+            if char < range_start {
+                ret.push((char, prev_char(range_start).unwrap()));
+            }
+        }
+        start = next_char(range_end);
+    }
+    if let Some(char) = start {
+        ret.push((char, char::MAX));
+    }
+
+    ret
+}
+
 #[cfg(test)]
+#[allow(clippy::toplevel_ref_arg)]
 mod chex_tests {
     use super::*;
 
@@ -249,122 +451,127 @@ mod chex_tests {
 
     #[test]
     fn chex() {
-        let chex = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
+        let chex = Chex::new(vec!['a', 'c', 'b'], true);
         assert_eq!("[abc]", chex.str);
     }
 
     #[test]
     fn invert_chex() {
-        let chex = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            false,
-        );
+        let chex = Chex::new(vec!['a', 'c', 'b'], false);
         assert_eq!("[^abc]", chex.str);
     }
 
     #[test]
     fn not_chex() {
-        let ref chex = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
+        let ref chex = Chex::new(vec!['a', 'c', 'b'], true);
         assert_eq!("[^abc]", (!chex).str);
     }
 
     #[test]
     fn not_invert_chex() {
-        let ref invert_chex = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            false,
-        );
+        let ref invert_chex = Chex::new(vec!['a', 'c', 'b'], false);
         assert_eq!("[abc]", (!invert_chex).str);
     }
 
     #[test]
     fn intersection_chex() {
-        let ref a = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
-        let ref b = Chex::new(
-            vec!['a', 'd', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
+        let ref a = Chex::new(vec!['a', 'c', 'b'], true);
+        let ref b = Chex::new(vec!['a', 'd', 'b'], true);
         assert_eq!("[ab]", (a & b).str);
     }
 
     #[test]
     fn union_chex() {
-        let ref a = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
-        let ref b = Chex::new(
-            vec!['a', 'd']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
+        let ref a = Chex::new(vec!['a', 'c', 'b'], true);
+        let ref b = Chex::new(vec!['a', 'd'], true);
         assert_eq!("[abcd]", (a | b).str);
     }
 
     #[test]
     fn not_intersection_chex() {
-        let ref a = Chex::new(
-            vec!['a'].iter_mut().map(|char| char.to_string()).collect(),
-            false,
-        );
-        let ref b = Chex::new(
-            vec!['a', 'd', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
+        let ref a = Chex::new(vec!['a'], false);
+        let ref b = Chex::new(vec!['a', 'd', 'b'], true);
         assert_eq!("[bd]", (a & b).str);
     }
 
     #[test]
     fn not_union_chex() {
-        let ref a = Chex::new(
-            vec!['a', 'c', 'b']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            false,
-        );
-        let ref b = Chex::new(
-            vec!['a', 'd']
-                .iter_mut()
-                .map(|char| char.to_string())
-                .collect(),
-            true,
-        );
+        let ref a = Chex::new(vec!['a', 'c', 'b'], false);
+        let ref b = Chex::new(vec!['a', 'd'], true);
         assert_eq!("[^bc]", (a | b).str);
     }
+
+    #[test]
+    fn escaped_chex() {
+        let chex = Chex::new(vec!['[', '\\', 'a'], true);
+        assert_eq!("[\\[\\\\a]", chex.str);
+        assert!(chex.contains('['));
+        assert!(chex.contains('\\'));
+        assert!(!chex.contains('b'));
+    }
+
+    #[test]
+    fn range_chex() {
+        let chex = Chex::from_ranges(vec![('a', 'c'), ('x', 'y'), ('d', 'f')], true);
+        assert_eq!("[a-fxy]", chex.str);
+        assert_eq!(8, chex.len);
+        assert!(chex.contains('e'));
+        assert!(!chex.contains('g'));
+    }
+
+    #[test]
+    fn range_chex_whole() {
+        let chex = &Chex::from_ranges(vec![('\0', 'm'), ('n', char::MAX)], true);
+        assert!(chex.whole());
+        assert!((!chex).blank());
+        let digit = &Chex::shorthand('d').unwrap();
+        assert!((digit | &!digit).whole());
+        assert!((digit & &!digit).blank());
+    }
+
+    #[test]
+    fn shorthand_chex() {
+        let digit = &Chex::shorthand('d').unwrap();
+        let not_digit = &Chex::shorthand('D').unwrap();
+        assert_eq!("[0-9]", digit.str);
+        assert_eq!("[^0-9]", not_digit.str);
+        assert!(digit == &!not_digit);
+        assert!(Chex::shorthand('w').unwrap().include(digit));
+        assert!(Chex::shorthand('s').unwrap().contains('\n'));
+        assert!(Chex::shorthand('x').is_none());
+    }
+
+    #[test]
+    fn posix_chex() {
+        assert_eq!(Chex::posix("digit").unwrap(), Chex::shorthand('d').unwrap());
+        assert_eq!("[0-9A-Fa-f]", Chex::posix("xdigit").unwrap().str);
+        assert!(Chex::posix("unknown").is_none());
+    }
+
+    #[test]
+    fn unicode_chex() {
+        let letter = &Chex::unicode("L").unwrap();
+        assert!(letter.contains('a'));
+        assert!(letter.contains('あ'));
+        assert!(!letter.contains('1'));
+        assert!(letter.include(&Chex::unicode("Lu").unwrap()));
+        assert!(letter.include(&Chex::posix("alpha").unwrap()));
+        assert!(Chex::unicode("Unknown").is_none());
+        assert!(Chex::unicode("L}|x").is_none());
+    }
+
+    #[test]
+    fn case_fold_chex() {
+        assert_eq!(
+            Chex::new(vec!['a', 'B'], true).case_fold().to_string(),
+            "[ABab]"
+        );
+        assert!(Chex::new(vec!['k'], true).case_fold().contains('\u{212a}'));
+        let chex = Chex::new(vec!['a'], false).case_fold();
+        assert!(!chex.contains('a'));
+        assert!(!chex.contains('A'));
+        assert!(chex.contains('b'));
+        assert!(Chex::shorthand('d').unwrap().case_fold() == Chex::shorthand('d').unwrap());
+        assert!(Chex::new_whole().case_fold().whole());
+    }
 }
@@ -0,0 +1,266 @@
+use crate::builder::build_spex;
+use crate::chex::Chex;
+use crate::parser::{
+    get_concat_node, get_empty_node, get_exc_chex, get_inc_chex, get_optional_node, get_or_node,
+    get_star_node, Node, Span,
+};
+use crate::spex::Spex;
+
+/// パスの区切り文字
+const SEPARATOR: char = '/';
+
+impl Spex {
+    /// パス全体に一致するglobをspexにする
+    pub fn from_glob(glob: &str) -> Spex {
+        build_spex(parse_glob(glob))
+    }
+}
+
+/// globをNodeにする
+/// `*` `?` `[...]` `[!...]` は区切りを越えない
+/// 区切りの間の `**` は0個以上のディレクトリに、末尾の `**` はその下のすべてに一致する
+/// `{a,b}` はどちらか、`\` は次の文字そのもの。閉じていない `[` `{` は文字として扱う
+pub fn parse_glob(glob: &str) -> Node {
+    let mut parser = GlobParser {
+        chars: glob.chars().collect(),
+        pos: 0,
+    };
+    parser.parse_seq(false)
+}
+
+struct GlobParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl GlobParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// in_braceなら `,` `}` の手前まで読む
+    fn parse_seq(&mut self, in_brace: bool) -> Node {
+        let start = self.pos;
+        let mut nodes: Vec<Node> = vec![];
+        while let Some(char) = self.peek() {
+            let node = match char {
+                ',' | '}' if in_brace => break,
+                '*' => self.parse_stars(),
+                '?' => {
+                    self.pos += 1;
+                    get_exc_chex(&[(SEPARATOR, SEPARATOR)], Span::new(self.pos - 1, self.pos))
+                }
+                '[' => self.parse_class(),
+                '{' => self.parse_brace(),
+                '\\' if self.pos + 1 < self.chars.len() => self.literal(2),
+                _ => self.literal(1),
+            };
+            nodes.push(node);
+        }
+
+        match nodes.len() {
+            0 => get_empty_node(Span::new(start, self.pos)),
+            1 => nodes.pop().unwrap(),
+            _ => get_concat_node(nodes),
+        }
+    }
+
+    /// len文字を読み、最後の文字そのものに一致させる
+    fn literal(&mut self, len: usize) -> Node {
+        let char = self.chars[self.pos + len - 1];
+        let span = Span::new(self.pos, self.pos + len);
+        self.pos += len;
+        get_inc_chex(&[(char, char)], span)
+    }
+
+    fn parse_stars(&mut self) -> Node {
+        let start = self.pos;
+        while self.peek() == Some('*') {
+            self.pos += 1;
+        }
+        let span = Span::new(start, self.pos);
+        let segment = start == 0 || self.chars[start - 1] == SEPARATOR;
+        if self.pos - start == 1 || !segment {
+            return get_star_node(get_exc_chex(&[(SEPARATOR, SEPARATOR)], span), span);
+        }
+
+        match self.peek() {
+            None => get_star_node(get_exc_chex(&[], span), span),
+            // `**/` は (.*/)?
+            Some(SEPARATOR) => {
+                self.pos += 1;
+                let span = Span::new(start, self.pos);
+                let dirs = get_concat_node(vec![
+                    get_star_node(get_exc_chex(&[], span), span),
+                    get_inc_chex(&[(SEPARATOR, SEPARATOR)], span),
+                ]);
+                get_optional_node(dirs, span)
+            }
+            // 区切りで終わらない `**` は `*` と同じ
+            _ => get_star_node(get_exc_chex(&[(SEPARATOR, SEPARATOR)], span), span),
+        }
+    }
+
+    fn parse_class(&mut self) -> Node {
+        let start = self.pos;
+        let mut pos = start + 1;
+        let negated = matches!(self.chars.get(pos), Some('!') | Some('^'));
+        if negated {
+            pos += 1;
+        }
+        let mut chex = Chex::new_blank();
+        let mut first = true;
+        loop {
+            let char = match self.chars.get(pos) {
+                Some(char) => *char,
+                None => return self.literal(1),
+            };
+            // 先頭の `]` は文字
+            if char == ']' && !first {
+                pos += 1;
+                break;
+            }
+            first = false;
+
+            if let Some((posix, end)) = self.posix_class(pos) {
+                chex = &chex | &posix;
+                pos = end;
+                continue;
+            }
+            let (lo, next) = self.class_char(pos);
+            pos = next;
+            if self.chars.get(pos) == Some(&'-')
+                && self.chars.get(pos + 1).is_some_and(|c| *c != ']')
+            {
+                let (hi, next) = self.class_char(pos + 1);
+                pos = next;
+                // 逆順の範囲は何にも一致しない
+                if lo <= hi {
+                    chex = &chex | &Chex::from_ranges(vec![(lo, hi)], true);
+                }
+            } else {
+                chex = &chex | &Chex::new(vec![lo], true);
+            }
+        }
+        self.pos = pos;
+
+        let span = Span::new(start, pos);
+        let separator = Chex::new(vec![SEPARATOR], true);
+        let chex = if negated {
+            !&(&chex | &separator)
+        } else {
+            &chex & &!&separator
+        };
+        if chex.include_flg {
+            get_inc_chex(&chex.ranges, span)
+        } else {
+            get_exc_chex(&chex.ranges, span)
+        }
+    }
+
+    /// `[:name:]` なら文字集合とその直後の位置
+    fn posix_class(&self, pos: usize) -> Option<(Chex, usize)> {
+        if self.chars.get(pos..pos + 2) != Some(&['[', ':']) {
+            return None;
+        }
+        let end = (pos + 2..self.chars.len().saturating_sub(1))
+            .find(|i| self.chars[*i] == ':' && self.chars[*i + 1] == ']')?;
+        let name: String = self.chars[pos + 2..end].iter().collect();
+        Chex::posix(&name).map(|chex| (chex, end + 2))
+    }
+
+    /// 括弧の中の一文字と、その直後の位置
+    fn class_char(&self, pos: usize) -> (char, usize) {
+        match self.chars.get(pos + 1) {
+            Some(char) if self.chars[pos] == '\\' => (*char, pos + 2),
+            _ => (self.chars[pos], pos + 1),
+        }
+    }
+
+    fn parse_brace(&mut self) -> Node {
+        if !self.brace_closed() {
+            return self.literal(1);
+        }
+        self.pos += 1;
+        let mut node = self.parse_seq(true);
+        while self.peek() == Some(',') {
+            self.pos += 1;
+            let right = self.parse_seq(true);
+            node = get_or_node(&node, &right);
+        }
+        if self.peek() == Some('}') {
+            self.pos += 1;
+        }
+
+        node
+    }
+
+    /// 今の `{` に対応する `}` があるか
+    fn brace_closed(&self) -> bool {
+        let mut depth = 0;
+        let mut pos = self.pos;
+        while let Some(char) = self.chars.get(pos) {
+            match char {
+                '\\' => pos += 1,
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+            pos += 1;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::spex;
+
+    #[test]
+    fn glob_wildcards() {
+        assert_eq!(Spex::from_glob("*.rs"), spex("[^/]*\\.rs"));
+        assert_eq!(Spex::from_glob("a?c"), spex("a[^/]c"));
+        assert_eq!(Spex::from_glob("[!a]*"), spex("[^a/][^/]*"));
+        assert_eq!(Spex::from_glob("[^a]*"), spex("[^a/][^/]*"));
+        assert_eq!(Spex::from_glob("[a-c/]"), spex("[a-c]"));
+        assert_eq!(Spex::from_glob("[]x]"), spex("[\\]x]"));
+        assert_eq!(Spex::from_glob("[[:digit:]_]"), spex("[0-9_]"));
+        assert_eq!(Spex::from_glob("a**b"), spex("a[^/]*b"));
+        assert_eq!(Spex::from_glob("\\*[a"), spex("\\*\\[a"));
+        assert_eq!(Spex::from_glob("*.{rs,toml}"), spex("[^/]*\\.(rs|toml)"));
+        assert_eq!(Spex::from_glob("a{b,}{c"), spex("ab?\\{c"));
+        assert_eq!(Spex::from_glob("**"), spex(".*"));
+    }
+
+    #[test]
+    fn glob_globstar() {
+        let glob = Spex::from_glob("src/**/test_?.py");
+        assert!(glob.is_match("src/test_a.py"));
+        assert!(glob.is_match("src/a/b/test_1.py"));
+        assert!(!glob.is_match("src/test_ab.py"));
+        assert!(!glob.is_match("lib/src/test_a.py"));
+
+        let glob = Spex::from_glob("docs/**");
+        assert!(glob.is_match("docs/a/b.md"));
+        assert!(!glob.is_match("docs"));
+        assert!(!Spex::from_glob("*").is_match("a/b"));
+    }
+
+    #[test]
+    fn glob_shadowing() {
+        let all = Spex::from_glob("src/**");
+        let rust = Spex::from_glob("src/**/*.rs");
+        let tests = Spex::from_glob("**/test_*");
+        assert!(all.include(&rust));
+        assert!(!rust.include(&all));
+        assert!(!(&rust & &tests).blank());
+        assert!((&Spex::from_glob("*.rs") & &Spex::from_glob("*.py")).blank());
+    }
+}
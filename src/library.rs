@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::builder::build_spex_with_env;
+use crate::parser::{
+    is_ident, parse_def, parse_def_body, sort_defs, split_statements, tokenize, trim_blank, Lexeme,
+    Node, Tokens,
+};
+use crate::spex::Spex;
+use crate::token::Token;
+
+const IMPORT: &str = "import";
+const EXPORT: &str = "export";
+
+/// `.spex` ライブラリファイルの読み込みで起きるエラー
+#[derive(Debug)]
+pub enum LibraryError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    ImportCycle {
+        paths: Vec<PathBuf>,
+    },
+    DuplicateName {
+        name: String,
+        path: PathBuf,
+    },
+    InvalidStatement {
+        statement: String,
+        path: PathBuf,
+    },
+    /// 定義の文の構文エラー
+    Syntax {
+        message: String,
+        statement: String,
+        path: PathBuf,
+    },
+    /// 互いに参照し合う定義
+    DefinitionCycle {
+        names: Vec<String>,
+        path: PathBuf,
+    },
+}
+
+impl Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LibraryError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            LibraryError::ImportCycle { paths } => write!(
+                f,
+                "import cycle {}",
+                paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            ),
+            LibraryError::DuplicateName { name, path } => {
+                write!(f, "{}: duplicate name {}", path.display(), name)
+            }
+            LibraryError::InvalidStatement { statement, path } => {
+                write!(f, "{}: invalid statement {}", path.display(), statement)
+            }
+            LibraryError::Syntax {
+                message,
+                statement,
+                path,
+            } => write!(f, "{}: {} in {}", path.display(), message, statement),
+            LibraryError::DefinitionCycle { names, path } => write!(
+                f,
+                "{}: cyclic definition {}",
+                path.display(),
+                names.join(" -> ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LibraryError {}
+
+/// ライブラリファイルを読み込み、そのファイルの定義を名前で引けるようにして返す
+///
+/// ファイルは `;` で区切った文の並びで、文は次のいずれか
+/// - `import "common.spex"` : 読み込むファイルのexportされた定義を使えるようにする
+/// - `export name = expr` : importしたファイルからも使える定義
+/// - `name = expr` : そのファイルの中だけで使う定義
+pub fn load(path: impl AsRef<Path>) -> Result<HashMap<String, Spex>, LibraryError> {
+    let mut loader = Loader {
+        loading: vec![],
+        loaded: HashMap::new(),
+    };
+    let path = loader.load(path.as_ref())?;
+
+    Ok(loader.loaded.remove(&path).unwrap().defs)
+}
+
+struct Module {
+    defs: HashMap<String, Spex>,
+    exports: Vec<String>,
+}
+
+impl Module {
+    fn exported(&self) -> impl Iterator<Item = (&String, &Spex)> {
+        self.exports
+            .iter()
+            .map(move |name| (name, &self.defs[name]))
+    }
+}
+
+struct Loader {
+    loading: Vec<PathBuf>,
+    loaded: HashMap<PathBuf, Module>,
+}
+
+impl Loader {
+    /// 読み込んだファイルの正規化したパスを返す
+    fn load(&mut self, path: &Path) -> Result<PathBuf, LibraryError> {
+        let path = fs::canonicalize(path).map_err(|error| LibraryError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        if let Some(pos) = self.loading.iter().position(|loading| loading == &path) {
+            let mut paths = self.loading[pos..].to_vec();
+            paths.push(path);
+            return Err(LibraryError::ImportCycle { paths });
+        }
+        if !self.loaded.contains_key(&path) {
+            self.loading.push(path.clone());
+            let module = self.load_module(&path);
+            self.loading.pop();
+            self.loaded.insert(path.clone(), module?);
+        }
+
+        Ok(path)
+    }
+
+    fn load_module(&mut self, path: &Path) -> Result<Module, LibraryError> {
+        let source = fs::read_to_string(path).map_err(|error| LibraryError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let duplicate = |name: &str| LibraryError::DuplicateName {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+        };
+        let syntax = |message: String, statement: &[Lexeme]| LibraryError::Syntax {
+            message,
+            statement: statement.concat(),
+            path: path.to_path_buf(),
+        };
+
+        let mut env: HashMap<String, Spex> = HashMap::new();
+        let mut imported: Vec<PathBuf> = vec![];
+        let mut defs: Vec<(String, Tokens, Tokens)> = vec![];
+        let mut exports: Vec<String> = vec![];
+        for statement in split_statements(&tokenize(source)) {
+            if statement.is_empty() {
+                continue;
+            }
+            match parse_statement(&statement) {
+                Some(Statement::Import(import_path)) => {
+                    let import_path = self.load(&path.parent().unwrap().join(import_path))?;
+                    if imported.contains(&import_path) {
+                        continue;
+                    }
+                    for (name, spex) in self.loaded[&import_path].exported() {
+                        if env.contains_key(name) {
+                            return Err(duplicate(name));
+                        }
+                        env.insert(name.clone(), spex.clone());
+                    }
+                    imported.push(import_path);
+                }
+                Some(Statement::Def { export, tokens }) => {
+                    let (name, tokens) = parse_def(&tokens)
+                        .map_err(|error| syntax(error.to_string(), &statement))?;
+                    if defs.iter().any(|(def_name, _, _)| def_name == &name) {
+                        return Err(duplicate(&name));
+                    }
+                    if export {
+                        exports.push(name.clone());
+                    }
+                    defs.push((name, tokens, statement));
+                }
+                None => {
+                    return Err(LibraryError::InvalidStatement {
+                        statement: statement.concat(),
+                        path: path.to_path_buf(),
+                    })
+                }
+            }
+        }
+        if let Some((name, _, _)) = defs.iter().find(|(name, _, _)| env.contains_key(name)) {
+            return Err(duplicate(name));
+        }
+
+        let mut names: Vec<String> = env.keys().cloned().collect();
+        names.extend(defs.iter().map(|(name, _, _)| name.clone()));
+        let mut nodes: Vec<(String, Node)> = vec![];
+        for (name, tokens, statement) in &defs {
+            let node = parse_def_body(tokens, &names)
+                .map_err(|error| syntax(error.to_string(), statement))?;
+            nodes.push((name.clone(), node));
+        }
+        let nodes = sort_defs(nodes).map_err(|names| LibraryError::DefinitionCycle {
+            names,
+            path: path.to_path_buf(),
+        })?;
+        let mut module = Module {
+            defs: HashMap::new(),
+            exports,
+        };
+        // 各定義は一度だけ組み立てて、以降の定義からはenvで参照する
+        for (name, node) in nodes {
+            let spex = build_spex_with_env(node, &env);
+            env.insert(name.clone(), spex.clone());
+            module.defs.insert(name, spex);
+        }
+
+        Ok(module)
+    }
+}
+
+enum Statement {
+    Import(String),
+    Def { export: bool, tokens: Tokens },
+}
+
+fn parse_statement(statement: &[Lexeme]) -> Option<Statement> {
+    let text = statement.concat();
+    let keyword = text
+        .find(char::is_whitespace)
+        .map(|len| &text[..len])
+        .filter(|keyword| is_ident(keyword));
+    match keyword {
+        Some(IMPORT) => {
+            let path = text[IMPORT.len()..].trim();
+            if path.len() >= 2 && path.starts_with('"') && path.ends_with('"') {
+                Some(Statement::Import(path[1..path.len() - 1].to_string()))
+            } else {
+                None
+            }
+        }
+        Some(EXPORT) => Some(Statement::Def {
+            export: true,
+            tokens: trim_blank(&statement[EXPORT.len()..]),
+        }),
+        _ if statement
+            .iter()
+            .any(|token| token == &Token::DEF.value().to_string()) =>
+        {
+            Some(Statement::Def {
+                export: false,
+                tokens: statement.to_vec(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::spex;
+
+    /// テストの終わりに消す一時ディレクトリ
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_library(dir: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("spexm_{}_{}", dir, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, source) in files {
+            fs::write(dir.join(name), source).unwrap();
+        }
+
+        TempDir(dir)
+    }
+
+    #[test]
+    fn load_with_import() {
+        let dir = write_library(
+            "import",
+            &[
+                (
+                    "common.spex",
+                    "export hex = [0-9a-f];\nexport digit = [0-9];\nlocal = x;\n",
+                ),
+                (
+                    "main.spex",
+                    "import \"common.spex\";\nuuid = hex{8}-hex{4};\nnum = digit+;\n",
+                ),
+            ],
+        );
+        let defs = load(dir.join("main.spex")).unwrap();
+        assert_eq!(defs.len(), 2);
+        assert!(defs["uuid"] == spex("[0-9a-f]{8}-[0-9a-f]{4}"));
+        assert!(defs["num"] == spex("[0-9]+"));
+    }
+
+    #[test]
+    fn load_diamond() {
+        let dir = write_library(
+            "diamond",
+            &[
+                ("base.spex", "export a = x;"),
+                ("left.spex", "import \"base.spex\"; export l = a+;"),
+                ("right.spex", "import \"base.spex\"; export r = a?;"),
+                (
+                    "main.spex",
+                    "import \"left.spex\"; import \"right.spex\"; lr = l|r",
+                ),
+            ],
+        );
+        let defs = load(dir.join("main.spex")).unwrap();
+        assert!(defs["lr"] == spex("x*"));
+    }
+
+    #[test]
+    fn load_private_name() {
+        let dir = write_library(
+            "private",
+            &[
+                ("common.spex", "local = x; export x2 = local{2};"),
+                ("main.spex", "import \"common.spex\"; y = local"),
+            ],
+        );
+        let defs = load(dir.join("main.spex")).unwrap();
+        assert!(defs["y"] == spex("local"));
+    }
+
+    #[test]
+    fn load_import_cycle() {
+        let dir = write_library(
+            "cycle",
+            &[
+                ("a.spex", "import \"b.spex\"; export a = x;"),
+                ("b.spex", "import \"a.spex\"; export b = y;"),
+            ],
+        );
+        match load(dir.join("a.spex")) {
+            Err(LibraryError::ImportCycle { paths }) => {
+                let names: Vec<String> = paths
+                    .iter()
+                    .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+                    .collect();
+                assert_eq!(names, vec!["a.spex", "b.spex", "a.spex"]);
+            }
+            result => panic!("unexpected result {:?}", result.map(|defs| defs.len())),
+        }
+    }
+
+    #[test]
+    fn load_duplicate_name() {
+        let dir = write_library(
+            "duplicate",
+            &[
+                ("a.spex", "export x = a;"),
+                ("b.spex", "export x = b;"),
+                ("local.spex", "import \"a.spex\"; x = c;"),
+                ("imports.spex", "import \"a.spex\"; import \"b.spex\";"),
+                ("defs.spex", "y = a; y = b;"),
+            ],
+        );
+        for file in ["local.spex", "imports.spex", "defs.spex"] {
+            match load(dir.join(file)) {
+                Err(LibraryError::DuplicateName { .. }) => {}
+                result => panic!("{}: unexpected {:?}", file, result.map(|defs| defs.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let dir = write_library("missing", &[("main.spex", "import \"none.spex\";")]);
+        assert!(matches!(
+            load(dir.join("main.spex")),
+            Err(LibraryError::Io { .. })
+        ));
+        assert!(matches!(
+            load(dir.join("other.spex")),
+            Err(LibraryError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn load_invalid_definition() {
+        let dir = write_library(
+            "invalid",
+            &[
+                ("group.spex", "a = x;\nb = (a;"),
+                ("name.spex", "export 1x = a;"),
+                ("export.spex", "export x;"),
+                ("cycle.spex", "a = b+; b = c|a; c = x;"),
+            ],
+        );
+        match load(dir.join("group.spex")) {
+            Err(LibraryError::Syntax {
+                message, statement, ..
+            }) => {
+                assert_eq!(message, ") not enough");
+                assert_eq!(statement, "b = (a");
+            }
+            result => panic!("unexpected {:?}", result.map(|defs| defs.len())),
+        }
+        for file in ["name.spex", "export.spex"] {
+            match load(dir.join(file)) {
+                Err(LibraryError::Syntax { .. }) => {}
+                result => panic!("{}: unexpected {:?}", file, result.map(|defs| defs.len())),
+            }
+        }
+        match load(dir.join("cycle.spex")) {
+            Err(LibraryError::DefinitionCycle { names, .. }) => {
+                assert_eq!(names, vec!["a", "b", "a"]);
+            }
+            result => panic!("unexpected {:?}", result.map(|defs| defs.len())),
+        }
+    }
+}
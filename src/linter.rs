@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::builder::{build_spex_with_env, shrink};
+use crate::optimizer::collect_operands;
+use crate::parser::{Node, Span};
+use crate::spex::Spex;
+
+/// 指摘の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// どの文字列も受理しない部分式(`a&b` など)
+    Empty,
+    /// `|` の他の要素に含まれていて、なくても変わらない要素
+    RedundantOperand,
+    /// `&` の他の要素から導かれて、なくても変わらない要素
+    ImpliedOperand,
+}
+
+/// 指摘と、その部分式の位置
+/// witnessは、その要素が受理する文字列のうち他の要素でも受理されるものの例
+/// 空の部分式には受理する文字列がないので、Lint::EmptyではいつもNone
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub span: Span,
+    pub witness: Option<String>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self.lint {
+            Lint::Empty => "empty subexpression",
+            Lint::RedundantOperand => "redundant operand of |",
+            Lint::ImpliedOperand => "implied operand of &",
+        };
+        write!(f, "{}..{}: {}", self.span.start, self.span.end, message)?;
+        if let Some(witness) = &self.witness {
+            write!(f, " (e.g. {:?})", witness)?;
+        }
+        Ok(())
+    }
+}
+
+/// 木を調べて、空の部分式・不要な `|` `&` の要素を位置の順に返す
+pub fn lint(node: &Node) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    lint_node(node, &HashMap::new(), true, &mut diagnostics);
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.span.start, diagnostic.span.end));
+
+    diagnostics
+}
+
+/// 調べた部分式が空か、と組み立てたspex
+/// 先読み・後読みを含んでいて単独では組み立てられないとき、spexはNone
+struct Linted {
+    empty: bool,
+    spex: Option<Spex>,
+}
+
+/// 子から順に調べ、子のspexを組み合わせて各部分式を一度だけ組み立てる
+fn lint_node(
+    node: &Node,
+    env: &HashMap<String, Spex>,
+    root: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Linted {
+    if let Node::Let { defs, body, .. } = node {
+        let mut env = env.clone();
+        for (name, node) in defs {
+            if let Some(spex) = lint_node(node, &env, true, diagnostics).spex {
+                env.insert(name.clone(), spex);
+            }
+        }
+        return lint_node(body, &env, root, diagnostics);
+    }
+
+    let mut operands = vec![];
+    let children: Vec<&Node> = match node {
+        Node::And { .. } | Node::Or { .. } => {
+            collect_operands(node, matches!(node, Node::And { .. }), &mut operands);
+            operands.iter().collect()
+        }
+        Node::Concat { nodes, .. } => nodes.iter().collect(),
+        Node::Invert { node, .. }
+        | Node::Containing { node, .. }
+        | Node::StartingWith { node, .. }
+        | Node::EndingWith { node, .. }
+        | Node::Repeat { node, .. }
+        | Node::Optional { node, .. }
+        | Node::Star { node, .. }
+        | Node::Bounded { node, .. }
+        | Node::Lookaround { node, .. } => vec![node],
+        _ => vec![],
+    };
+    let linted: Vec<Linted> = children
+        .iter()
+        .map(|child| lint_node(child, env, false, diagnostics))
+        .collect();
+    let any_empty = linted.iter().any(|linted| linted.empty);
+    let children_empty = match node {
+        Node::Or { .. } => linted.iter().all(|linted| linted.empty),
+        Node::And { .. }
+        | Node::Concat { .. }
+        | Node::Containing { .. }
+        | Node::StartingWith { .. }
+        | Node::EndingWith { .. }
+        | Node::Repeat { .. } => any_empty,
+        // 空の否定は空にならない
+        Node::Lookaround { negative, .. } => any_empty && !*negative,
+        Node::Bounded { min, .. } => any_empty && *min > 0,
+        // 空の定義はその定義で指摘する
+        Node::Ref { name, .. } => env.get(name).is_some_and(Spex::blank),
+        _ => false,
+    };
+
+    let spexes: Option<Vec<Spex>> = linted.into_iter().map(|linted| linted.spex).collect();
+    let spex = match &spexes {
+        // 先読み・後読みは前後と合わせないと意味が決まらない
+        _ if root && node.has_lookaround() => Some(build_spex_with_env(node.clone(), env)),
+        Some(spexes) => combine(node, spexes, env),
+        None => None,
+    };
+    if children_empty {
+        return Linted { empty: true, spex };
+    }
+    let spex = match spex {
+        Some(spex) => spex,
+        None => return Linted { empty: false, spex },
+    };
+    if spex.blank() {
+        diagnostics.push(Diagnostic {
+            lint: Lint::Empty,
+            span: node.span(),
+            witness: None,
+        });
+        return Linted {
+            empty: true,
+            spex: Some(spex),
+        };
+    }
+    if let (Node::And { .. } | Node::Or { .. }, Some(spexes)) = (node, &spexes) {
+        let is_and = matches!(node, Node::And { .. });
+        lint_operands(&operands, spexes, is_and, diagnostics);
+    }
+
+    Linted {
+        empty: false,
+        spex: Some(spex),
+    }
+}
+
+/// 子のspexから、build_spex_with_envと同じ組み立て方でnodeのspexを作る
+fn combine(node: &Node, spexes: &[Spex], env: &HashMap<String, Spex>) -> Option<Spex> {
+    let spex = spexes.first();
+    Some(match node {
+        Node::And { .. } => spexes
+            .iter()
+            .cloned()
+            .reduce(|a, b| shrink(&a & &b, &[&a, &b]))?,
+        Node::Or { .. } => spexes
+            .iter()
+            .cloned()
+            .reduce(|a, b| shrink(&a | &b, &[&a, &b]))?,
+        Node::Concat { .. } => spexes
+            .iter()
+            .cloned()
+            .reduce(|a, b| shrink(a.concat(&b), &[&a, &b]))?,
+        Node::Invert { .. } => spex.map(|spex| !spex)?,
+        Node::Containing { .. } => Spex::containing(spex?),
+        Node::StartingWith { .. } => Spex::starting_with(spex?),
+        Node::EndingWith { .. } => Spex::ending_with(spex?),
+        Node::Repeat { .. } => shrink(spex?.repeat(), &[spex?]),
+        Node::Optional { .. } => shrink(spex?.optional(), &[spex?]),
+        Node::Star { .. } => shrink(spex?.star(), &[spex?]),
+        Node::Bounded { min, max, .. } => spex?.bounded(*min, *max),
+        Node::IncChex { .. } | Node::ExcChex { .. } => build_spex_with_env(node.clone(), env),
+        Node::Ref { name, .. } => env.get(name)?.clone(),
+        Node::Lookaround { .. } | Node::Let { .. } => return None,
+    })
+}
+
+/// 後ろの要素から順に、残りの要素だけで足りるものを指摘する
+fn lint_operands(
+    operands: &[Node],
+    spexes: &[Spex],
+    is_and: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut kept = vec![true; operands.len()];
+    for i in (0..operands.len()).rev() {
+        if spexes[i].blank() {
+            continue;
+        }
+        let others = (0..operands.len()).filter(|&j| j != i && kept[j]);
+        let witness = if is_and {
+            let rest = others
+                .map(|j| spexes[j].clone())
+                .reduce(|a, b| &a & &b)
+                .unwrap_or_else(Spex::new_whole);
+            if spexes[i].include(&rest) {
+                rest.example()
+            } else {
+                continue;
+            }
+        } else {
+            match others.into_iter().find(|&j| spexes[j].include(&spexes[i])) {
+                Some(_) => spexes[i].example(),
+                None => continue,
+            }
+        };
+        kept[i] = false;
+        diagnostics.push(Diagnostic {
+            lint: if is_and {
+                Lint::ImpliedOperand
+            } else {
+                Lint::RedundantOperand
+            },
+            span: operands[i].span(),
+            witness,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, tokenize};
+
+    fn lints(src: &str) -> Vec<(Lint, Span, Option<String>)> {
+        lint(&parse(&tokenize(src)))
+            .into_iter()
+            .map(|diagnostic| (diagnostic.lint, diagnostic.span, diagnostic.witness))
+            .collect()
+    }
+
+    #[test]
+    fn lint_empty() {
+        assert_eq!(lints("x(a&b)y"), vec![(Lint::Empty, Span::new(1, 6), None)]);
+        assert_eq!(lints("a[]|b"), vec![(Lint::Empty, Span::new(1, 3), None)]);
+        assert_eq!(lints("!.*"), vec![(Lint::Empty, Span::new(0, 3), None)]);
+        assert_eq!(lints("(a&b)*c"), vec![(Lint::Empty, Span::new(0, 5), None)]);
+        assert!(lints("a(?=b).").is_empty());
+        assert_eq!(lints("(?=a)"), vec![(Lint::Empty, Span::new(0, 5), None)]);
+        assert_eq!(lints("!(a&b)"), vec![(Lint::Empty, Span::new(1, 6), None)]);
+        // 空の否定は空ではないので、`&` の要素として調べる
+        assert_eq!(
+            lints("!(a&b)&x&x"),
+            vec![
+                (Lint::ImpliedOperand, Span::new(0, 6), Some("x".to_string())),
+                (Lint::Empty, Span::new(1, 6), None),
+                (
+                    Lint::ImpliedOperand,
+                    Span::new(9, 10),
+                    Some("x".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn lint_redundant_operand() {
+        assert_eq!(
+            lints("ab|a+b|c"),
+            vec![(
+                Lint::RedundantOperand,
+                Span::new(0, 2),
+                Some("ab".to_string())
+            )]
+        );
+        assert_eq!(
+            lints("x|x"),
+            vec![(
+                Lint::RedundantOperand,
+                Span::new(2, 3),
+                Some("x".to_string())
+            )]
+        );
+        assert!(lints("a|b").is_empty());
+    }
+
+    #[test]
+    fn lint_implied_operand() {
+        assert_eq!(
+            lints("\\d+&[0-9a-f]+"),
+            vec![(
+                Lint::ImpliedOperand,
+                Span::new(4, 13),
+                Some("0".to_string())
+            )]
+        );
+        assert_eq!(
+            lints("a+&.+"),
+            vec![(Lint::ImpliedOperand, Span::new(3, 5), Some("a".to_string()))]
+        );
+        assert!(lints("a+&!aa").is_empty());
+    }
+
+    #[test]
+    fn lint_nested() {
+        // 各部分式は一度だけ組み立てる
+        let src = format!("{}x{}", "((a|b)".repeat(20), ")*".repeat(20));
+        assert!(lints(&src).is_empty());
+        assert_eq!(
+            lints("((a&b)|c)(?=c)."),
+            vec![(Lint::Empty, Span::new(1, 6), None)]
+        );
+    }
+
+    #[test]
+    fn lint_let() {
+        let diagnostics = lint(&parse(&tokenize("x = a&b; y = x|c; yd")));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].lint, Lint::Empty);
+        assert_eq!(diagnostics[0].to_string(), "4..7: empty subexpression");
+        assert_eq!(
+            lint(&parse(&tokenize("x|[xy]")))[0].to_string(),
+            "0..1: redundant operand of | (e.g. \"x\")"
+        );
+    }
+}
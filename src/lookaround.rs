@@ -0,0 +1,457 @@
+//! 先読み・後読みを含む式の組み立て
+//!
+//! 式をNFAにして、部分集合構成でDFAにする
+//! 後読み `(?<=X)` は `.*X` のDFAを入力の先頭から動かしておき、その位置で受理状態かを見る
+//! 先読み `(?=X)` はその位置から `X.*` のDFAを動かし始め、入力の終わりで受理状態かを見る
+//! `&` `!` と定義の中の先読み・後読みはその中だけで完結する
+//!
+//! 繰返しの外に出ない位置なら、先読みは後ろに続く言語との積 `(X.*) & K`、
+//! 後読みは前の言語との積 `P & (.*X)` に書き換えられる
+//! しかし `((?!foo).)*` のように繰返しの中にあると、後ろに続く言語が回数ごとに変わり、
+//! `&` と連接を有限回組み合わせた式にはならない
+//! そのためNFAの各スレッドに残りの条件を持たせて決定化する
+//! DFAの状態は (後読みのDFAの状態, スレッドの集合) で、最悪では先読みの数に対して指数的に増えるが、
+//! 目印の文字を使っていたときの積と同じ程度で、状態数の上限で止まる
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::builder::{build_within, LimitError};
+use crate::chex::Chex;
+use crate::parser::Node;
+use crate::spex::{SidGen, Spex, Transition};
+
+type Sid = isize;
+/// NFAの状態と、まだ決まっていない先読みの (条件, 状態)
+type Thread = (usize, BTreeSet<(usize, Sid)>);
+/// 後読みのDFAの状態と、生きているスレッド
+type Key = (Vec<Sid>, BTreeSet<Thread>);
+
+/// 先読み・後読みを含む式のDFA
+/// 状態数がlimitに達したらやめてエラーにする
+pub fn build_lookaround(
+    parsed: &Node,
+    env: &HashMap<String, Spex>,
+    limit: Option<usize>,
+) -> Result<Spex, LimitError> {
+    let exceeded = LimitError::States {
+        limit: limit.unwrap_or(usize::MAX),
+    };
+    let mut nfa = Nfa {
+        limit,
+        ..Default::default()
+    };
+    let (start, end) = nfa.build(parsed, env)?;
+    nfa.end = end;
+
+    let behind = vec![0; nfa.assertions.len()];
+    let threads = nfa.closure(&behind, vec![(start, BTreeSet::new())]);
+    let mut new_tn_list: Vec<Transition> = vec![];
+    let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+    let mut sid_gen = SidGen::with_limit(limit);
+    let mut sid_dict_by_key: HashMap<Key, Sid> = HashMap::new();
+    let key = nfa.key(behind, threads);
+    if nfa.accepts(&key) {
+        new_accepts_sids.insert(0);
+    }
+    sid_dict_by_key.insert(key.clone(), 0);
+
+    let mut stack = vec![(0, key)];
+    while let Some((new_fr_sid, key)) = stack.pop() {
+        if sid_gen.exhausted() {
+            break;
+        }
+        for target_chex in nfa.chex_pattern(&key) {
+            let next_key = nfa.step(&key, &target_chex);
+            let new_to_sid = match sid_dict_by_key.get(&next_key) {
+                Some(&sid) => sid,
+                None => {
+                    let sid = sid_gen.get();
+                    if nfa.accepts(&next_key) {
+                        new_accepts_sids.insert(sid);
+                    }
+                    sid_dict_by_key.insert(next_key.clone(), sid);
+                    stack.push((sid, next_key));
+                    sid
+                }
+            };
+            new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex));
+        }
+    }
+
+    if sid_gen.exhausted() {
+        return Err(exceeded);
+    }
+
+    Ok(Spex::new(new_tn_list, new_accepts_sids).minimize())
+}
+
+/// NFAの辺
+enum Edge {
+    Eps(usize),
+    Chex(Chex, usize),
+    /// 条件を満たすときだけ通れる
+    Assert(usize, usize),
+}
+
+/// 先読み・後読みの条件
+struct Assertion {
+    behind: bool,
+    negative: bool,
+    /// 後読みなら `.*X`、先読みなら `X.*`
+    spex: Spex,
+    /// 受理状態に到達できる状態
+    live_sids: HashSet<Sid>,
+    /// 受理しない状態に到達できる状態
+    unlive_sids: HashSet<Sid>,
+}
+
+impl Assertion {
+    /// 先読みのDFAが状態sidにあるとき、この先の入力によらず満たすならSome(true)、
+    /// 満たさないならSome(false)
+    fn settled(&self, sid: Sid) -> Option<bool> {
+        if !self.live_sids.contains(&sid) {
+            Some(self.negative)
+        } else if !self.unlive_sids.contains(&sid) {
+            Some(!self.negative)
+        } else {
+            None
+        }
+    }
+
+    /// DFAが状態sidにあるとき、ここで入力が終わるなら条件を満たすか
+    fn holds(&self, sid: Sid) -> bool {
+        self.spex.accepts_sids.contains(&sid) != self.negative
+    }
+
+    fn next_sid(&self, sid: Sid, chex: &Chex) -> Sid {
+        self.spex.tns_dict[&sid]
+            .iter()
+            .find(|tn| tn.chex.include(chex))
+            .unwrap()
+            .to_sid
+    }
+}
+
+#[derive(Default)]
+struct Nfa {
+    edges: Vec<Vec<Edge>>,
+    assertions: Vec<Assertion>,
+    end: usize,
+    /// 組み立てる状態数の上限
+    limit: Option<usize>,
+}
+
+impl Nfa {
+    fn add_state(&mut self) -> usize {
+        self.edges.push(vec![]);
+        self.edges.len() - 1
+    }
+
+    fn add_eps(&mut self, from: usize, to: usize) {
+        self.edges[from].push(Edge::Eps(to));
+    }
+
+    /// nodeのNFAを作り、その入口と出口を返す
+    fn build(
+        &mut self,
+        node: &Node,
+        env: &HashMap<String, Spex>,
+    ) -> Result<(usize, usize), LimitError> {
+        Ok(match node {
+            Node::Lookaround {
+                node,
+                behind,
+                negative,
+                ..
+            } => {
+                let spex = build_within(node.as_ref().clone(), env, self.limit)?;
+                let any = Spex::new_whole();
+                let spex = if *behind {
+                    any.concat_within(&spex, self.limit)
+                } else {
+                    spex.concat_within(&any, self.limit)
+                }
+                .ok_or(LimitError::States {
+                    limit: self.limit.unwrap_or(usize::MAX),
+                })?
+                .minimize();
+                let assertion = match self.assertions.iter().position(|assertion| {
+                    assertion.behind == *behind
+                        && assertion.negative == *negative
+                        && assertion.spex == spex
+                }) {
+                    Some(assertion) => assertion,
+                    None => {
+                        self.assertions.push(Assertion {
+                            behind: *behind,
+                            negative: *negative,
+                            live_sids: spex.live_sids(),
+                            unlive_sids: (!&spex).live_sids(),
+                            spex,
+                        });
+                        self.assertions.len() - 1
+                    }
+                };
+                let (start, end) = (self.add_state(), self.add_state());
+                self.edges[start].push(Edge::Assert(assertion, end));
+                (start, end)
+            }
+            Node::Or { left, right, .. } if node.has_lookaround() => {
+                let (start, end) = (self.add_state(), self.add_state());
+                for node in [left, right] {
+                    let (node_start, node_end) = self.build(node, env)?;
+                    self.add_eps(start, node_start);
+                    self.add_eps(node_end, end);
+                }
+                (start, end)
+            }
+            Node::Concat { nodes, .. } if node.has_lookaround() => {
+                let start = self.add_state();
+                let mut end = start;
+                for node in nodes {
+                    let (node_start, node_end) = self.build(node, env)?;
+                    self.add_eps(end, node_start);
+                    end = node_end;
+                }
+                (start, end)
+            }
+            Node::Repeat { node, .. } if node.has_lookaround() => {
+                self.wrap(node, env, false, true)?
+            }
+            Node::Optional { node, .. } if node.has_lookaround() => {
+                self.wrap(node, env, true, false)?
+            }
+            Node::Star { node, .. } if node.has_lookaround() => self.wrap(node, env, true, true)?,
+            Node::Bounded { node, min, max, .. } if node.has_lookaround() => {
+                let start = self.add_state();
+                let mut end = start;
+                let mut optional_ends = vec![];
+                for i in 0..max.unwrap_or(*min).max(*min) {
+                    if self.limit.is_some_and(|limit| self.edges.len() >= limit) {
+                        return Err(LimitError::States {
+                            limit: self.limit.unwrap(),
+                        });
+                    }
+                    if i >= *min {
+                        optional_ends.push(end);
+                    }
+                    let (node_start, node_end) = self.build(node, env)?;
+                    self.add_eps(end, node_start);
+                    end = node_end;
+                }
+                if max.is_none() {
+                    let (star_start, star_end) = self.wrap(node, env, true, true)?;
+                    self.add_eps(end, star_start);
+                    end = star_end;
+                }
+                for optional_end in optional_ends {
+                    self.add_eps(optional_end, end);
+                }
+                (start, end)
+            }
+            _ => self.embed(&build_within(node.clone(), env, self.limit)?),
+        })
+    }
+
+    /// nodeを新しい入口と出口で囲み、skipなら飛ばせるように、repeatなら繰り返せるようにする
+    fn wrap(
+        &mut self,
+        node: &Node,
+        env: &HashMap<String, Spex>,
+        skip: bool,
+        repeat: bool,
+    ) -> Result<(usize, usize), LimitError> {
+        let (start, end) = (self.add_state(), self.add_state());
+        let (node_start, node_end) = self.build(node, env)?;
+        self.add_eps(start, node_start);
+        self.add_eps(node_end, end);
+        if skip {
+            self.add_eps(start, end);
+        }
+        if repeat {
+            self.add_eps(node_end, node_start);
+        }
+        Ok((start, end))
+    }
+
+    /// DFAをそのままNFAの一部にする
+    fn embed(&mut self, spex: &Spex) -> (usize, usize) {
+        let live_sids = spex.live_sids();
+        let mut state_by_sid: HashMap<Sid, usize> = HashMap::new();
+        let mut sids: Vec<&Sid> = live_sids.iter().collect();
+        sids.sort_unstable();
+        for sid in sids {
+            state_by_sid.insert(*sid, self.add_state());
+        }
+        let (start, end) = (self.add_state(), self.add_state());
+        if let Some(state) = state_by_sid.get(&0) {
+            self.add_eps(start, *state);
+        }
+        for (sid, state) in &state_by_sid {
+            for tn in &spex.tns_dict[sid] {
+                if let Some(to_state) = state_by_sid.get(&tn.to_sid) {
+                    self.edges[*state].push(Edge::Chex(tn.chex.clone(), *to_state));
+                }
+            }
+            if spex.accepts_sids.contains(sid) {
+                self.add_eps(*state, end);
+            }
+        }
+
+        (start, end)
+    }
+
+    /// 空の辺と、満たされた条件の辺で到達できるスレッド
+    /// 文字を読めず出口でもないスレッドは除く
+    fn closure(&self, behind: &[Sid], threads: Vec<Thread>) -> BTreeSet<Thread> {
+        let mut visited: HashSet<Thread> = HashSet::new();
+        let mut stack = threads;
+        let mut ret = BTreeSet::new();
+        while let Some(thread) = stack.pop() {
+            if !visited.insert(thread.clone()) {
+                continue;
+            }
+            let (state, pending) = &thread;
+            for edge in &self.edges[*state] {
+                match edge {
+                    Edge::Eps(to) => stack.push((*to, pending.clone())),
+                    Edge::Assert(i, to) => {
+                        let assertion = &self.assertions[*i];
+                        if assertion.behind {
+                            if assertion.holds(behind[*i]) {
+                                stack.push((*to, pending.clone()));
+                            }
+                        } else {
+                            match assertion.settled(0) {
+                                Some(true) => stack.push((*to, pending.clone())),
+                                Some(false) => {}
+                                None => {
+                                    let mut pending = pending.clone();
+                                    pending.insert((*i, 0));
+                                    stack.push((*to, pending));
+                                }
+                            }
+                        }
+                    }
+                    Edge::Chex(..) => {}
+                }
+            }
+            let readable = self.edges[*state]
+                .iter()
+                .any(|edge| matches!(edge, Edge::Chex(..)));
+            if readable || *state == self.end {
+                ret.insert(thread);
+            }
+        }
+
+        ret
+    }
+
+    /// 行き止まりは一つの状態にまとめる
+    fn key(&self, behind: Vec<Sid>, threads: BTreeSet<Thread>) -> Key {
+        if threads.is_empty() {
+            (vec![], threads)
+        } else {
+            (behind, threads)
+        }
+    }
+
+    fn accepts(&self, (_, threads): &Key) -> bool {
+        threads.iter().any(|(state, pending)| {
+            *state == self.end
+                && pending
+                    .iter()
+                    .all(|(i, sid)| self.assertions[*i].holds(*sid))
+        })
+    }
+
+    /// 同じ文字集合の中では同じ状態に移るように文字を分ける
+    fn chex_pattern(&self, (behind, threads): &Key) -> Vec<Chex> {
+        let mut chex_pattern = vec![Chex::new_whole()];
+        let mut split_by_tns = |tns: &Vec<Transition>| {
+            let mut tmp_chex_pattern: Vec<Chex> = vec![];
+            for target_chex in &chex_pattern {
+                for tn in tns {
+                    let and_chex = target_chex & &tn.chex;
+                    if !and_chex.blank() {
+                        tmp_chex_pattern.push(and_chex);
+                    }
+                }
+            }
+            chex_pattern = tmp_chex_pattern;
+        };
+        for (i, sid) in behind.iter().enumerate() {
+            if self.assertions[i].behind {
+                split_by_tns(&self.assertions[i].spex.tns_dict[sid]);
+            }
+        }
+        let mut edge_chexes: Vec<&Chex> = vec![];
+        for (state, pending) in threads {
+            for (i, sid) in pending {
+                split_by_tns(&self.assertions[*i].spex.tns_dict[sid]);
+            }
+            for edge in &self.edges[*state] {
+                if let Edge::Chex(chex, _) = edge {
+                    if !edge_chexes.contains(&chex) {
+                        edge_chexes.push(chex);
+                    }
+                }
+            }
+        }
+        for chex in edge_chexes {
+            let not_chex = !chex;
+            chex_pattern = chex_pattern
+                .iter()
+                .flat_map(|target_chex| [target_chex & chex, target_chex & &not_chex])
+                .filter(|and_chex| !and_chex.blank())
+                .collect();
+        }
+
+        chex_pattern
+    }
+
+    /// target_chexの文字を一つ読んだ後の状態
+    fn step(&self, (behind, threads): &Key, target_chex: &Chex) -> Key {
+        let behind: Vec<Sid> = behind
+            .iter()
+            .enumerate()
+            .map(|(i, sid)| {
+                let assertion = &self.assertions[i];
+                if assertion.behind {
+                    assertion.next_sid(*sid, target_chex)
+                } else {
+                    *sid
+                }
+            })
+            .collect();
+        let mut next_threads: Vec<Thread> = vec![];
+        for (state, pending) in threads {
+            let mut next_pending = BTreeSet::new();
+            let mut alive = true;
+            for (i, sid) in pending {
+                let assertion = &self.assertions[*i];
+                let next_sid = assertion.next_sid(*sid, target_chex);
+                match assertion.settled(next_sid) {
+                    Some(true) => {}
+                    Some(false) => alive = false,
+                    None => {
+                        next_pending.insert((*i, next_sid));
+                    }
+                }
+            }
+            if !alive {
+                continue;
+            }
+            for edge in &self.edges[*state] {
+                if let Edge::Chex(chex, to) = edge {
+                    if chex.include(target_chex) {
+                        next_threads.push((*to, next_pending.clone()));
+                    }
+                }
+            }
+        }
+        let threads = self.closure(&behind, next_threads);
+
+        self.key(behind, threads)
+    }
+}
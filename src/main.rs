@@ -1,29 +1,44 @@
+pub mod binary;
 pub mod builder;
 pub mod chex;
+pub mod glob;
+pub mod library;
+pub mod linter;
+pub mod lookaround;
+pub mod optimizer;
 pub mod parser;
+pub mod printer;
+pub mod regex;
+#[cfg(feature = "serde")]
+pub mod schema;
 pub mod spex;
+pub mod sql;
 pub mod token;
 
-use chex::Chex;
-use spex::Spex;
-
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::Write;
 
 use crate::builder::spex;
 
 fn main() {
     let mut file = File::create("./result.md").unwrap();
     //write!(file, "{}", spex("((a[bc])+&!((ac)+))|a+|((([^def]&[l])|[op])&[^u])+").mermaid()).unwrap();
-    write!(file, "{}", spex("((a[bc])+&!((ac)+))|a+").mermaid());
+    write!(file, "{}", spex("((a[bc])+&!((ac)+))|a+").mermaid()).unwrap();
     file.flush().unwrap();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::builder::{spex_with, try_spex_with, LimitError, Limits, Options};
+    use crate::chex::Chex;
+    use crate::spex::{Direction, RenderOptions, Spex, Transition};
+    use crate::token::{Dialect, Token};
+    use std::collections::HashSet;
+    use std::iter::FromIterator;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test1() {
         assert_eq!(spex("(a+|ab)&!a") == spex("a(a+|b)"), true);
         assert_eq!(spex("(a+|ab)") == spex("a(a+|b)"), false);
@@ -32,4 +47,428 @@ mod tests {
         assert_eq!(!(&spex("(abc)+") & &spex("....a|....a.+")).blank(), false);
         assert_eq!(!(&spex("(abc)+") & &spex("....b|....b.+")).blank(), true);
     }
+
+    #[test]
+    fn test_escape() {
+        assert!(spex("\\&").is_match("&"));
+        assert!(spex("\\|").is_match("|"));
+        assert!(spex("\\(").is_match("("));
+        assert!(spex("\\.").is_match("."));
+        assert!(!spex("\\.").is_match("a"));
+        assert!(spex("\\\\").is_match("\\"));
+        assert!(!spex("\\&").is_match("\\&"));
+        assert!(spex("[\\&\\|]+").is_match("&|&"));
+        assert!(spex("a\\(b\\)").is_match("a(b)"));
+    }
+
+    #[test]
+    fn test_class() {
+        assert!(spex("\\d+").is_match("2024"));
+        assert!(!spex("\\d+").is_match("20x4"));
+        assert!(spex("\\D").is_match("x"));
+        assert!(spex("[\\w-]+").is_match("a_b-1"));
+        assert!(spex("\\s").is_match("\t"));
+        assert!(spex("[[:alpha:][:digit:]]+") == spex("[a-zA-Z0-9]+"));
+        assert!(spex("\\w&!\\d") == spex("[a-zA-Z_]"));
+        assert!(spex("\\p{L}+").is_match("ことば"));
+        assert!(spex("\\P{L}").is_match("1"));
+        assert!(spex("\\p{L}&[[:ascii:]]") == spex("[[:alpha:]]"));
+        assert!(spex("!\\D") == spex("\\d?|..+"));
+    }
+
+    #[test]
+    fn test_empty_string() {
+        assert!(Spex::empty_string().is_match(""));
+        assert!(!Spex::empty_string().is_match("a"));
+        assert!(spex(".*").whole());
+        assert!(!spex(".+").whole());
+        assert!(spex("!.+") == Spex::empty_string());
+        assert!(spex("a*").is_match(""));
+        assert!(spex("a*").is_match("aaa"));
+        assert!(!spex("a+").is_match(""));
+        assert!((!&spex("a+")).is_match(""));
+        assert!(!(!&spex("a*")).is_match(""));
+        assert!(spex("ab?c").is_match("ac"));
+        assert!(spex("ab?c").is_match("abc"));
+        assert!(!spex("ab?c").is_match("abbc"));
+        assert!(spex("a*b") == spex("b|a+b"));
+        assert!(spex("ab*") == spex("a|ab+"));
+        assert!(spex("a?b?") == spex("a|b|ab|!.+"));
+        assert!(spex("(ab)*") == spex("((ab)+)?"));
+        assert!(spex("(a?)+") == spex("a*"));
+        assert!(spex("(a*b*)+") == spex("[ab]*"));
+        assert!(spex("a*").include(&Spex::empty_string()));
+        assert!((&spex("a*") & &spex("b*")) == Spex::empty_string());
+    }
+
+    #[test]
+    fn test_bounded() {
+        assert!(spex("[0-9]{4}").is_match("2024"));
+        assert!(!spex("[0-9]{4}").is_match("202"));
+        assert!(!spex("[0-9]{4}").is_match("20245"));
+        assert!(spex("a{2,3}") == spex("aa|aaa"));
+        assert!(spex("a{2,}") == spex("aaa*"));
+        assert!(spex("a{0,}") == spex("a*"));
+        assert!(spex("a{0,1}") == spex("a?"));
+        assert!(spex("a{0}") == Spex::empty_string());
+        assert!(spex("(ab|c){2}") == spex("(ab|c)(ab|c)"));
+        assert!(spex("(a?b?){2,3}") == spex("(a?b?)(a?b?)(a?b?)?"));
+        assert!(spex("(a*){3,5}") == spex("a*"));
+        assert!(spex("(a+b*){2}") == spex("a+b*a+b*"));
+        assert!(spex("x{1,2}y") == spex("xy|xxy"));
+        assert!(spex("\\{2\\}").is_match("{2}"));
+        assert!(spex("a{b").is_match("a{b"));
+    }
+
+    #[test]
+    fn test_bounded_large() {
+        let digits = spex("[0-9]{1000}");
+        assert!(digits.is_match(&"7".repeat(1000)));
+        assert!(!digits.is_match(&"7".repeat(999)));
+        let digits = spex("[0-9]{2,3000}");
+        assert!(digits.is_match(&"7".repeat(3000)));
+        assert!(!digits.is_match(&"7".repeat(3001)));
+    }
+
+    #[test]
+    fn test_let() {
+        let uuid = spex("hex = [0-9a-f]; hex{8}-hex{4}-hex{4}-hex{4}-hex{12}");
+        assert!(uuid.is_match("123e4567-e89b-12d3-a456-426614174000"));
+        assert!(!uuid.is_match("123e4567-e89b-12d3-a456-42661417400"));
+        assert!(spex("ab = a|b; ab+&!a+") == spex("[ab]+&!a+"));
+        assert!(spex("x = [ab]; xy = (x)x; xy|xyz") == spex("[ab][ab]|xyz"));
+        assert!(spex("d = \\d; n = d+(\\.d+)?; n(,n)*").is_match("1.5,20"));
+        assert!(spex("a = x; [a]").is_match("a"));
+        assert!(spex("a\\=b").is_match("a=b"));
+        assert!(spex("a=b") == spex("a\\=b"));
+        assert!(spex("[;]").is_match(";"));
+        assert!(spex("[=]").is_match("="));
+        assert!(spex("[;=#]+").is_match(";=#"));
+        assert!(spex("eq = [=]; a(eq|[;])b") == spex("a[=;]b"));
+        assert!(spex("x = a=b; x+") == spex("(a\\=b)+"));
+    }
+
+    #[test]
+    fn test_verbose() {
+        let options = Options {
+            verbose: true,
+            ..Default::default()
+        };
+        let verbose = spex_with(
+            r"
+            (
+                (a[bc])+ & !((ac)+)     # a に b か c が続く、ただし ac の繰返しは除く
+            )
+            | a+
+            | (
+                (([^def] & [l]) | [op]) & [^u]
+            )+
+            ",
+            &options,
+        );
+        assert!(verbose == spex("((a[bc])+&!((ac)+))|a+|((([^def]&[l])|[op])&[^u])+"));
+        assert!(spex_with("hello\\ world", &options).is_match("hello world"));
+        assert!(spex_with("a[ ]b\\#", &options).is_match("a b#"));
+        assert!(!spex_with("a b", &options).is_match("a b"));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let options = Options {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let header = spex_with("content-type&!x-.+", &options);
+        assert!(header.is_match("Content-Type"));
+        assert!(header.is_match("CONTENT-TYPE"));
+        assert!(spex("(?i)content-type") == header);
+        assert!(spex("(?i:ab)c") == spex("[aA][bB]c"));
+        assert!(spex("a((?i)b|c)") == spex("a[bBcC]"));
+        assert!(spex("a(?i)bc") == spex("a[bB][cC]"));
+        assert!(spex("(a(?i)b|c)d") == spex("(a[bB]|[cC])d"));
+        assert!(spex("x|a(?i)b&.b|c") == spex("x|a[bB]&.[bB]|[cC]"));
+        assert!(spex("a(?i)(b|c)+") == spex("a[bBcC]+"));
+        assert!(spex("(?i)[^a]") == spex("[^aA]"));
+        assert!(spex("(?i)\\D") == spex("\\D"));
+        assert!(spex("(?i)k").is_match("\u{212a}"));
+        assert!(spex_with("x = ab; x|c", &options) == spex("[aA][bB]|[cC]"));
+        assert!(spex("\\(\\?i\\)").is_match("(?i)"));
+    }
+
+    #[test]
+    fn test_dialect() {
+        let options = Options {
+            dialect: Dialect::logical(),
+            ..Default::default()
+        };
+        let csv = spex_with("[^|]+(|[^|]*)*", &options);
+        assert!(csv.is_match("a|b||c"));
+        assert!(!csv.is_match("|a"));
+        assert!(spex_with("a∨b∧¬c", &options) == spex("a|b&!c"));
+        assert!(spex_with("wow!", &options).is_match("wow!"));
+        assert!(spex_with("(?¬a).", &options) == spex("(?!a)."));
+        assert!(spex_with("x = a∨b; x+", &options) == spex("[ab]+"));
+        let options = Options {
+            verbose: true,
+            dialect: Dialect::default().with(Token::CMNT, '%').unwrap(),
+            ..Default::default()
+        };
+        assert!(spex_with("a b # c % comment", &options) == spex("ab#c"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let options = Options::default();
+        let limits = Limits {
+            max_states: 10_000,
+            ..Default::default()
+        };
+        let deep = format!("{}a{}", "(".repeat(100), ")".repeat(100));
+        assert!(matches!(
+            try_spex_with(deep, &options, &limits),
+            Err(LimitError::Depth { limit: 64, .. })
+        ));
+        assert!(matches!(
+            try_spex_with("!".repeat(100) + "a", &options, &limits),
+            Err(LimitError::Depth { .. })
+        ));
+        assert_eq!(
+            try_spex_with("a".repeat(5000), &options, &limits).unwrap_err(),
+            LimitError::Tokens {
+                limit: 4096,
+                count: 5000
+            }
+        );
+        assert_eq!(
+            try_spex_with(".*a.{20}", &options, &limits).unwrap_err(),
+            LimitError::States { limit: 10_000 }
+        );
+        assert_eq!(
+            try_spex_with("(a", &options, &limits).unwrap_err(),
+            LimitError::Syntax {
+                message: ") not enough".to_string()
+            }
+        );
+        assert!(matches!(
+            try_spex_with("[a", &options, &limits),
+            Err(LimitError::Syntax { .. })
+        ));
+        assert_eq!(
+            try_spex_with("(?=.*a.{20}).*", &options, &limits).unwrap_err(),
+            LimitError::States { limit: 10_000 }
+        );
+        let password = try_spex_with("(?=.*\\d)(?=.*[a-z]).{8,}", &options, &limits).unwrap();
+        assert!(password.is_match("abcdefg1"));
+        assert!(try_spex_with(".*a.{5}", &options, &limits).unwrap() == spex(".*a.{5}"));
+        assert!(spex(".*a.{12}").is_match("a123456789012"));
+    }
+
+    #[test]
+    fn test_lookaround() {
+        let password = spex("(?=.*\\d)(?=.*[a-z])(?!.*\\s).{8,}");
+        assert!(password.is_match("abcdefg1"));
+        assert!(!password.is_match("abcdefgh"));
+        assert!(!password.is_match("abc defg1"));
+        assert!(!password.is_match("abcde1"));
+        assert!(spex("a(?=b).") == spex("ab"));
+        assert!(spex("a(?!b).") == spex("a[^b]"));
+        assert!(spex(".(?<=b)c") == spex("bc"));
+        assert!(spex(".(?<!b)c") == spex("[^b]c"));
+        assert!(spex("((?!foo).)*") == spex("!(.*foo.*)"));
+        assert!(spex("(a(?=bc)|ab)(b|c)+") == spex("abc[bc]*|ab[bc]+"));
+        assert!(spex("(?=a)") == spex("a&b"));
+        assert!(spex("(?!a)") == Spex::empty_string());
+        assert!(spex("(?=.*x).*").is_match("x\u{fdd0}"));
+        // 繰返しの中の先読み・後読みは、繰返しの外まで見る
+        assert!(spex("(a(?=b)|b)*") == spex("(ab|b)*"));
+        assert!(spex("((?<=a)b|a)*") == spex("(ab|a)*"));
+        assert!(spex("((?=a).){3}") == spex("aaa"));
+        assert!(spex("a(?=.*z)b(?<=ab).*") == spex("ab.*z.*"));
+        let chars: Vec<char> = ('ぁ'..).take(40).collect();
+        let many: String = chars.iter().map(|c| format!("(?!{}).", c)).collect();
+        let expected: String = chars.iter().map(|c| format!("[^{}]", c)).collect();
+        assert!(spex(&many) == spex(&expected));
+    }
+
+    #[test]
+    fn test_minimize() {
+        let spex1 = &spex("(a|b)+c") | &spex("a+c|b+c");
+        let minimized = spex1.minimize();
+        assert!(minimized == spex1);
+        assert!(minimized.tns_dict.len() < spex1.tns_dict.len());
+        assert!(spex(".*").minimize().whole());
+        assert!(spex("a&b").minimize().blank());
+        assert!(Spex::empty_string().minimize() == Spex::empty_string());
+    }
+
+    /// 0から1へ `a` と `b` の二本の遷移があるspex
+    fn parallel_edges() -> Spex {
+        Spex::new(
+            vec![
+                Transition::new(0, 1, Chex::new(vec!['a'], true)),
+                Transition::new(0, 1, Chex::new(vec!['b'], true)),
+                Transition::new(0, 2, Chex::new(vec!['a', 'b'], false)),
+                Transition::new(1, 2, Chex::new_whole()),
+                Transition::new(2, 2, Chex::new_whole()),
+            ],
+            HashSet::from_iter([1]),
+        )
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let spex1 = parallel_edges();
+        assert_eq!(
+            spex1.mermaid(),
+            "```mermaid\ngraph LR\n    0(( ))\n    1( )\n    2( )\n    \
+             style 0 fill:#000,stroke-width:0px\n    \
+             style 1 stroke:#dc3545,stroke-width:4px\n    \
+             0 -- \"a\" --> 1\n    0 -- \"b\" --> 1\n    0 -- \"[^ab]\" --> 2\n    \
+             1 -- \".\" --> 2\n    2 -- \".\" --> 2\n```"
+        );
+        assert_eq!(spex1.mermaid(), parallel_edges().mermaid());
+
+        let options = RenderOptions::new()
+            .hide_dead(true)
+            .merge_edges(true)
+            .state_labels(true)
+            .direction(Direction::TopDown);
+        assert_eq!(
+            spex1.mermaid_with(&options),
+            "```mermaid\ngraph TD\n    0((0))\n    1(1)\n    \
+             style 0 fill:#000,color:#fff,stroke-width:0px\n    \
+             style 1 stroke:#dc3545,stroke-width:4px\n    \
+             0 -- \"[ab]\" --> 1\n```"
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let spex1 = parallel_edges();
+        let run = spex1.run("ab");
+        assert_eq!(run.steps, vec![(0, 1, 'a'), (1, 2, 'b')]);
+        assert!(!run.accepted);
+        assert_eq!(run.fault, Some(1));
+        assert_eq!(spex1.run("a").fault, None);
+        assert_eq!(spex("a|abc").run("ab").fault, Some(1));
+        assert_eq!(spex("abc").run("ab").fault, None);
+
+        let options = RenderOptions::new().hide_dead(true);
+        assert_eq!(
+            spex1.mermaid_run("ab", &options),
+            "```mermaid\ngraph LR\n    0(( ))\n    1( )\n    2( )\n    \
+             style 0 fill:#000,stroke-width:0px\n    \
+             style 1 fill:#9ec5fe,stroke:#dc3545,stroke-width:4px\n    \
+             style 2 fill:#fd7e14\n    0 -- \"a (1)\" --> 1\n    0 -- \"b\" --> 1\n    \
+             0 -- \"[^ab]\" --> 2\n    1 -- \". (2)\" --> 2\n    2 -- \".\" --> 2\n    \
+             linkStyle 0 stroke:#0d6efd,stroke-width:3px\n    \
+             linkStyle 3 stroke:#fd7e14,stroke-width:3px\n```"
+        );
+        assert_eq!(
+            spex1.to_dot_run("b", &RenderOptions::default()),
+            "digraph spex {\n    rankdir=LR;\n    node [shape=circle, label=\"\"];\n    \
+             0 [style=filled, fillcolor=black];\n    \
+             1 [style=filled, fillcolor=\"#9ec5fe\", shape=doublecircle];\n    2;\n    \
+             0 -> 1 [label=\"a\"];\n    \
+             0 -> 1 [label=\"b (1)\", color=\"#0d6efd\", penwidth=2];\n    \
+             0 -> 2 [label=\"[^ab]\"];\n    1 -> 2 [label=\".\"];\n    \
+             2 -> 2 [label=\".\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let spex1 = Spex::build_by_chex(&Chex::new(vec!['"'], true));
+        assert_eq!(
+            spex1.to_dot(),
+            "digraph spex {\n    rankdir=LR;\n    node [shape=circle, label=\"\"];\n    \
+             0 [style=filled, fillcolor=black];\n    1 [shape=doublecircle];\n    2;\n    \
+             0 -> 1 [label=\"\\\"\"];\n    0 -> 2 [label=\"[^\\\"]\"];\n    \
+             1 -> 2 [label=\".\"];\n    2 -> 2 [label=\".\"];\n}\n"
+        );
+
+        let spex2 = parallel_edges();
+        assert_eq!(spex2.to_dot().matches(" -> ").count(), 5);
+        let options = RenderOptions::new().hide_dead(true).merge_edges(true);
+        assert_eq!(
+            spex2.to_dot_with(&options),
+            "digraph spex {\n    rankdir=LR;\n    node [shape=circle, label=\"\"];\n    \
+             0 [style=filled, fillcolor=black];\n    1 [shape=doublecircle];\n    \
+             0 -> 1 [label=\"[ab]\"];\n}\n"
+        );
+        let options = options.state_labels(true).direction(Direction::TopDown);
+        assert_eq!(
+            spex2.to_dot_with(&options),
+            "digraph spex {\n    rankdir=TB;\n    node [shape=circle];\n    \
+             0 [style=filled, fillcolor=black, fontcolor=white];\n    1 [shape=doublecircle];\n    \
+             0 -> 1 [label=\"[ab]\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_to_expression() {
+        for src in [
+            "(a+|ab)&!a",
+            "((a[bc])+&!((ac)+))|a+",
+            "!(.*foo.*)",
+            "a*b|c",
+            "ab?c",
+            "(ab|c){2}",
+            "~>abc",
+            "\\d+(\\.\\d+)?",
+            "[^a]*a[^a]*",
+            "(a(?=bc)|ab)(b|c)+",
+            "x = [ab]; (x\\-)+",
+        ] {
+            let expected = spex(src);
+            let expression = expected.to_expression();
+            assert!(spex(&expression) == expected, "{} -> {}", src, expression);
+        }
+        // 状態の多いDFAの式も、上限の中で組み立て直せる
+        let expected = spex(".*a.{2}");
+        assert!(expected.minimize().tns_dict.len() >= 8);
+        let limits = Limits {
+            max_states: 2000,
+            ..Default::default()
+        };
+        let rebuilt = try_spex_with(expected.to_expression(), &Options::default(), &limits);
+        assert!(rebuilt.unwrap() == expected);
+        assert_eq!(spex("ab").to_expression(), "ab");
+        assert_eq!(spex("a|b|c").to_expression(), "[abc]");
+        assert_eq!(spex("(a|b)+").to_expression(), "[ab]+");
+        assert_eq!(spex("ab*").to_string(), "ab*");
+        assert_eq!(spex("a?").to_string(), "a?");
+        assert_eq!(spex(".*").to_string(), ".*");
+        assert_eq!(spex("a&b").to_string(), "[]");
+        assert_eq!(Spex::empty_string().to_string(), "!.+");
+    }
+
+    #[test]
+    fn test_affix() {
+        assert!(spex("~abc") == spex(".*abc.*"));
+        assert!(spex("~<abc") == spex("abc.*"));
+        assert!(spex("~>abc") == spex(".*abc"));
+        assert!(spex("~aba") == spex("!(!(.*aba.*))"));
+        assert!(spex("~>(ab|b)") == spex(".*b"));
+        assert!(spex("~error&!~warn").is_match("an error occurred"));
+        assert!(!spex("~error&!~warn").is_match("warn: error"));
+        assert!(spex("~a*").whole());
+        assert!(spex("~>a*").whole());
+        assert!(spex("~(a&b)").blank());
+        assert!(spex("!~x") == spex("[^x]*"));
+        assert!(spex("~ab|c") == spex("(.*ab.*)|c"));
+        assert!(spex("\\~a").is_match("~a"));
+        assert!(spex("a<b>").is_match("a<b>"));
+        assert!(Spex::containing(&spex("x")) == Spex::starting_with(&spex(".*x")));
+        assert!(spex("~<a*") == spex(".*"));
+        assert!(spex("~<a*").whole());
+        assert!((!&Spex::starting_with(&spex("a*"))).blank());
+        assert!(Spex::from_bytes(&spex("~<a*").to_bytes()).unwrap() == spex(".*"));
+        assert!(spex("[~]").is_match("~"));
+        assert!(spex("[&|!~]+").is_match("&|!~"));
+        assert!(spex("[&|!~]") == spex("\\&|\\||\\!|\\~"));
+        assert!(spex("a~b").is_match("a~b"));
+        assert!(spex("a~<b>*").is_match("a~<b>>"));
+        assert!(spex("(a|~b)~") == spex("(a|.*b.*)\\~"));
+    }
 }
@@ -0,0 +1,396 @@
+use crate::parser::{
+    get_and_node, get_bounded_node, get_concat_node, get_containing_node, get_ending_with_node,
+    get_exc_chex, get_inc_chex, get_invert_node, get_let_node, get_lookaround_node,
+    get_optional_node, get_or_node, get_repeat_node, get_star_node, get_starting_with_node, Node,
+    Span,
+};
+use crate::printer::to_source;
+
+type Predicate = fn(&Node) -> bool;
+
+/// 書き換えの規則
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// `a|a` → `a`、`a&a` → `a`
+    Idempotence,
+    /// `!!a` → `a`
+    DoubleNegation,
+    /// `a|(a&b)` → `a`、`a&(a|b)` → `a`
+    Absorption,
+    /// `a|[]` → `a`、`a&.*` → `a`、`a&.+` → `a`(aが空文字列を含まないとき)、`a[]` → `[]` など
+    Identity,
+    /// `(a|b)|c` → `a|b|c`、`a(bc)` → `abc`
+    Flatten,
+    /// `(a+)+` → `a+`、`(a?)*` → `a*` など
+    RepeatCollapse,
+}
+
+/// 適用した規則と、書き換える前の位置
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fired {
+    pub rule: Rule,
+    pub span: Span,
+}
+
+/// 組み立てる前に、言語を変えない書き換えで木を簡単にする
+pub fn optimize(node: &Node) -> (Node, Vec<Fired>) {
+    let mut fired = vec![];
+    let node = optimize_node(node, &mut fired);
+
+    (node, fired)
+}
+
+fn optimize_node(node: &Node, fired: &mut Vec<Fired>) -> Node {
+    let span = node.span();
+    let fire = |fired: &mut Vec<Fired>, rule: Rule| fired.push(Fired { rule, span });
+    match node {
+        Node::And { .. } | Node::Or { .. } => {
+            let is_and = matches!(node, Node::And { .. });
+            let mut operands = vec![];
+            if collect_operands(node, is_and, &mut operands) {
+                fire(fired, Rule::Flatten);
+            }
+            let operands: Vec<Node> = operands
+                .iter()
+                .map(|operand| optimize_node(operand, fired))
+                .collect();
+            simplify_operands(operands, is_and, span, fired)
+        }
+        Node::Invert { node, .. } => match optimize_node(node, fired) {
+            Node::Invert { node, .. } => {
+                fire(fired, Rule::DoubleNegation);
+                *node
+            }
+            node => get_invert_node(node, span),
+        },
+        Node::Concat { nodes, .. } => {
+            let mut flat: Vec<Node> = vec![];
+            for node in nodes {
+                match optimize_node(node, fired) {
+                    Node::Concat { nodes, .. } => {
+                        fire(fired, Rule::Flatten);
+                        flat.extend(nodes);
+                    }
+                    node => flat.push(node),
+                }
+            }
+            if flat.iter().any(is_blank) {
+                fire(fired, Rule::Identity);
+                get_inc_chex(&[], span)
+            } else {
+                get_concat_node(flat)
+            }
+        }
+        Node::Repeat { node: inner, .. }
+        | Node::Optional { node: inner, .. }
+        | Node::Star { node: inner, .. } => {
+            let outer = postfix(node).unwrap().0;
+            let inner = optimize_node(inner, fired);
+            match postfix(&inner) {
+                Some((kind, inner_node)) => {
+                    fire(fired, Rule::RepeatCollapse);
+                    let kind = if kind == outer { kind } else { Postfix::Star };
+                    get_postfix_node(kind, inner_node.clone(), span)
+                }
+                None => get_postfix_node(outer, inner, span),
+            }
+        }
+        Node::Bounded { node, min, max, .. } => {
+            get_bounded_node(optimize_node(node, fired), *min, *max, span)
+        }
+        Node::Containing { node, .. } => get_containing_node(optimize_node(node, fired), span),
+        Node::StartingWith { node, .. } => get_starting_with_node(optimize_node(node, fired), span),
+        Node::EndingWith { node, .. } => get_ending_with_node(optimize_node(node, fired), span),
+        Node::Lookaround {
+            node,
+            behind,
+            negative,
+            ..
+        } => get_lookaround_node(optimize_node(node, fired), *behind, *negative, span),
+        Node::Let { defs, body, .. } => get_let_node(
+            defs.iter()
+                .map(|(name, node)| (name.clone(), optimize_node(node, fired)))
+                .collect(),
+            optimize_node(body, fired),
+            span,
+        ),
+        Node::IncChex { .. } | Node::ExcChex { .. } | Node::Ref { .. } => node.clone(),
+    }
+}
+
+/// `&` または `|` が続く部分の要素を集める
+/// 左側に同じ演算子があって並べ直したならtrue
+pub(crate) fn collect_operands(node: &Node, is_and: bool, operands: &mut Vec<Node>) -> bool {
+    match node {
+        Node::And { left, right, .. } if is_and => {
+            let flattened = matches!(left.as_ref(), Node::And { .. });
+            collect_operands(left, is_and, operands)
+                | collect_operands(right, is_and, operands)
+                | flattened
+        }
+        Node::Or { left, right, .. } if !is_and => {
+            let flattened = matches!(left.as_ref(), Node::Or { .. });
+            collect_operands(left, is_and, operands)
+                | collect_operands(right, is_and, operands)
+                | flattened
+        }
+        _ => {
+            operands.push(node.clone());
+            false
+        }
+    }
+}
+
+fn simplify_operands(
+    operands: Vec<Node>,
+    is_and: bool,
+    span: Span,
+    fired: &mut Vec<Fired>,
+) -> Node {
+    let fire = |fired: &mut Vec<Fired>, rule: Rule| fired.push(Fired { rule, span });
+
+    // 書き換えで現れた同じ演算子を平らにする
+    let mut flat: Vec<Node> = vec![];
+    for operand in operands {
+        let mut inner = vec![];
+        collect_operands(&operand, is_and, &mut inner);
+        if inner.len() > 1 {
+            fire(fired, Rule::Flatten);
+        }
+        flat.extend(inner);
+    }
+
+    // 単位元と零元
+    let (unit, zero): (Predicate, Predicate) = if is_and {
+        (is_whole, is_blank)
+    } else {
+        (is_blank, is_whole)
+    };
+    if flat.iter().any(zero) {
+        fire(fired, Rule::Identity);
+        return flat.into_iter().find(zero).unwrap();
+    }
+    let len = flat.len();
+    flat.retain(|operand| !unit(operand));
+    if is_and
+        && flat
+            .iter()
+            .any(|operand| !is_whole_plus(operand) && nullable(operand) == Some(false))
+    {
+        flat.retain(|operand| !is_whole_plus(operand));
+    }
+    if flat.len() != len {
+        fire(fired, Rule::Identity);
+    }
+    if flat.is_empty() {
+        return if is_and {
+            get_star_node(get_exc_chex(&[], span), span)
+        } else {
+            get_inc_chex(&[], span)
+        };
+    }
+
+    let mut operands: Vec<Node> = vec![];
+    for operand in flat {
+        if operands.iter().any(|other| same(other, &operand)) {
+            fire(fired, Rule::Idempotence);
+        } else {
+            operands.push(operand);
+        }
+    }
+
+    // a|(a&b) → a、a&(a|b) → a
+    let absorbed: Vec<bool> = operands
+        .iter()
+        .enumerate()
+        .map(|(i, operand)| {
+            let mut inner = vec![];
+            collect_operands(operand, !is_and, &mut inner);
+            inner.len() > 1
+                && operands
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| i != j && inner.iter().any(|inner| same(inner, other)))
+        })
+        .collect();
+    if absorbed.contains(&true) {
+        fire(fired, Rule::Absorption);
+        operands = operands
+            .into_iter()
+            .zip(absorbed)
+            .filter(|(_, absorbed)| !absorbed)
+            .map(|(operand, _)| operand)
+            .collect();
+    }
+
+    let last = operands.pop().unwrap();
+    operands.into_iter().rev().fold(last, |right, left| {
+        if is_and {
+            get_and_node(&left, &right)
+        } else {
+            get_or_node(&left, &right)
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Postfix {
+    Repeat,
+    Optional,
+    Star,
+}
+
+fn postfix(node: &Node) -> Option<(Postfix, &Node)> {
+    match node {
+        Node::Repeat { node, .. } => Some((Postfix::Repeat, node)),
+        Node::Optional { node, .. } => Some((Postfix::Optional, node)),
+        Node::Star { node, .. } => Some((Postfix::Star, node)),
+        _ => None,
+    }
+}
+
+fn get_postfix_node(kind: Postfix, node: Node, span: Span) -> Node {
+    match kind {
+        Postfix::Repeat => get_repeat_node(node, span),
+        Postfix::Optional => get_optional_node(node, span),
+        Postfix::Star => get_star_node(node, span),
+    }
+}
+
+/// 木の形が同じか(位置は見ない)
+fn same(a: &Node, b: &Node) -> bool {
+    to_source(a) == to_source(b)
+}
+
+/// `[]`
+fn is_blank(node: &Node) -> bool {
+    matches!(node, Node::IncChex { ranges, .. } if ranges.is_empty())
+}
+
+/// `.`
+fn is_whole_chex(node: &Node) -> bool {
+    matches!(node, Node::ExcChex { ranges, .. } if ranges.is_empty())
+}
+
+/// `.*`
+fn is_whole(node: &Node) -> bool {
+    matches!(node, Node::Star { node, .. } if is_whole_chex(node))
+}
+
+/// `.+`
+fn is_whole_plus(node: &Node) -> bool {
+    matches!(node, Node::Repeat { node, .. } if is_whole_chex(node))
+}
+
+/// 空文字列を含むか。木の形だけでは決まらなければNone
+fn nullable(node: &Node) -> Option<bool> {
+    match node {
+        Node::IncChex { .. } | Node::ExcChex { .. } => Some(false),
+        Node::Star { .. } | Node::Optional { .. } => Some(true),
+        Node::Repeat { node, .. }
+        | Node::Containing { node, .. }
+        | Node::StartingWith { node, .. }
+        | Node::EndingWith { node, .. } => nullable(node),
+        Node::Bounded { node, min, .. } => {
+            if *min == 0 {
+                Some(true)
+            } else {
+                nullable(node)
+            }
+        }
+        Node::Invert { node, .. } => nullable(node).map(|nullable| !nullable),
+        Node::Concat { nodes, .. } => nodes.iter().map(nullable).try_fold(true, |acc, nullable| {
+            nullable.map(|nullable| acc && nullable)
+        }),
+        Node::And { left, right, .. } => match (nullable(left), nullable(right)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+        Node::Or { left, right, .. } => match (nullable(left), nullable(right)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+        Node::Let { body, .. } => nullable(body),
+        Node::Lookaround { .. } | Node::Ref { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_spex;
+    use crate::parser::{parse, tokenize};
+
+    /// 書き換えた結果と、規則が発火したことと、言語が変わらないことを確かめる
+    fn check(src: &str, expected: &str, rule: Rule) {
+        let node = parse(&tokenize(src));
+        let (optimized, fired) = optimize(&node);
+        assert_eq!(to_source(&optimized), expected, "{}", src);
+        assert!(
+            fired.iter().any(|fired| fired.rule == rule),
+            "{} {:?}",
+            src,
+            fired
+        );
+        assert!(build_spex(node) == build_spex(optimized), "{}", src);
+    }
+
+    #[test]
+    fn optimize_idempotence() {
+        check("a|a", "a", Rule::Idempotence);
+        check("ab&ab&c", "ab&c", Rule::Idempotence);
+        check("(x|y)|x", "x|y", Rule::Idempotence);
+    }
+
+    #[test]
+    fn optimize_double_negation() {
+        check("!!x", "x", Rule::DoubleNegation);
+        check("(!!(ab))c", "abc", Rule::DoubleNegation);
+    }
+
+    #[test]
+    fn optimize_absorption() {
+        check("a|(a&b)", "a", Rule::Absorption);
+        check("(x|y)&x", "x", Rule::Absorption);
+    }
+
+    #[test]
+    fn optimize_identity() {
+        check("x&.+", "x", Rule::Identity);
+        check("x*&.*", "x*", Rule::Identity);
+        check("x|[]", "x", Rule::Identity);
+        check("x|.*", ".*", Rule::Identity);
+        check("a[]b", "[]", Rule::Identity);
+        check("x?&.+&x?", "x?&.+", Rule::Idempotence);
+    }
+
+    #[test]
+    fn optimize_flatten() {
+        check("(a|b)|c", "a|b|c", Rule::Flatten);
+        check("a(bc)d", "abcd", Rule::Flatten);
+        check("((a&b)&c)&d", "a&b&c&d", Rule::Flatten);
+    }
+
+    #[test]
+    fn optimize_repeat_collapse() {
+        check("(x+)+", "x+", Rule::RepeatCollapse);
+        check("(x*)*", "x*", Rule::RepeatCollapse);
+        check("(x?)+", "x*", Rule::RepeatCollapse);
+        check("((ab)+)?", "(ab)*", Rule::RepeatCollapse);
+        check("((x?)?)?", "x?", Rule::RepeatCollapse);
+    }
+
+    #[test]
+    fn optimize_report_span() {
+        let (_, fired) = optimize(&parse(&tokenize("a(!!b)")));
+        assert_eq!(
+            fired,
+            vec![Fired {
+                rule: Rule::DoubleNegation,
+                span: Span::new(1, 6),
+            }]
+        );
+    }
+}
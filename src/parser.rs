@@ -1,191 +1,916 @@
-use crate::token::{self, Token};
+use std::borrow::Borrow;
+use std::fmt::{self, Display};
+use std::ops::Deref;
 
-type Tokens = Vec<String>;
+use crate::chex::{Chex, Range};
+use crate::token::{Dialect, Token};
+
+pub type Tokens = Vec<Lexeme>;
+
+/// `(?` に続く修飾子。`(?i)` は括弧の残り全体に、`(?i:X)` はXだけに効く
+/// `(?=X)` `(?!X)` `(?<=X)` `(?<!X)` は先読み・後読み
+const GROUP_MODIFIERS: [&str; 6] = ["i)", "i:", "=", "!", "<=", "<!"];
+
+/// ソース上の位置(文字単位で、endは含まない)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// 両方を覆う位置
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// ソース上の位置を持つトークン
+#[derive(Debug, Clone)]
+pub struct Lexeme {
+    pub text: String,
+    pub span: Span,
+}
+
+impl Lexeme {
+    pub fn new(text: impl Into<String>, span: Span) -> Self {
+        Self {
+            text: text.into(),
+            span,
+        }
+    }
+}
+
+impl Deref for Lexeme {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.text
+    }
+}
+
+impl Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Borrow<str> for Lexeme {
+    fn borrow(&self) -> &str {
+        &self.text
+    }
+}
+
+impl PartialEq for Lexeme {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl PartialEq<String> for Lexeme {
+    fn eq(&self, other: &String) -> bool {
+        &self.text == other
+    }
+}
+
+impl PartialEq<&str> for Lexeme {
+    fn eq(&self, other: &&str) -> bool {
+        self.text == *other
+    }
+}
+
+/// トークン列全体の位置
+fn tokens_span(tokens: &[Lexeme]) -> Span {
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => first.span.to(last.span),
+        _ => Span::default(),
+    }
+}
 
 pub fn tokenize(input_str: impl Into<String>) -> Tokens {
+    tokenize_with(input_str, false, &Dialect::default())
+}
+
+/// 文字集合の外の空白を無視し、`#` から行末までをコメントとして読み飛ばす
+/// 空白や `#` そのものは `\ ` `\#` のようにエスケープするか文字集合に入れる
+pub fn tokenize_verbose(input_str: impl Into<String>) -> Tokens {
+    tokenize_with(input_str, true, &Dialect::default())
+}
+
+/// dialectの表で読み、既定の表のトークンにして返す。位置はソース上のまま
+pub fn tokenize_with(input_str: impl Into<String>, verbose: bool, dialect: &Dialect) -> Tokens {
     let mut ret: Tokens = Vec::new();
+    let chars: Vec<char> = input_str.into().chars().collect();
+    let is = |char: &char, token: Token| *char == dialect.value(token);
     let mut escape_flg = false;
-    for ref char in input_str.into().chars() {
+    let mut in_ch = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let char = &chars[i];
+        let start = if escape_flg { i - 1 } else { i };
+        let mut push =
+            |text: String, end: usize| ret.push(Lexeme::new(text, Span::new(start, end)));
         if escape_flg {
-            if char == &Token::ESC.value() {
-                ret.push(format!("{}{}", Token::ESC.value(), Token::ESC.value()))
-            } else if Token::escapes().contains(char) {
-                ret.push(format!("{}{}", Token::ESC.value(), char));
+            if is(char, Token::ESC)
+                || Token::escape_tokens().iter().any(|token| is(char, *token))
+                || Chex::shorthand(*char).is_some()
+                || (verbose && (char.is_whitespace() || is(char, Token::CMNT)))
+            {
+                push(format!("{}{}", Token::ESC.value(), char), i + 1);
+            } else if let Some((name, len)) = unicode_class_name(&chars[i..]) {
+                push(
+                    format!("{}{}{{{}}}", Token::ESC.value(), char, name),
+                    i + len,
+                );
+                i += len - 1;
             } else {
-                ret.push(Token::ESC.value().to_string());
-                ret.push(char.to_string());
+                ret.push(Lexeme::new(literal(chars[i - 1]), Span::new(start, i)));
+                ret.push(Lexeme::new(literal(*char), Span::new(i, i + 1)));
             }
             escape_flg = false;
-        } else if char == &Token::ESC.value() {
+        } else if is(char, Token::ESC) {
             escape_flg = true;
+        } else if verbose && !in_ch && char.is_whitespace() {
+            // 空白は無視する
+        } else if verbose && !in_ch && is(char, Token::CMNT) {
+            while i + 1 < chars.len() && chars[i + 1] != '\n' {
+                i += 1;
+            }
+        } else if in_ch && is(char, Token::CH_S) && chars.get(i + 1) == Some(&':') {
+            // [:alpha:]
+            let end = chars[i + 2..]
+                .windows(2)
+                .position(|window| window[0] == ':' && is(&window[1], Token::CH_E));
+            match end {
+                Some(end) if end > 0 => {
+                    let name: String = chars[i + 2..i + 2 + end].iter().collect();
+                    push(
+                        format!("{}:{}:{}", Token::CH_S.value(), name, Token::CH_E.value()),
+                        i + end + 4,
+                    );
+                    i += end + 3;
+                }
+                _ => push(Token::CH_S.value().to_string(), i + 1),
+            }
+        } else if !in_ch
+            && is(char, Token::CONT)
+            && chars
+                .get(i + 1)
+                .is_some_and(|next| is(next, Token::HEAD) || is(next, Token::TAIL))
+        {
+            // ~< ~>
+            push(to_default(&chars[i..i + 2], dialect).unwrap(), i + 2);
+            i += 1;
+        } else if let Some(len) = group_token_len(&chars[i..], dialect).filter(|_| !in_ch) {
+            // (?i) (?i: (?= (?! (?<= (?<!
+            push(to_default(&chars[i..i + len], dialect).unwrap(), i + len);
+            i += len - 1;
+        } else if let Some(len) = count_token_len(&chars[i..], dialect).filter(|_| !in_ch) {
+            // {n} {n,} {n,m}
+            push(to_default(&chars[i..i + len], dialect).unwrap(), i + len);
+            i += len - 1;
         } else {
-            ret.push(char.to_string());
+            if is(char, Token::CH_S) {
+                in_ch = true;
+            } else if is(char, Token::CH_E) {
+                in_ch = false;
+            }
+            match dialect
+                .token(*char)
+                .filter(|token| Token::escape_tokens().contains(token))
+            {
+                Some(token) if in_ch && !Token::chex_tokens().contains(&token) => {
+                    push(literal(token.value()), i + 1)
+                }
+                Some(token) => push(token.value().to_string(), i + 1),
+                None => push(literal(*char), i + 1),
+            }
         }
+        i += 1;
     }
     if escape_flg {
-        ret.push(format!("{}{}", Token::ESC.value(), Token::ESC.value()))
+        ret.push(Lexeme::new(
+            format!("{}{}", Token::ESC.value(), Token::ESC.value()),
+            Span::new(chars.len() - 1, chars.len()),
+        ))
     }
 
     ret
 }
 
-pub fn parse(tokens: &Tokens) -> Node {
-    parse_and_or(tokens)
-}
-
-pub fn parse_and_or(tokens: &Tokens) -> Node {
-    let mut gourp_level = 0;
-    for (i, token) in tokens.iter().enumerate() {
-        if *token == Token::SP_S.value().to_string() {
-            gourp_level += 1;
-        } else if *token == Token::SP_E.value().to_string() {
-            gourp_level -= 1;
-        } else if gourp_level == 0 {
-            if *token == Token::AND.value().to_string() {
-                return get_and_node(
-                    &parse_and_or(&tokens[..i].to_vec()),
-                    &parse_and_or(&tokens[i + 1..].to_vec()),
-                );
-            } else if *token == Token::OR.value().to_string() {
-                return get_or_node(
-                    &parse_and_or(&tokens[..i].to_vec()),
-                    &parse_and_or(&tokens[i + 1..].to_vec()),
-                );
-            }
+/// 文字として読んだ文字を、既定の表で同じ文字を表すトークンにする
+fn literal(char: char) -> String {
+    Token::escape(char)
+}
+
+/// 構文として読んだ文字の並びを既定の表の文字にする
+fn to_default(chars: &[char], dialect: &Dialect) -> Option<String> {
+    chars.iter().map(|char| dialect.to_default(*char)).collect()
+}
+
+/// `(?i)` `(?i:` `(?=` などの文字数を得る
+fn group_token_len(chars: &[char], dialect: &Dialect) -> Option<usize> {
+    if !chars.starts_with(&[dialect.value(Token::SP_S), dialect.value(Token::OPT)]) {
+        return None;
+    }
+    let rest: String = chars[2..]
+        .iter()
+        .take(2)
+        .map_while(|char| dialect.to_default(*char))
+        .collect();
+
+    GROUP_MODIFIERS
+        .iter()
+        .find(|modifier| rest.starts_with(*modifier))
+        .map(|modifier| modifier.len() + 2)
+}
+
+/// `(` や `(?i:` `(?=` のように括弧を開くトークン
+fn is_group_start(token: &str) -> bool {
+    token.starts_with(Token::SP_S.value()) && !token.ends_with(Token::SP_E.value())
+}
+
+/// 括弧の残り全体に効く `(?i)`。途中に置くと、そこから括弧の終わりまでに効く
+fn is_flag_token(token: &str) -> bool {
+    token.len() > 2
+        && token.starts_with(Token::SP_S.value())
+        && token.ends_with(Token::SP_E.value())
+}
+
+/// `{n}` `{n,}` `{n,m}` の文字数を得る
+fn count_token_len(chars: &[char], dialect: &Dialect) -> Option<usize> {
+    if chars[0] != dialect.value(Token::CNT_S) {
+        return None;
+    }
+    let len = chars
+        .iter()
+        .position(|char| *char == dialect.value(Token::CNT_E))?;
+    let body: String = chars[1..len].iter().collect();
+    let mut nums = body.splitn(2, ',');
+    let min = nums.next().unwrap();
+    let valid = !min.is_empty()
+        && min.chars().all(|char| char.is_ascii_digit())
+        && nums.all(|max| max.chars().all(|char| char.is_ascii_digit()));
+
+    if valid {
+        Some(len + 1)
+    } else {
+        None
+    }
+}
+
+/// `p{L}` `pL` からクラス名と消費した文字数を得る
+fn unicode_class_name(chars: &[char]) -> Option<(String, usize)> {
+    if chars[0] != 'p' && chars[0] != 'P' {
+        return None;
+    }
+    match chars.get(1) {
+        Some('{') => {
+            let len = chars.iter().position(|char| *char == '}')?;
+            Some((chars[2..len].iter().collect(), len + 1))
         }
+        Some(char) if char.is_ascii_alphabetic() => Some((char.to_string(), 2)),
+        _ => None,
     }
-    if gourp_level != 0 {
-        panic!("SyntaxError {} not enough", Token::SP_E.value());
+}
+
+/// パースのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Syntax {
+        message: String,
+    },
+    /// 括弧の入れ子と演算子の連なりが上限より深い
+    Depth {
+        limit: usize,
+        span: Span,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax { message } => f.write_str(message),
+            ParseError::Depth { limit, span } => write!(
+                f,
+                "{}..{}: nesting deeper than {}",
+                span.start, span.end, limit
+            ),
+        }
     }
+}
+
+impl std::error::Error for ParseError {}
 
-    return parse_invert(tokens);
+fn syntax(message: String) -> ParseError {
+    ParseError::Syntax { message }
 }
 
-pub fn parse_invert(tokens: &Tokens) -> Node {
-    if tokens.len() == 0 {
-        panic!("SyntaxError invalid blank node")
-    } else if tokens[0] == Token::INVT.value().to_string() {
-        get_invert_node(parse_concat(&tokens[1..].to_vec()))
-    } else {
-        parse_concat(&tokens)
+/// 構文エラーならpanicする
+pub fn parse(tokens: &Tokens) -> Node {
+    try_parse(tokens, usize::MAX).unwrap_or_else(|error| panic!("SyntaxError {}", error))
+}
+
+/// parseと同じだが、構文エラーと、括弧の入れ子と `&` `|` `!` `~` の連なりが
+/// max_depthより深いものをErrにする
+pub fn try_parse(tokens: &Tokens, max_depth: usize) -> Result<Node, ParseError> {
+    Parser::new(max_depth).parse(tokens)
+}
+
+/// `;` で文に分け、前後の空白を取り除く
+pub fn split_statements(tokens: &[Lexeme]) -> Vec<Tokens> {
+    tokens
+        .split(|token| token == &Token::SEP.value().to_string())
+        .map(trim_blank)
+        .collect()
+}
+
+/// `name = expr` を定義名と式のトークンに分ける
+pub fn parse_def(statement: &[Lexeme]) -> Result<(String, Tokens), ParseError> {
+    let pos = statement
+        .iter()
+        .position(|token| token == &Token::DEF.value().to_string())
+        .ok_or_else(|| syntax(format!("{} not enough", Token::DEF.value())))?;
+    let name: String = trim_blank(&statement[..pos]).concat();
+    if !is_ident(&name) {
+        return Err(syntax(format!("{} invalid definition name", name)));
     }
+
+    Ok((name, trim_blank(&statement[pos + 1..])))
 }
 
-pub fn parse_concat(tokens: &Tokens) -> Node {
-    let mut gourp_level = 0;
-    let mut in_ch = false;
-    let mut current_tokens: Tokens = vec![];
-    let mut node_kind = NodeKind::UNNECESSARY;
-    let mut nodes: Vec<Node> = vec![];
+/// 定義の式を、namesの定義名を参照にして解析する
+pub fn parse_def_body(tokens: &[Lexeme], names: &[String]) -> Result<Node, ParseError> {
+    Parser::new(usize::MAX).parse_def_body(tokens, names)
+}
 
-    for (i, token) in tokens.iter().enumerate() {
-        if node_kind != NodeKind::UNNECESSARY {
-            let mut node = match node_kind {
-                NodeKind::GROUP => parse_and_or(&current_tokens),
-                NodeKind::SINGLE => parse_inc_chex(&current_tokens),
-                NodeKind::MULTI => parse_chex(&current_tokens),
-                _ => unreachable!(),
-            };
-            current_tokens = vec![];
-            node_kind = NodeKind::UNNECESSARY;
-            if token == &Token::REPT.value().to_string() {
-                node = get_repeat_node(node);
-                nodes.push(node);
-                continue;
-            }
-            nodes.push(node);
-        }
-
-        if gourp_level == 0 {
-            if token == &Token::INVT.value().to_string() {
-                panic!("SyntaxError {} Npt at the beginning", Token::INVT.value());
-            } else if token == &Token::SP_E.value().to_string() {
-                panic!("SyntaxError {} invalid position", Token::SP_E.value());
-            } else if token == &Token::REPT.value().to_string() {
-                panic!("SyntaxError {} invalid position", Token::REPT.value());
-            } else if token == &Token::SP_S.value().to_string() {
-                if in_ch {
-                    panic!("SyntaxError {} invalid position", Token::SP_S.value());
-                }
-                gourp_level += 1;
-            } else if token == &Token::CH_S.value().to_string() {
-                if in_ch {
-                    panic!("SyntaxError {} invalid position", Token::CH_S.value());
-                }
+pub(crate) fn trim_blank(tokens: &[Lexeme]) -> Tokens {
+    let is_blank = |token: &Lexeme| token.chars().all(char::is_whitespace);
+    let start = tokens.iter().position(|token| !is_blank(token));
+    let end = tokens.iter().rposition(|token| !is_blank(token));
+    match (start, end) {
+        (Some(start), Some(end)) => tokens[start..=end].to_vec(),
+        _ => vec![],
+    }
+}
+
+pub(crate) fn is_ident_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || char == '_'
+}
+
+pub(crate) fn is_ident(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|char| !char.is_ascii_digit())
+        && name.chars().all(is_ident_char)
+}
+
+fn is_ref_token(token: &str) -> bool {
+    token.len() > 1 && token.starts_with(Token::DEF.value())
+}
+
+/// 定義名と一致する単語を参照のトークンにまとめる
+fn resolve_refs(tokens: &[Lexeme], names: &[String]) -> Tokens {
+    let is_word = |token: &Lexeme| token.chars().count() == 1 && token.chars().all(is_ident_char);
+    let mut ret: Tokens = vec![];
+    let mut in_ch = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        let len = if in_ch {
+            0
+        } else {
+            tokens[i..]
+                .iter()
+                .take_while(|token| is_word(token))
+                .count()
+        };
+        let word = tokens[i..i + len].concat();
+        if len > 0 && names.contains(&word) {
+            ret.push(Lexeme::new(
+                format!("{}{}", Token::DEF.value(), word),
+                tokens_span(&tokens[i..i + len]),
+            ));
+            i += len;
+            continue;
+        }
+        let len = len.max(1);
+        for token in &tokens[i..i + len] {
+            if token == &Token::CH_S.value().to_string() {
                 in_ch = true;
             } else if token == &Token::CH_E.value().to_string() {
-                if !in_ch {
-                    panic!("SyntaxError {} invalid position", Token::CH_E.value());
-                }
                 in_ch = false;
-                node_kind = NodeKind::MULTI;
-            } else {
-                current_tokens.push(token.clone());
-                if !in_ch {
-                    node_kind = NodeKind::SINGLE;
-                }
             }
+            ret.push(token.clone());
+        }
+        i += len;
+    }
+
+    ret
+}
+
+/// 参照される定義が先に来るように並べ替える
+/// 定義が循環していれば、その循環の定義名を返す
+pub fn sort_defs(defs: Vec<(String, Node)>) -> Result<Vec<(String, Node)>, Vec<String>> {
+    fn visit(
+        name: &str,
+        defs: &[(String, Node)],
+        path: &mut Vec<String>,
+        sorted: &mut Vec<(String, Node)>,
+    ) -> Result<(), Vec<String>> {
+        if sorted.iter().any(|(sorted_name, _)| sorted_name == name) {
+            return Ok(());
+        }
+        if let Some(pos) = path.iter().position(|path_name| path_name == name) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(name.to_string());
+            return Err(cycle);
+        }
+        let node = match defs.iter().find(|(def_name, _)| def_name == name) {
+            Some((_, node)) => node,
+            None => return Ok(()), // 外部の定義
+        };
+        path.push(name.to_string());
+        for ref_name in node.refs() {
+            visit(&ref_name, defs, path, sorted)?;
+        }
+        path.pop();
+        sorted.push((name.to_string(), node.clone()));
+
+        Ok(())
+    }
+
+    let mut sorted = vec![];
+    for (name, _) in &defs {
+        visit(name, &defs, &mut vec![], &mut sorted)?;
+    }
+
+    Ok(sorted)
+}
+
+/// 再帰下降のパーサ。辿っている深さを数え、max_depthを超えたらErrにする
+struct Parser {
+    max_depth: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(max_depth: usize) -> Self {
+        Self {
+            max_depth,
+            depth: 0,
+        }
+    }
+
+    fn parse(&mut self, tokens: &Tokens) -> Result<Node, ParseError> {
+        if tokens
+            .iter()
+            .any(|token| token == &Token::SEP.value().to_string())
+        {
+            self.parse_let(tokens)
         } else {
-            if token == &Token::SP_S.value().to_string() {
+            self.parse_and_or(tokens)
+        }
+    }
+
+    /// `name = expr; ... expr` の形の定義付きの式
+    fn parse_let(&mut self, tokens: &Tokens) -> Result<Node, ParseError> {
+        let statements = split_statements(tokens);
+        let (body, statements) = statements.split_last().unwrap();
+
+        let mut defs: Vec<(String, Tokens)> = vec![];
+        for statement in statements {
+            let (name, tokens) = parse_def(statement)?;
+            if defs.iter().any(|(def_name, _)| def_name == &name) {
+                return Err(syntax(format!("{} duplicate definition", name)));
+            }
+            defs.push((name, tokens));
+        }
+
+        let names: Vec<String> = defs.iter().map(|(name, _)| name.clone()).collect();
+        Ok(get_let_node(
+            self.parse_defs(&defs, &names)?,
+            self.parse_and_or(&resolve_refs(body, &names))?,
+            tokens_span(tokens),
+        ))
+    }
+
+    /// 定義の式を解析し、参照される定義が先に来るように並べる
+    fn parse_defs(
+        &mut self,
+        defs: &[(String, Tokens)],
+        names: &[String],
+    ) -> Result<Vec<(String, Node)>, ParseError> {
+        let defs = defs
+            .iter()
+            .map(|(name, tokens)| Ok((name.clone(), self.parse_def_body(tokens, names)?)))
+            .collect::<Result<_, ParseError>>()?;
+
+        sort_defs(defs).map_err(|cycle| syntax(format!("cyclic definition {}", cycle.join(" -> "))))
+    }
+
+    /// namesには外部の定義名も含めてよい
+    fn parse_def_body(&mut self, tokens: &[Lexeme], names: &[String]) -> Result<Node, ParseError> {
+        self.parse_and_or(&resolve_refs(tokens, names))
+    }
+
+    /// 一段深くしてfを呼ぶ
+    fn nest(
+        &mut self,
+        tokens: &[Lexeme],
+        f: impl FnOnce(&mut Self) -> Result<Node, ParseError>,
+    ) -> Result<Node, ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(ParseError::Depth {
+                limit: self.max_depth,
+                span: tokens_span(tokens),
+            });
+        }
+        self.depth += 1;
+        let ret = f(self);
+        self.depth -= 1;
+
+        ret
+    }
+
+    fn parse_and_or(&mut self, tokens: &Tokens) -> Result<Node, ParseError> {
+        self.nest(tokens, |parser| parser.split_and_or(tokens))
+    }
+
+    fn split_and_or(&mut self, tokens: &Tokens) -> Result<Node, ParseError> {
+        if let Some((flag, rest)) = tokens.split_first().filter(|(flag, _)| is_flag_token(flag)) {
+            return self.parse_group(flag, &rest.to_vec(), tokens_span(tokens));
+        }
+        let mut gourp_level = 0;
+        let mut flagged = false; // 途中の `(?i)` より後ろにいる
+        for (i, token) in tokens.iter().enumerate() {
+            if is_group_start(token) {
                 gourp_level += 1;
-                current_tokens.push(token.clone());
-            } else if token == &Token::SP_E.value().to_string() {
+            } else if *token == Token::SP_E.value().to_string() {
                 gourp_level -= 1;
-                if gourp_level != 0 {
-                    current_tokens.push(token.clone());
+            } else if gourp_level == 0 {
+                if is_flag_token(token) {
+                    flagged = true;
+                } else if *token == Token::AND.value().to_string()
+                    || *token == Token::OR.value().to_string()
+                {
+                    let left = self.parse_and_or(&tokens[..i].to_vec())?;
+                    let right = self.parse_and_or(&tokens[i + 1..].to_vec())?;
+                    let right = if flagged { right.case_fold() } else { right };
+                    return Ok(if *token == Token::AND.value().to_string() {
+                        get_and_node(&left, &right)
+                    } else {
+                        get_or_node(&left, &right)
+                    });
+                }
+            }
+        }
+        if gourp_level != 0 {
+            return Err(syntax(format!("{} not enough", Token::SP_E.value())));
+        }
+
+        self.parse_invert(tokens)
+    }
+
+    /// 先頭の `!` `~` `~<` `~>` はその後ろの連接全体に効く
+    fn parse_invert(&mut self, tokens: &Tokens) -> Result<Node, ParseError> {
+        if tokens.is_empty() {
+            return Err(syntax("invalid blank node".to_string()));
+        }
+        let rest = tokens[1..].to_vec();
+        let span = tokens_span(tokens);
+        Ok(match tokens[0].as_str() {
+            token if token == Token::INVT.value().to_string() => get_invert_node(
+                self.nest(tokens, |parser| parser.parse_invert(&rest))?,
+                span,
+            ),
+            token if is_affix_token(token) => {
+                let node = self.nest(tokens, |parser| parser.parse_invert(&rest))?;
+                match token.chars().nth(1) {
+                    None => get_containing_node(node, span),
+                    Some(char) if char == Token::HEAD.value() => get_starting_with_node(node, span),
+                    Some(_) => get_ending_with_node(node, span),
+                }
+            }
+            _ => self.parse_concat(tokens)?,
+        })
+    }
+
+    fn parse_concat(&mut self, tokens: &Tokens) -> Result<Node, ParseError> {
+        let mut gourp_level = 0;
+        let mut in_ch = false;
+        let mut current_tokens: Tokens = vec![];
+        let mut group_start = Token::SP_S.value().to_string();
+        let mut atom_span = Span::default(); // 括弧を含めた位置
+        let mut node_kind = NodeKind::UNNECESSARY;
+        let mut nodes: Vec<Node> = vec![];
+
+        for (i, token) in tokens.iter().enumerate() {
+            if node_kind != NodeKind::UNNECESSARY {
+                let mut node =
+                    self.parse_atom(&node_kind, &group_start, &current_tokens, atom_span)?;
+                current_tokens = vec![];
+                node_kind = NodeKind::UNNECESSARY;
+                let span = node.span().to(token.span);
+                if token == &Token::REPT.value().to_string() {
+                    node = get_repeat_node(node, span);
+                    nodes.push(node);
+                    continue;
+                } else if token == &Token::OPT.value().to_string() {
+                    node = get_optional_node(node, span);
+                    nodes.push(node);
+                    continue;
+                } else if token == &Token::STAR.value().to_string() {
+                    node = get_star_node(node, span);
+                    nodes.push(node);
+                    continue;
+                } else if is_count_token(token) {
+                    let (min, max) = parse_count(token)?;
+                    node = get_bounded_node(node, min, max, span);
+                    nodes.push(node);
+                    continue;
+                }
+                nodes.push(node);
+            }
+            if gourp_level == 0 && !in_ch {
+                atom_span = token.span;
+            }
+
+            if gourp_level == 0 {
+                if token == &Token::INVT.value().to_string() {
+                    return Err(syntax(format!(
+                        "{} Npt at the beginning",
+                        Token::INVT.value()
+                    )));
+                } else if is_affix_token(token) {
+                    // 先頭以外の `~` `~<` `~>` は文字
+                    let lexemes: Vec<Lexeme> = token
+                        .chars()
+                        .enumerate()
+                        .map(|(i, char)| {
+                            let start = token.span.start + i;
+                            Lexeme::new(literal(char), Span::new(start, start + 1))
+                        })
+                        .collect();
+                    let (last, rest) = lexemes.split_last().unwrap();
+                    for lexeme in rest {
+                        nodes.push(parse_inc_chex(&vec![lexeme.clone()])?);
+                    }
+                    current_tokens.push(last.clone());
+                    node_kind = NodeKind::SINGLE;
+                } else if token == &Token::SP_E.value().to_string() {
+                    return Err(syntax(format!("{} invalid position", Token::SP_E.value())));
+                } else if token == &Token::DEF.value().to_string() {
+                    // 定義の外の `=` は文字
+                    current_tokens.push(Lexeme::new(literal(Token::DEF.value()), token.span));
+                    node_kind = NodeKind::SINGLE;
+                } else if token == &Token::SEP.value().to_string()
+                    || token == &Token::REPT.value().to_string()
+                    || token == &Token::OPT.value().to_string()
+                    || token == &Token::STAR.value().to_string()
+                    || is_count_token(token)
+                {
+                    return Err(syntax(format!("{} invalid position", token)));
+                } else if is_flag_token(token) {
+                    // 途中の `(?i)` は連接の残りに効く。後ろの `|` `&` の先はparse_and_orで畳む
+                    let rest = tokens[i + 1..].to_vec();
+                    let node = self.nest(&rest, |parser| parser.parse_concat(&rest))?;
+                    nodes.push(node.case_fold());
+                    break;
+                } else if is_group_start(token) {
+                    if in_ch {
+                        return Err(syntax(format!("{} invalid position", Token::SP_S.value())));
+                    }
+                    group_start = token.text.clone();
+                    gourp_level += 1;
+                } else if token == &Token::CH_S.value().to_string() {
+                    if in_ch {
+                        return Err(syntax(format!("{} invalid position", Token::CH_S.value())));
+                    }
+                    in_ch = true;
+                } else if token == &Token::CH_E.value().to_string() {
+                    if !in_ch {
+                        return Err(syntax(format!("{} invalid position", Token::CH_E.value())));
+                    }
+                    in_ch = false;
+                    atom_span = atom_span.to(token.span);
+                    node_kind = NodeKind::MULTI;
                 } else {
-                    node_kind = NodeKind::GROUP;
+                    current_tokens.push(token.clone());
+                    if !in_ch {
+                        node_kind = NodeKind::SINGLE;
+                    }
                 }
             } else {
-                current_tokens.push(token.clone());
+                if is_group_start(token) {
+                    gourp_level += 1;
+                    current_tokens.push(token.clone());
+                } else if token == &Token::SP_E.value().to_string() {
+                    gourp_level -= 1;
+                    if gourp_level != 0 {
+                        current_tokens.push(token.clone());
+                    } else {
+                        atom_span = atom_span.to(token.span);
+                        node_kind = NodeKind::GROUP;
+                    }
+                } else {
+                    current_tokens.push(token.clone());
+                }
             }
         }
+
+        if gourp_level != 0 {
+            return Err(syntax(format!("{} not enough", Token::SP_E.value())));
+        }
+        if in_ch {
+            return Err(syntax(format!("{} not nough", Token::CH_E.value())));
+        }
+        if node_kind != NodeKind::UNNECESSARY {
+            nodes.push(self.parse_atom(&node_kind, &group_start, &current_tokens, atom_span)?);
+        }
+        if nodes.is_empty() {
+            Err(syntax("invalid blank code".to_string()))
+        } else if nodes.len() == 1 {
+            Ok(nodes[0].clone())
+        } else {
+            Ok(get_concat_node(nodes))
+        }
     }
 
-    if gourp_level != 0 {
-        panic!("SyntaxError {} not enough", Token::SP_E.value());
+    /// 括弧や文字集合の記号を含めた位置を付けて連接の要素を解析する
+    fn parse_atom(
+        &mut self,
+        node_kind: &NodeKind,
+        group_start: &str,
+        tokens: &Tokens,
+        span: Span,
+    ) -> Result<Node, ParseError> {
+        Ok(match node_kind {
+            NodeKind::GROUP => self.parse_group(group_start, tokens, span)?.with_span(span),
+            NodeKind::SINGLE => parse_inc_chex(tokens)?,
+            NodeKind::MULTI => parse_chex(tokens)?.with_span(span),
+            _ => unreachable!(),
+        })
     }
-    if in_ch {
-        panic!("SyntaxError {} not nough", Token::CH_E.value());
+
+    /// `(X)` `(?i:X)` `(?i)X` `(?=X)` などの括弧の中身
+    fn parse_group(
+        &mut self,
+        group_start: &str,
+        tokens: &Tokens,
+        span: Span,
+    ) -> Result<Node, ParseError> {
+        let node = self.parse_and_or(tokens)?;
+        if group_start.len() == 1 {
+            return Ok(node);
+        }
+        Ok(match &group_start[2..] {
+            "i)" | "i:" => node.case_fold(),
+            "=" => get_lookaround_node(node, false, false, span),
+            "!" => get_lookaround_node(node, false, true, span),
+            "<=" => get_lookaround_node(node, true, false, span),
+            "<!" => get_lookaround_node(node, true, true, span),
+            _ => return Err(syntax(format!("{} unknown group", group_start))),
+        })
     }
-    if node_kind != NodeKind::UNNECESSARY {
-        match node_kind {
-            NodeKind::GROUP => nodes.push(parse_and_or(&current_tokens)),
-            NodeKind::SINGLE => nodes.push(parse_inc_chex(&current_tokens)),
-            NodeKind::MULTI => nodes.push(parse_chex(&current_tokens)),
-            _ => unreachable!(),
+}
+
+/// `~` `~<` `~>`
+fn is_affix_token(token: &str) -> bool {
+    token.starts_with(Token::CONT.value()) && token.chars().count() <= 2
+}
+
+fn is_count_token(token: &str) -> bool {
+    token.len() > 1 && token.starts_with(Token::CNT_S.value())
+}
+
+/// `{n}` `{n,}` `{n,m}` から回数の下限と上限を得る
+pub fn parse_count(token: &str) -> Result<(usize, Option<usize>), ParseError> {
+    let body = &token[1..token.len() - 1];
+    let parse_num = |num: &str| {
+        num.parse::<usize>()
+            .map_err(|_| syntax(format!("{} invalid count", token)))
+    };
+    let (min, max) = match body.split_once(',') {
+        Some((min, "")) => (parse_num(min)?, None),
+        Some((min, max)) => (parse_num(min)?, Some(parse_num(max)?)),
+        None => (parse_num(body)?, Some(parse_num(body)?)),
+    };
+    if max.is_some_and(|max| max < min) {
+        return Err(syntax(format!("{} invalid count", token)));
+    }
+
+    Ok((min, max))
+}
+
+pub fn parse_chex(tokens: &Tokens) -> Result<Node, ParseError> {
+    let span = tokens_span(tokens);
+    Ok(
+        if !tokens.is_empty() && tokens[0] == Token::DENY.value().to_string() {
+            get_exc_chex(&get_ranges(&tokens[1..].to_vec())?, span)
+        } else {
+            get_inc_chex(&get_ranges(tokens)?, span)
+        },
+    )
+}
+
+pub fn parse_inc_chex(tokens: &Tokens) -> Result<Node, ParseError> {
+    let span = tokens_span(tokens);
+    if let [token] = tokens.as_slice() {
+        if is_ref_token(token) {
+            return Ok(get_ref_node(&token[1..], span));
         }
     }
-    if nodes.len() == 0 {
-        panic!("SyntaxError invalid blank code");
-    } else if nodes.len() == 1 {
-        return nodes[0].clone();
+    if tokens
+        .iter()
+        .any(|token| token == &Token::WHOL.value().to_string())
+    {
+        Ok(get_exc_chex(&[], span))
     } else {
-        return get_concat_node(nodes);
+        let chex = tokens
+            .iter()
+            .map(|token| parse_chex_item(token))
+            .try_fold(Chex::new_blank(), |a, b| Ok::<_, ParseError>(&a | &b?))?;
+        Ok(if chex.include_flg {
+            get_inc_chex(&chex.ranges, span)
+        } else {
+            get_exc_chex(&chex.ranges, span)
+        })
     }
 }
 
-pub fn parse_chex(tokens: &Tokens) -> Node {
-    if tokens.len() > 0 && tokens[0] == Token::DENY.value().to_string() {
-        get_exc_chex(&tokens[1..].to_vec())
+pub fn parse_exc_chex(tokens: &Tokens) -> Result<Node, ParseError> {
+    let span = tokens_span(tokens);
+    if tokens
+        .iter()
+        .any(|token| token == &Token::WHOL.value().to_string())
+    {
+        Ok(get_inc_chex(&[], span))
     } else {
-        get_inc_chex(tokens)
+        Ok(get_exc_chex(&get_ranges(tokens)?, span))
     }
 }
 
-pub fn parse_inc_chex(tokens: &Tokens) -> Node {
-    if tokens.contains(&Token::WHOL.value().to_string()) {
-        get_exc_chex(&vec![])
-    } else {
-        get_inc_chex(tokens)
+/// 文字集合の中身を文字の範囲にまとめる
+pub fn get_ranges(tokens: &Tokens) -> Result<Vec<Range>, ParseError> {
+    let range = Token::RANGE.value().to_string();
+    let mut chex = Chex::new_blank();
+    let mut i = 0;
+    while i < tokens.len() {
+        let item = if i + 2 < tokens.len() && tokens[i + 1] == range {
+            let start = parse_range_end(&tokens[i])?;
+            let end = parse_range_end(&tokens[i + 2])?;
+            if end < start {
+                return Err(syntax(format!(
+                    "{}{}{} invalid range",
+                    tokens[i],
+                    range,
+                    tokens[i + 2]
+                )));
+            }
+            i += 3;
+            Chex::from_ranges(vec![(start, end)], true)
+        } else {
+            i += 1;
+            parse_chex_item(&tokens[i - 1])?
+        };
+        chex = &chex | &item;
     }
+
+    Ok(chex.include_ranges())
 }
 
-pub fn parse_exc_chex(tokens: &Tokens) -> Node {
-    if tokens.contains(&Token::WHOL.value().to_string()) {
-        get_inc_chex(&vec![])
-    } else {
-        get_exc_chex(tokens)
+/// `\d` `\p{L}` `[:alpha:]` などのクラスまたは1文字
+pub fn parse_chex_item(token: &str) -> Result<Chex, ParseError> {
+    let esc = Token::ESC.value();
+    let mut chars = token.chars();
+    match (chars.next(), chars.next()) {
+        (Some(first), Some(name)) if first == esc && Chex::shorthand(name).is_some() => {
+            Ok(Chex::shorthand(name).unwrap())
+        }
+        (Some(first), Some(kind)) if first == esc && (kind == 'p' || kind == 'P') => {
+            let name = &token[3..token.len() - 1];
+            let chex = Chex::unicode(name)
+                .ok_or_else(|| syntax(format!("{} unknown unicode class", token)))?;
+            Ok(if kind == 'P' { !&chex } else { chex })
+        }
+        (Some(first), Some(':')) if first == Token::CH_S.value() && token.len() > 4 => {
+            let name = &token[2..token.len() - 2];
+            Chex::posix(name).ok_or_else(|| syntax(format!("{} unknown posix class", token)))
+        }
+        _ => Token::unescape(token)
+            .map(|char| Chex::new(vec![char], true))
+            .ok_or_else(|| syntax(format!("{} invalid token", token))),
+    }
+}
+
+fn parse_range_end(token: &str) -> Result<char, ParseError> {
+    match parse_chex_item(token)? {
+        chex if chex.include_flg && chex.len == 1 => Ok(chex.ranges[0].0),
+        _ => Err(syntax(format!("{} invalid range", token))),
     }
 }
 
@@ -193,6 +918,7 @@ pub fn get_and_node(left: &Node, right: &Node) -> Node {
     Node::And {
         left: Box::new(left.clone()),
         right: Box::new(right.clone()),
+        span: left.span().to(right.span()),
     }
 }
 
@@ -200,48 +926,343 @@ pub fn get_or_node(left: &Node, right: &Node) -> Node {
     Node::Or {
         left: Box::new(left.clone()),
         right: Box::new(right.clone()),
+        span: left.span().to(right.span()),
     }
 }
 
 pub fn get_concat_node(nodes: Vec<Node>) -> Node {
-    Node::Concat { nodes }
+    let span = match (nodes.first(), nodes.last()) {
+        (Some(first), Some(last)) => first.span().to(last.span()),
+        _ => Span::default(),
+    };
+    Node::Concat { nodes, span }
 }
 
-pub fn get_invert_node(node: Node) -> Node {
+pub fn get_invert_node(node: Node, span: Span) -> Node {
     Node::Invert {
         node: Box::new(node),
+        span,
     }
 }
 
-pub fn get_repeat_node(node: Node) -> Node {
+pub fn get_containing_node(node: Node, span: Span) -> Node {
+    Node::Containing {
+        node: Box::new(node),
+        span,
+    }
+}
+
+pub fn get_starting_with_node(node: Node, span: Span) -> Node {
+    Node::StartingWith {
+        node: Box::new(node),
+        span,
+    }
+}
+
+pub fn get_ending_with_node(node: Node, span: Span) -> Node {
+    Node::EndingWith {
+        node: Box::new(node),
+        span,
+    }
+}
+
+pub fn get_repeat_node(node: Node, span: Span) -> Node {
     Node::Repeat {
         node: Box::new(node),
+        span,
+    }
+}
+
+pub fn get_optional_node(node: Node, span: Span) -> Node {
+    Node::Optional {
+        node: Box::new(node),
+        span,
+    }
+}
+
+pub fn get_star_node(node: Node, span: Span) -> Node {
+    Node::Star {
+        node: Box::new(node),
+        span,
+    }
+}
+
+pub fn get_bounded_node(node: Node, min: usize, max: Option<usize>, span: Span) -> Node {
+    Node::Bounded {
+        node: Box::new(node),
+        min,
+        max,
+        span,
     }
 }
 
-pub fn get_inc_chex(tokens: &Tokens) -> Node {
+pub fn get_lookaround_node(node: Node, behind: bool, negative: bool, span: Span) -> Node {
+    Node::Lookaround {
+        node: Box::new(node),
+        behind,
+        negative,
+        span,
+    }
+}
+
+pub fn get_let_node(defs: Vec<(String, Node)>, body: Node, span: Span) -> Node {
+    Node::Let {
+        defs,
+        body: Box::new(body),
+        span,
+    }
+}
+
+pub fn get_ref_node(name: &str, span: Span) -> Node {
+    Node::Ref {
+        name: name.to_string(),
+        span,
+    }
+}
+
+pub fn get_inc_chex(ranges: &[Range], span: Span) -> Node {
     Node::IncChex {
-        tokens: tokens.clone(),
+        ranges: ranges.to_vec(),
+        span,
     }
 }
 
-pub fn get_exc_chex(tokens: &Tokens) -> Node {
+pub fn get_exc_chex(ranges: &[Range], span: Span) -> Node {
     Node::ExcChex {
-        tokens: tokens.clone(),
+        ranges: ranges.to_vec(),
+        span,
     }
 }
 
+/// 空文字列だけに一致する `!.+`
+pub fn get_empty_node(span: Span) -> Node {
+    get_invert_node(get_repeat_node(get_exc_chex(&[], span), span), span)
+}
+
+/// spanはソース上の位置で、括弧や文字集合の記号も含む
 #[derive(Debug, Clone)]
 pub enum Node {
-    And { left: Box<Node>, right: Box<Node> },
-    Or { left: Box<Node>, right: Box<Node> },
-    IncChex { tokens: Tokens },
-    ExcChex { tokens: Tokens },
-    Invert { node: Box<Node> },
-    Repeat { node: Box<Node> },
-    Concat { nodes: Vec<Node> },
+    And {
+        left: Box<Node>,
+        right: Box<Node>,
+        span: Span,
+    },
+    Or {
+        left: Box<Node>,
+        right: Box<Node>,
+        span: Span,
+    },
+    IncChex {
+        ranges: Vec<Range>,
+        span: Span,
+    },
+    ExcChex {
+        ranges: Vec<Range>,
+        span: Span,
+    },
+    Invert {
+        node: Box<Node>,
+        span: Span,
+    },
+    Containing {
+        node: Box<Node>,
+        span: Span,
+    },
+    StartingWith {
+        node: Box<Node>,
+        span: Span,
+    },
+    EndingWith {
+        node: Box<Node>,
+        span: Span,
+    },
+    Repeat {
+        node: Box<Node>,
+        span: Span,
+    },
+    Optional {
+        node: Box<Node>,
+        span: Span,
+    },
+    Star {
+        node: Box<Node>,
+        span: Span,
+    },
+    Bounded {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        span: Span,
+    },
+    Concat {
+        nodes: Vec<Node>,
+        span: Span,
+    },
+    /// behindなら後読み、negativeなら否定
+    Lookaround {
+        node: Box<Node>,
+        behind: bool,
+        negative: bool,
+        span: Span,
+    },
+    Let {
+        defs: Vec<(String, Node)>,
+        body: Box<Node>,
+        span: Span,
+    },
+    Ref {
+        name: String,
+        span: Span,
+    },
 }
 
+impl Node {
+    pub fn span(&self) -> Span {
+        match self {
+            Node::And { span, .. }
+            | Node::Or { span, .. }
+            | Node::IncChex { span, .. }
+            | Node::ExcChex { span, .. }
+            | Node::Invert { span, .. }
+            | Node::Containing { span, .. }
+            | Node::StartingWith { span, .. }
+            | Node::EndingWith { span, .. }
+            | Node::Repeat { span, .. }
+            | Node::Optional { span, .. }
+            | Node::Star { span, .. }
+            | Node::Bounded { span, .. }
+            | Node::Concat { span, .. }
+            | Node::Lookaround { span, .. }
+            | Node::Let { span, .. }
+            | Node::Ref { span, .. } => *span,
+        }
+    }
+
+    fn with_span(mut self, new_span: Span) -> Node {
+        match &mut self {
+            Node::And { span, .. }
+            | Node::Or { span, .. }
+            | Node::IncChex { span, .. }
+            | Node::ExcChex { span, .. }
+            | Node::Invert { span, .. }
+            | Node::Containing { span, .. }
+            | Node::StartingWith { span, .. }
+            | Node::EndingWith { span, .. }
+            | Node::Repeat { span, .. }
+            | Node::Optional { span, .. }
+            | Node::Star { span, .. }
+            | Node::Bounded { span, .. }
+            | Node::Concat { span, .. }
+            | Node::Lookaround { span, .. }
+            | Node::Let { span, .. }
+            | Node::Ref { span, .. } => *span = new_span,
+        }
+
+        self
+    }
+
+    /// 文字集合を大文字・小文字を区別しないものに置き換える
+    /// 外側の定義への参照はそのまま残る
+    pub fn case_fold(&self) -> Node {
+        let fold = |ranges: &[Range], include_flg: bool, span: Span| {
+            let chex = Chex::from_ranges(ranges.to_vec(), include_flg).case_fold();
+            if chex.include_flg {
+                get_inc_chex(&chex.ranges, span)
+            } else {
+                get_exc_chex(&chex.ranges, span)
+            }
+        };
+        let span = self.span();
+        match self {
+            Node::IncChex { ranges, .. } => fold(ranges, true, span),
+            Node::ExcChex { ranges, .. } => fold(ranges, false, span),
+            Node::And { left, right, .. } => get_and_node(&left.case_fold(), &right.case_fold()),
+            Node::Or { left, right, .. } => get_or_node(&left.case_fold(), &right.case_fold()),
+            Node::Invert { node, .. } => get_invert_node(node.case_fold(), span),
+            Node::Containing { node, .. } => get_containing_node(node.case_fold(), span),
+            Node::StartingWith { node, .. } => get_starting_with_node(node.case_fold(), span),
+            Node::EndingWith { node, .. } => get_ending_with_node(node.case_fold(), span),
+            Node::Repeat { node, .. } => get_repeat_node(node.case_fold(), span),
+            Node::Optional { node, .. } => get_optional_node(node.case_fold(), span),
+            Node::Star { node, .. } => get_star_node(node.case_fold(), span),
+            Node::Bounded { node, min, max, .. } => {
+                get_bounded_node(node.case_fold(), *min, *max, span)
+            }
+            Node::Concat { nodes, .. } => {
+                get_concat_node(nodes.iter().map(|node| node.case_fold()).collect())
+            }
+            Node::Lookaround {
+                node,
+                behind,
+                negative,
+                ..
+            } => get_lookaround_node(node.case_fold(), *behind, *negative, span),
+            Node::Let { defs, body, .. } => get_let_node(
+                defs.iter()
+                    .map(|(name, node)| (name.clone(), node.case_fold()))
+                    .collect(),
+                body.case_fold(),
+                span,
+            ),
+            Node::Ref { .. } => self.clone(),
+        }
+    }
+
+    /// 先読み・後読みを含むか
+    pub fn has_lookaround(&self) -> bool {
+        match self {
+            Node::Lookaround { .. } => true,
+            Node::And { left, right, .. } | Node::Or { left, right, .. } => {
+                left.has_lookaround() || right.has_lookaround()
+            }
+            Node::Invert { node, .. }
+            | Node::Containing { node, .. }
+            | Node::StartingWith { node, .. }
+            | Node::EndingWith { node, .. }
+            | Node::Repeat { node, .. }
+            | Node::Optional { node, .. }
+            | Node::Star { node, .. }
+            | Node::Bounded { node, .. } => node.has_lookaround(),
+            Node::Concat { nodes, .. } => nodes.iter().any(|node| node.has_lookaround()),
+            Node::Let { defs, body, .. } => {
+                body.has_lookaround() || defs.iter().any(|(_, node)| node.has_lookaround())
+            }
+            Node::IncChex { .. } | Node::ExcChex { .. } | Node::Ref { .. } => false,
+        }
+    }
+
+    /// 参照している定義名
+    pub fn refs(&self) -> Vec<String> {
+        match self {
+            Node::Ref { name, .. } => vec![name.clone()],
+            Node::And { left, right, .. } | Node::Or { left, right, .. } => {
+                let mut refs = left.refs();
+                refs.extend(right.refs());
+                refs
+            }
+            Node::Invert { node, .. }
+            | Node::Containing { node, .. }
+            | Node::StartingWith { node, .. }
+            | Node::EndingWith { node, .. }
+            | Node::Repeat { node, .. }
+            | Node::Optional { node, .. }
+            | Node::Star { node, .. }
+            | Node::Bounded { node, .. }
+            | Node::Lookaround { node, .. } => node.refs(),
+            Node::Concat { nodes, .. } => nodes.iter().flat_map(|node| node.refs()).collect(),
+            Node::Let { defs, body, .. } => {
+                let names: Vec<&String> = defs.iter().map(|(name, _)| name).collect();
+                body.refs()
+                    .into_iter()
+                    .filter(|name| !names.contains(&name))
+                    .collect()
+            }
+            Node::IncChex { .. } | Node::ExcChex { .. } => vec![],
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 enum NodeKind {
     /// 0
@@ -257,6 +1278,7 @@ enum NodeKind {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::token::DialectError;
 
     #[test]
     fn test_tokenize() {
@@ -264,4 +1286,253 @@ mod tests {
         assert_eq!(tokenize("[^abc]"), vec!["[", "^", "a", "b", "c", "]"]);
         assert_eq!(tokenize("[\\[abc]"), vec!["[", "\\[", "a", "b", "c", "]"]);
     }
+
+    #[test]
+    fn test_parse_escape() {
+        match parse(&tokenize("[\\[\\\\a]")) {
+            Node::IncChex { ranges, .. } => assert_eq!(ranges, vec![('[', '\\'), ('a', 'a')]),
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    fn test_tokenize_class() {
+        assert_eq!(tokenize("\\d\\W"), vec!["\\d", "\\W"]);
+        assert_eq!(tokenize("\\pL\\P{Greek}"), vec!["\\p{L}", "\\P{Greek}"]);
+        assert_eq!(tokenize("[[:alpha:]_]"), vec!["[", "[:alpha:]", "_", "]"]);
+        assert_eq!(
+            tokenize("[:alpha:]"),
+            vec!["[", ":", "a", "l", "p", "h", "a", ":", "]"]
+        );
+        assert_eq!(tokenize("[a-z]"), vec!["[", "a", "-", "z", "]"]);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        match parse(&tokenize("[a-cx\\-]")) {
+            Node::IncChex { ranges, .. } => {
+                assert_eq!(ranges, vec![('-', '-'), ('a', 'c'), ('x', 'x')])
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        match parse(&tokenize("[^-0-9]")) {
+            Node::ExcChex { ranges, .. } => assert_eq!(ranges, vec![('-', '-'), ('0', '9')]),
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    fn test_tokenize_count() {
+        assert_eq!(tokenize("a{2,3}b{4}"), vec!["a", "{2,3}", "b", "{4}"]);
+        assert_eq!(tokenize("a{2,}[{]"), vec!["a", "{2,}", "[", "\\{", "]"]);
+        assert_eq!(tokenize("a{x}"), vec!["a", "{", "x", "}"]);
+        assert_eq!(parse_count("{2,3}"), Ok((2, Some(3))));
+        assert_eq!(parse_count("{2,}"), Ok((2, None)));
+        assert_eq!(parse_count("{4}"), Ok((4, Some(4))));
+    }
+
+    #[test]
+    fn test_parse_count_invalid() {
+        assert!(parse_count("{3,2}").is_err());
+    }
+
+    #[test]
+    fn test_parse_let() {
+        match parse(&tokenize("hex = [0-9a-f]; hexa = hex{2}; hexa hex")) {
+            Node::Let { defs, body, .. } => {
+                let names: Vec<&String> = defs.iter().map(|(name, _)| name).collect();
+                assert_eq!(names, vec!["hex", "hexa"]);
+                assert_eq!(body.refs(), vec!["hexa", "hex"]);
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        match parse(&tokenize("b = a; a = x; b")) {
+            Node::Let { defs, .. } => {
+                let names: Vec<&String> = defs.iter().map(|(name, _)| name).collect();
+                assert_eq!(names, vec!["a", "b"]);
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cyclic definition a -> b -> a")]
+    fn test_parse_let_cyclic() {
+        parse(&tokenize("a = x(b); b = a|y; a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate definition")]
+    fn test_parse_let_duplicate() {
+        parse(&tokenize("a = x; a = y; a"));
+    }
+
+    #[test]
+    fn test_tokenize_verbose() {
+        assert_eq!(
+            tokenize_verbose("a b # comment\n  c+ # (\n"),
+            vec!["a", "b", "c", "+"]
+        );
+        assert_eq!(tokenize_verbose("a\\ b\\#"), vec!["a", "\\ ", "b", "\\#"]);
+        assert_eq!(tokenize_verbose("[ #]"), vec!["[", " ", "#", "]"]);
+        assert_eq!(tokenize("a #b"), vec!["a", " ", "#", "b"]);
+    }
+
+    #[test]
+    fn test_tokenize_group() {
+        assert_eq!(tokenize("(?i)ab"), vec!["(?i)", "a", "b"]);
+        assert_eq!(tokenize("(?i:a)"), vec!["(?i:", "a", ")"]);
+        assert_eq!(tokenize("[(?i)]"), vec!["[", "\\(", "\\?", "i", "\\)", "]"]);
+    }
+
+    #[test]
+    fn test_tokenize_dialect() {
+        let dialect = Dialect::logical();
+        let tokens = tokenize_with("a|b∨¬c!", false, &dialect);
+        assert_eq!(tokens, vec!["a", "\\|", "b", "|", "!", "c", "\\!"]);
+        assert_eq!(tokens[1].span, Span::new(1, 2));
+        assert_eq!(
+            tokenize_with("(?¬a)(?!a)\\∨", false, &dialect),
+            vec!["(?!", "a", ")", "(", "?", "\\!", "a", ")", "\\∨"]
+        );
+        let dialect = Dialect::default()
+            .with(Token::ESC, '%')
+            .and_then(|dialect| dialect.with(Token::CH_S, '«'))
+            .and_then(|dialect| dialect.with(Token::CH_E, '»'))
+            .unwrap();
+        assert_eq!(
+            tokenize_with("%d%%\\«[«:alpha:»]»", false, &dialect),
+            vec!["\\d", "\\%", "\\\\", "[", "\\[", "[:alpha:]", "\\]", "]"]
+        );
+    }
+
+    #[test]
+    fn test_dialect_invalid() {
+        let error = Dialect::default().with(Token::OR, '&').unwrap_err();
+        assert_eq!(
+            error,
+            DialectError::Duplicate {
+                char: '&',
+                token: Token::AND,
+            }
+        );
+        assert_eq!(error.to_string(), "& is already used by AND");
+        assert_eq!(
+            Dialect::default().with(Token::OR, 'x'),
+            Err(DialectError::Reserved { char: 'x' })
+        );
+        assert!(Dialect::default().with(Token::OR, '|').is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid blank code")]
+    fn test_parse_flag_invalid() {
+        parse(&tokenize("a(?i)"));
+    }
+
+    #[test]
+    fn test_try_parse_depth() {
+        assert!(try_parse(&tokenize("ab"), 1).is_ok());
+        assert!(try_parse(&tokenize("((a)b)+c*"), 8).is_ok());
+        assert!(try_parse(&tokenize("[((((!]"), 2).is_ok());
+        assert_eq!(
+            try_parse(&tokenize("a(b(!c))"), 3).err(),
+            Some(ParseError::Depth {
+                limit: 3,
+                span: Span::new(4, 6),
+            })
+        );
+        assert_eq!(
+            try_parse(&tokenize("(a"), 8).err(),
+            Some(ParseError::Syntax {
+                message: ") not enough".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_lookaround() {
+        assert_eq!(
+            tokenize("(?=a)(?!b)(?<=c)(?<!d)"),
+            vec!["(?=", "a", ")", "(?!", "b", ")", "(?<=", "c", ")", "(?<!", "d", ")"]
+        );
+        match parse(&tokenize("a(?<!b|c)")) {
+            Node::Concat { nodes, .. } => match &nodes[1] {
+                Node::Lookaround {
+                    node,
+                    behind,
+                    negative,
+                    ..
+                } => {
+                    assert!(matches!(node.as_ref(), Node::Or { .. }));
+                    assert!(behind);
+                    assert!(negative);
+                }
+                node => panic!("unexpected {:?}", node),
+            },
+            node => panic!("unexpected {:?}", node),
+        }
+    }
+
+    #[test]
+    fn test_parse_affix() {
+        assert_eq!(
+            tokenize("~a~<b~>c<>"),
+            vec!["~", "a", "~<", "b", "~>", "c", "<", ">"]
+        );
+        assert_eq!(tokenize("[~<]"), vec!["[", "\\~", "<", "]"]);
+        assert!(matches!(
+            parse(&tokenize("!~ab")),
+            Node::Invert { node, .. } if matches!(node.as_ref(), Node::Containing { node, .. } if matches!(node.as_ref(), Node::Concat { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_parse_affix_literal() {
+        assert!(matches!(parse(&tokenize("a~b")), Node::Concat { nodes, .. } if nodes.len() == 3));
+        assert!(matches!(parse(&tokenize("a~<")), Node::Concat { nodes, .. } if nodes.len() == 3));
+        assert!(matches!(parse(&tokenize("[~]")), Node::IncChex { .. }));
+    }
+
+    #[test]
+    fn test_span() {
+        let tokens = tokenize("a\\|[b-c]");
+        assert_eq!(tokens[1].span, Span::new(1, 3));
+        let node = parse(&tokens);
+        assert_eq!(node.span(), Span::new(0, 8));
+        match node {
+            Node::Concat { nodes, .. } => {
+                assert_eq!(nodes[0].span(), Span::new(0, 1));
+                assert_eq!(nodes[1].span(), Span::new(1, 3));
+                assert_eq!(nodes[2].span(), Span::new(3, 8));
+            }
+            node => panic!("unexpected {:?}", node),
+        }
+        match parse(&tokenize("x|[b-c]|(de)+")) {
+            Node::Or { left, right, .. } => {
+                assert_eq!(left.span(), Span::new(0, 1));
+                match *right {
+                    Node::Or { left, right, .. } => {
+                        assert_eq!(left.span(), Span::new(2, 7));
+                        assert_eq!(right.span(), Span::new(8, 13));
+                        assert!(
+                            matches!(*right, Node::Repeat { node, .. } if node.span() == Span::new(8, 12))
+                        );
+                    }
+                    node => panic!("unexpected {:?}", node),
+                }
+            }
+            node => panic!("unexpected {:?}", node),
+        }
+        match parse(&tokenize("ab = x; !ab")) {
+            Node::Let { body, span, .. } => {
+                assert_eq!(span, Span::new(0, 11));
+                assert_eq!(body.span(), Span::new(8, 11));
+                assert!(
+                    matches!(*body, Node::Invert { node, .. } if node.span() == Span::new(9, 11))
+                );
+            }
+            node => panic!("unexpected {:?}", node),
+        }
+    }
 }
@@ -0,0 +1,297 @@
+use std::fmt::{self, Display};
+
+use crate::chex::Chex;
+use crate::parser::{is_ident_char, Node};
+use crate::token::Token;
+
+/// 結合の強さ。要素がこれより弱ければ括弧で囲む
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Level {
+    AndOr,
+    Prefix,
+    Concat,
+    Postfix,
+    Atom,
+}
+
+/// 出力の断片。参照は前後の文字と続けて読まれないように後でまとめて調べる
+#[derive(Debug, Clone)]
+enum Piece {
+    Text(String),
+    Ref(String),
+}
+
+/// 解析し直すと同じ形の木になるspexのソースを、必要な括弧だけ付けて出力する
+pub fn to_source(node: &Node) -> String {
+    match node {
+        Node::Let { defs, body, .. } => {
+            let names: Vec<String> = defs.iter().map(|(name, _)| name.clone()).collect();
+            let mut statements: Vec<String> = defs
+                .iter()
+                .map(|(name, node)| {
+                    format!(
+                        "{} {} {}",
+                        name,
+                        Token::DEF.value(),
+                        render(pieces(node, Level::AndOr), &names)
+                    )
+                })
+                .collect();
+            statements.push(render(pieces(body, Level::AndOr), &names));
+            statements.join(&format!("{} ", Token::SEP.value()))
+        }
+        _ => render(pieces(node, Level::AndOr), &[]),
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&to_source(self))
+    }
+}
+
+fn level(node: &Node) -> Level {
+    match node {
+        Node::And { .. } | Node::Or { .. } => Level::AndOr,
+        Node::Invert { .. }
+        | Node::Containing { .. }
+        | Node::StartingWith { .. }
+        | Node::EndingWith { .. } => Level::Prefix,
+        Node::Concat { .. } => Level::Concat,
+        Node::Repeat { .. } | Node::Optional { .. } | Node::Star { .. } | Node::Bounded { .. } => {
+            Level::Postfix
+        }
+        Node::IncChex { .. }
+        | Node::ExcChex { .. }
+        | Node::Lookaround { .. }
+        | Node::Ref { .. }
+        | Node::Let { .. } => Level::Atom,
+    }
+}
+
+fn text(text: impl Into<String>) -> Piece {
+    Piece::Text(text.into())
+}
+
+/// requiredより弱い結合のnodeは括弧で囲む
+fn pieces(node: &Node, required: Level) -> Vec<Piece> {
+    let mut ret = match node {
+        Node::And { left, right, .. } | Node::Or { left, right, .. } => {
+            // `&` `|` は右結合
+            let operator = if matches!(node, Node::And { .. }) {
+                Token::AND.value()
+            } else {
+                Token::OR.value()
+            };
+            let mut ret = pieces(left, Level::Prefix);
+            ret.push(text(operator));
+            ret.extend(pieces(right, Level::AndOr));
+            ret
+        }
+        Node::Invert { node, .. } => prefixed(Token::INVT.value().to_string(), node),
+        Node::Containing { node, .. } => prefixed(Token::CONT.value().to_string(), node),
+        Node::StartingWith { node, .. } => prefixed(
+            format!("{}{}", Token::CONT.value(), Token::HEAD.value()),
+            node,
+        ),
+        Node::EndingWith { node, .. } => prefixed(
+            format!("{}{}", Token::CONT.value(), Token::TAIL.value()),
+            node,
+        ),
+        Node::Concat { nodes, .. } => nodes
+            .iter()
+            .flat_map(|node| pieces(node, Level::Postfix))
+            .collect(),
+        Node::Repeat { node, .. } => postfixed(node, Token::REPT.value().to_string()),
+        Node::Optional { node, .. } => postfixed(node, Token::OPT.value().to_string()),
+        Node::Star { node, .. } => postfixed(node, Token::STAR.value().to_string()),
+        Node::Bounded { node, min, max, .. } => {
+            let count = match max {
+                Some(max) if max == min => min.to_string(),
+                Some(max) => format!("{},{}", min, max),
+                None => format!("{},", min),
+            };
+            postfixed(
+                node,
+                format!("{}{}{}", Token::CNT_S.value(), count, Token::CNT_E.value()),
+            )
+        }
+        Node::IncChex { ranges, .. } => vec![text(Chex::from_ranges(ranges.clone(), true).str)],
+        Node::ExcChex { ranges, .. } => {
+            vec![text(Chex::from_ranges(ranges.clone(), false).str)]
+        }
+        Node::Lookaround {
+            node,
+            behind,
+            negative,
+            ..
+        } => {
+            let modifier = match (behind, negative) {
+                (false, false) => "=",
+                (false, true) => "!",
+                (true, false) => "<=",
+                (true, true) => "<!",
+            };
+            let mut ret = vec![text(format!(
+                "{}{}{}",
+                Token::SP_S.value(),
+                Token::OPT.value(),
+                modifier
+            ))];
+            ret.extend(pieces(node, Level::AndOr));
+            ret.push(text(Token::SP_E.value()));
+            ret
+        }
+        Node::Ref { name, .. } => vec![Piece::Ref(name.clone())],
+        Node::Let { .. } => panic!("SyntaxError definitions must be at the top level"),
+    };
+    if level(node) < required {
+        ret.insert(0, text(Token::SP_S.value()));
+        ret.push(text(Token::SP_E.value()));
+    }
+
+    ret
+}
+
+fn prefixed(operator: String, node: &Node) -> Vec<Piece> {
+    let mut ret = vec![text(operator)];
+    ret.extend(pieces(node, Level::Prefix));
+    ret
+}
+
+fn postfixed(node: &Node, operator: String) -> Vec<Piece> {
+    let mut ret = pieces(node, Level::Atom);
+    ret.push(text(operator));
+    ret
+}
+
+/// 1文字の識別子の文字
+fn is_word(piece: &Piece) -> bool {
+    match piece {
+        Piece::Text(text) => text.chars().count() == 1 && text.chars().all(is_ident_char),
+        Piece::Ref(_) => true,
+    }
+}
+
+/// 参照と文字が続けて1つの単語として読まれないようにして連結する
+fn render(mut pieces: Vec<Piece>, names: &[String]) -> String {
+    // 定義名と同じ綴りになる文字の並びは先頭の文字を文字集合にする
+    let mut i = 0;
+    while i < pieces.len() {
+        let len = pieces[i..]
+            .iter()
+            .take_while(|piece| matches!(piece, Piece::Text(_)) && is_word(piece))
+            .count();
+        let word: String = pieces[i..i + len]
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.as_str(),
+                Piece::Ref(_) => unreachable!(),
+            })
+            .collect();
+        if len > 0 && names.contains(&word) {
+            pieces[i] = text(format!(
+                "{}{}{}",
+                Token::CH_S.value(),
+                &word[..1],
+                Token::CH_E.value()
+            ));
+        }
+        i += len.max(1);
+    }
+    // 前後の空白は定義の区切りで取り除かれるので文字集合にする
+    if !names.is_empty() {
+        for i in [0, pieces.len().saturating_sub(1)] {
+            if let Some(Piece::Text(text)) = pieces.get(i) {
+                if text.chars().all(char::is_whitespace) && !text.is_empty() {
+                    pieces[i] = Piece::Text(format!(
+                        "{}{}{}",
+                        Token::CH_S.value(),
+                        text,
+                        Token::CH_E.value()
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut ret = String::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Text(text) => ret += text,
+            Piece::Ref(name) => {
+                let prev = i > 0 && is_word(&pieces[i - 1]);
+                let next = pieces.get(i + 1).is_some_and(is_word);
+                if prev || next {
+                    ret += &format!("{}{}{}", Token::SP_S.value(), name, Token::SP_E.value());
+                } else {
+                    ret += name;
+                }
+            }
+        }
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_spex;
+    use crate::parser::{parse, tokenize};
+
+    fn print(src: &str) -> String {
+        to_source(&parse(&tokenize(src)))
+    }
+
+    #[test]
+    fn print_minimal_parenthesis() {
+        assert_eq!(print("((a))"), "a");
+        assert_eq!(print("(a|b)c"), "(a|b)c");
+        assert_eq!(print("a|(b|c)"), "a|b|c");
+        assert_eq!(print("(a|b)|c"), "(a|b)|c");
+        assert_eq!(print("(a&b)|(c)"), "(a&b)|c");
+        assert_eq!(print("((ab))+"), "(ab)+");
+        assert_eq!(print("((a)+)"), "a+");
+        assert_eq!(print("(a+)+"), "(a+)+");
+        assert_eq!(print("!(ab)"), "!ab");
+        assert_eq!(print("!(a|b)"), "!(a|b)");
+        assert_eq!(print("a(!b)"), "a(!b)");
+        assert_eq!(print("~<(ab){2,}"), "~<(ab){2,}");
+        assert_eq!(print("[a-z]{3}|x{1,2}"), "[a-z]{3}|x{1,2}");
+        assert_eq!(print("a(?<!b|c)."), "a(?<!b|c).");
+        assert_eq!(print("[^\\]]\\&\\d"), "[^\\]]\\&[0-9]");
+    }
+
+    #[test]
+    fn print_let() {
+        assert_eq!(
+            print("hex = [0-9a-f]; hex{8}-hex{4}"),
+            "hex = [0-9a-f]; hex{8}\\-hex{4}"
+        );
+        assert_eq!(print("x = a; (x)(x)"), "x = a; (x)(x)");
+        assert_eq!(print("ab = x; [a]b|ab"), "ab = x; [a]b|ab");
+    }
+
+    #[test]
+    fn print_round_trip() {
+        for src in [
+            "((a[bc])+&!((ac)+))|a+|((([^def]&[l])|[op])&[^u])+",
+            "(?=.*\\d)(?!.*\\s).{8,}",
+            "~error&!~>warn",
+            "(?i)ab[^c]",
+            "a?b*(c|\\.)+",
+            "d = \\d; n = d+(\\.d+)?; n(,n)*",
+            "x = [ab]; xy = (x)x; xy|xyz",
+        ] {
+            let printed = print(src);
+            assert_eq!(print(&printed), printed, "{}", src);
+            assert!(
+                build_spex(parse(&tokenize(src))) == build_spex(parse(&tokenize(&printed))),
+                "{} -> {}",
+                src,
+                printed
+            );
+        }
+    }
+}
@@ -0,0 +1,1085 @@
+use std::fmt::{self, Display};
+
+use regex_syntax::hir::{Class, HirKind};
+
+use crate::builder::build_spex;
+use crate::chex::{next_char, prev_char, Chex, Range};
+use crate::parser::{
+    get_and_node, get_bounded_node, get_concat_node, get_empty_node, get_exc_chex, get_inc_chex,
+    get_lookaround_node, get_optional_node, get_or_node, get_repeat_node, get_star_node, Node,
+    Span,
+};
+use crate::spex::{Re, Spex};
+
+/// 書き出す正規表現の方言
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// POSIX拡張正規表現。括弧の中ではエスケープを使わない
+    Ere,
+    /// JavaScriptの正規表現。コードポイント単位で扱う `u` フラグを前提にする
+    EcmaScript,
+    /// Rustのregexクレート
+    Rust,
+}
+
+/// 結合の強さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Alt,
+    Cat,
+    Postfix,
+    Atom,
+}
+
+impl Spex {
+    /// 連接・選択・文字クラス・`*` `+` `?` とエスケープだけを使った正規表現にする
+    /// spexは文字列全体との一致なので、`^` と `$` で囲む
+    pub fn to_regex(&self, flavor: Flavor) -> String {
+        match self.eliminate() {
+            // どの文字列にも一致しない
+            None => match flavor {
+                Flavor::Ere => "a^".to_string(),
+                Flavor::EcmaScript | Flavor::Rust => "[^\\s\\S]".to_string(),
+            },
+            Some(Re::Eps) => "^$".to_string(),
+            Some(re) => {
+                let (body, prec) = render(&re, flavor);
+                if prec == Prec::Alt {
+                    format!("^{}$", group(&body, flavor))
+                } else {
+                    format!("^{}$", body)
+                }
+            }
+        }
+    }
+}
+
+fn render(re: &Re, flavor: Flavor) -> (String, Prec) {
+    match re {
+        Re::Eps => (group("", flavor), Prec::Atom),
+        Re::Chex(chex) => render_chex(chex, flavor),
+        Re::Cat(items) => {
+            let pieces: Vec<String> = Re::fuse_plus(items)
+                .into_iter()
+                .map(|(re, plus)| {
+                    if plus {
+                        format!("{}+", atom(re, flavor))
+                    } else {
+                        let (text, prec) = render(re, flavor);
+                        if prec == Prec::Alt {
+                            group(&text, flavor)
+                        } else {
+                            text
+                        }
+                    }
+                })
+                .collect();
+            (pieces.concat(), Prec::Cat)
+        }
+        Re::Alt(items) => {
+            let others: Vec<&Re> = items.iter().filter(|item| *item != &Re::Eps).collect();
+            if others.len() < items.len() {
+                let text = if others.len() == 1 {
+                    atom(others[0], flavor)
+                } else {
+                    group(&render_alt(&others, flavor), flavor)
+                };
+                (format!("{}?", text), Prec::Postfix)
+            } else {
+                (render_alt(&others, flavor), Prec::Alt)
+            }
+        }
+        Re::Star(re) => (format!("{}*", atom(re, flavor)), Prec::Postfix),
+    }
+}
+
+fn render_alt(items: &[&Re], flavor: Flavor) -> String {
+    items
+        .iter()
+        .map(|re| render(re, flavor).0)
+        .collect::<Vec<String>>()
+        .join("|")
+}
+
+/// 後置の演算子を付けられる形にする
+fn atom(re: &Re, flavor: Flavor) -> String {
+    let (text, prec) = render(re, flavor);
+    if prec == Prec::Atom {
+        text
+    } else {
+        group(&text, flavor)
+    }
+}
+
+fn group(text: &str, flavor: Flavor) -> String {
+    match flavor {
+        Flavor::Ere => format!("({})", text),
+        Flavor::EcmaScript | Flavor::Rust => format!("(?:{})", text),
+    }
+}
+
+fn render_chex(chex: &Chex, flavor: Flavor) -> (String, Prec) {
+    if chex.whole() {
+        let text = match flavor {
+            Flavor::Ere => ".",
+            Flavor::EcmaScript => "[\\s\\S]",
+            Flavor::Rust => "(?s:.)",
+        };
+        return (text.to_string(), Prec::Atom);
+    }
+    // 範囲の少ない方で書く
+    let included = chex.include_ranges();
+    let excluded = (!chex).include_ranges();
+    let (ranges, negated) = if excluded.len() < included.len() {
+        (excluded, true)
+    } else {
+        (included, false)
+    };
+    if let [(start, end)] = ranges[..] {
+        if start == end && !negated {
+            return (escape_char(start, flavor), Prec::Atom);
+        }
+    }
+
+    let body = match flavor {
+        Flavor::Ere => ere_class_body(&ranges, negated),
+        Flavor::EcmaScript | Flavor::Rust => ranges
+            .iter()
+            .map(|&(start, end)| {
+                if start == end {
+                    escape_class_char(start, flavor)
+                } else if next_char(start) == Some(end) {
+                    escape_class_char(start, flavor) + &escape_class_char(end, flavor)
+                } else {
+                    format!(
+                        "{}-{}",
+                        escape_class_char(start, flavor),
+                        escape_class_char(end, flavor)
+                    )
+                }
+            })
+            .collect(),
+    };
+    let deny = if negated { "^" } else { "" };
+
+    (format!("[{}{}]", deny, body), Prec::Atom)
+}
+
+/// POSIXの括弧の中は `\` が効かないので、`]` を先頭に、`-` を末尾に置き、
+/// `^` は先頭を避け、`[` は `[.` `[:` `[=` にならないように後ろに置く
+fn ere_class_body(ranges: &[Range], negated: bool) -> String {
+    const SPECIALS: [char; 4] = ['-', '[', ']', '^'];
+    let mut specials: Vec<char> = vec![];
+    let mut plain: Vec<Range> = vec![];
+    for &(range_start, end) in ranges {
+        let inside: Vec<char> = SPECIALS
+            .iter()
+            .copied()
+            .filter(|special| (range_start..=end).contains(special))
+            .collect();
+        let mut start = Some(range_start);
+        for special in inside {
+            specials.push(special);
+            if let Some(start) = start.filter(|start| *start < special) {
+                plain.push((start, prev_char(special).unwrap()));
+            }
+            start = next_char(special).filter(|next| *next <= end);
+        }
+        if let Some(start) = start {
+            plain.push((start, end));
+        }
+    }
+    plain.sort_unstable();
+
+    let mut body = String::new();
+    if specials.contains(&']') {
+        body.push(']');
+    }
+    for (start, end) in &plain {
+        body.push(*start);
+        if start != end {
+            if next_char(*start) != Some(*end) {
+                body.push('-');
+            }
+            body.push(*end);
+        }
+    }
+    if specials.contains(&'[') {
+        body.push('[');
+    }
+    if body.is_empty() && !negated && specials == ['-', '^'] {
+        // [^-] は否定になるので [-^] にする
+        return "-^".to_string();
+    }
+    if specials.contains(&'^') {
+        body.push('^');
+    }
+    if specials.contains(&'-') {
+        body.push('-');
+    }
+
+    body
+}
+
+/// 括弧の外の文字
+fn escape_char(char: char, flavor: Flavor) -> String {
+    let meta = match flavor {
+        Flavor::Ere => "\\.[]()*+?{}|^$".contains(char),
+        Flavor::EcmaScript => "\\^$.*+?()[]{}|/".contains(char),
+        Flavor::Rust => regex_syntax::is_meta_character(char),
+    };
+    if meta {
+        format!("\\{}", char)
+    } else {
+        hex_escape(char, flavor).unwrap_or_else(|| char.to_string())
+    }
+}
+
+/// 括弧の中の文字(ERE以外)
+fn escape_class_char(char: char, flavor: Flavor) -> String {
+    let meta = match flavor {
+        Flavor::EcmaScript => "\\]-[^".contains(char),
+        _ => regex_syntax::is_meta_character(char),
+    };
+    if meta {
+        format!("\\{}", char)
+    } else {
+        hex_escape(char, flavor).unwrap_or_else(|| char.to_string())
+    }
+}
+
+/// 制御文字などの見えない文字
+fn hex_escape(char: char, flavor: Flavor) -> Option<String> {
+    if !(char.is_control() || (char.is_whitespace() && char != ' ')) {
+        return None;
+    }
+    match flavor {
+        Flavor::Ere => None,
+        Flavor::EcmaScript if (char as u32) <= 0xffff => Some(format!("\\u{:04X}", char as u32)),
+        Flavor::EcmaScript => Some(format!("\\u{{{:X}}}", char as u32)),
+        Flavor::Rust => Some(format!("\\x{{{:X}}}", char as u32)),
+    }
+}
+
+/// 正規表現を読み込むときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegexError {
+    /// 後方参照は正規言語にならない
+    Backreference {
+        span: Span,
+    },
+    /// 単語境界や途中のアンカーなど、対応していない機能
+    Unsupported {
+        feature: String,
+        span: Span,
+    },
+    Syntax {
+        message: String,
+        span: Span,
+    },
+}
+
+impl Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegexError::Backreference { span } => write!(
+                f,
+                "{}..{}: backreferences are not regular",
+                span.start, span.end
+            ),
+            RegexError::Unsupported { feature, span } => write!(
+                f,
+                "{}..{}: {} is not supported",
+                span.start, span.end, feature
+            ),
+            RegexError::Syntax { message, span } => {
+                write!(f, "{}..{}: {}", span.start, span.end, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegexError {}
+
+impl Spex {
+    /// 正規表現が一致する(文字列のどこかに現れる)文字列の集合
+    pub fn from_regex(regex: &str, flavor: Flavor) -> Result<Spex, RegexError> {
+        Ok(build_spex(parse_regex(regex, flavor)?))
+    }
+}
+
+/// 正規表現の正規な部分(文字クラス・量指定子・選択・グループ)を木にする
+/// 先頭の `^` と末尾の `$` がなければ、前後に任意の文字列を許す
+pub fn parse_regex(regex: &str, flavor: Flavor) -> Result<Node, RegexError> {
+    let mut parser = RegexParser {
+        chars: regex.chars().collect(),
+        pos: 0,
+        flavor,
+        flags: Flags::default(),
+    };
+    let node = parser.parse_alt(true)?;
+    match parser.peek() {
+        None => Ok(node),
+        Some(_) => Err(parser.syntax("unmatched )", 1)),
+    }
+}
+
+struct RegexParser {
+    chars: Vec<char>,
+    pos: usize,
+    flavor: Flavor,
+    flags: Flags,
+}
+
+/// Rustの `(?i)` `(?s)` などで変わる読み方。グループを出ると元に戻る
+#[derive(Debug, Clone, Copy, Default)]
+struct Flags {
+    case_insensitive: bool,
+    dot_all: bool,
+}
+
+/// 文字クラスの要素
+enum ClassItem {
+    Char(char),
+    Chex(Chex),
+}
+
+impl RegexParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(i, char)| self.peek_at(i) == Some(char))
+    }
+
+    fn span(&self, len: usize) -> Span {
+        Span::new(self.pos, (self.pos + len).min(self.chars.len()))
+    }
+
+    fn syntax(&self, message: &str, len: usize) -> RegexError {
+        RegexError::Syntax {
+            message: message.to_string(),
+            span: self.span(len),
+        }
+    }
+
+    fn unsupported(&self, feature: &str, len: usize) -> RegexError {
+        RegexError::Unsupported {
+            feature: feature.to_string(),
+            span: self.span(len),
+        }
+    }
+
+    fn expect(&mut self, char: char) -> Result<(), RegexError> {
+        if self.peek() == Some(char) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.syntax(&format!("{} expected", char), 1))
+        }
+    }
+
+    fn parse_alt(&mut self, top: bool) -> Result<Node, RegexError> {
+        let mut nodes = vec![self.parse_concat(top)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            nodes.push(self.parse_concat(top)?);
+        }
+        let last = nodes.pop().unwrap();
+
+        Ok(nodes
+            .iter()
+            .rev()
+            .fold(last, |right, left| get_or_node(left, &right)))
+    }
+
+    /// topなら `^` `$` でその選択肢の前後を固定する
+    /// 途中の `^` より前と `$` より後は空文字列にしか一致できない
+    fn parse_concat(&mut self, top: bool) -> Result<Node, RegexError> {
+        let start = self.pos;
+        let mut head = false;
+        let mut tail: Option<usize> = None;
+        let mut nodes: Vec<Node> = vec![];
+        while let Some(char) = self.peek() {
+            match char {
+                '|' | ')' => break,
+                '^' if top => {
+                    if !nodes.is_empty() {
+                        nodes = vec![only_empty(nodes)];
+                    }
+                    head = true;
+                    self.pos += 1;
+                }
+                '$' if top => {
+                    tail.get_or_insert(nodes.len());
+                    self.pos += 1;
+                }
+                '^' | '$' => return Err(self.unsupported("anchor inside a group", 1)),
+                '(' if self.flag_group_len().is_some_and(|(_, end)| end == ')') => {
+                    // (?i) はグループの残りに効く
+                    self.pos += 1;
+                    self.parse_flags()?;
+                }
+                _ => nodes.push(self.parse_quantified()?),
+            }
+        }
+        if let Some(tail) = tail.filter(|tail| *tail < nodes.len()) {
+            let rest = nodes.split_off(tail);
+            nodes.push(only_empty(rest));
+        }
+        let span = Span::new(start, self.pos);
+        let mut node = match nodes.len() {
+            0 => get_empty_node(span),
+            1 => nodes.pop().unwrap(),
+            _ => get_concat_node(nodes),
+        };
+        if top {
+            let any = || get_star_node(get_exc_chex(&[], span), span);
+            if !head {
+                node = get_concat_node(vec![any(), node]);
+            }
+            if tail.is_none() {
+                node = get_concat_node(vec![node, any()]);
+            }
+        }
+
+        Ok(node)
+    }
+
+    fn parse_quantified(&mut self) -> Result<Node, RegexError> {
+        let mut node = self.parse_atom()?;
+        loop {
+            let start = node.span().start;
+            node = match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    get_star_node(node, Span::new(start, self.pos))
+                }
+                Some('+') => {
+                    self.pos += 1;
+                    get_repeat_node(node, Span::new(start, self.pos))
+                }
+                Some('?') => {
+                    self.pos += 1;
+                    get_optional_node(node, Span::new(start, self.pos))
+                }
+                Some('{') => match self.parse_count()? {
+                    Some((min, max)) => {
+                        get_bounded_node(node, min, max, Span::new(start, self.pos))
+                    }
+                    None => return Ok(node),
+                },
+                _ => return Ok(node),
+            };
+            // 最短一致の `?` は言語を変えない
+            if self.flavor != Flavor::Ere && self.peek() == Some('?') {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// `{n}` `{n,}` `{n,m}`。回数指定でなければ、Rust以外では `{` を文字として扱う
+    fn parse_count(&mut self) -> Result<Option<(usize, Option<usize>)>, RegexError> {
+        let len = match self.chars[self.pos..].iter().position(|char| *char == '}') {
+            Some(len) => len,
+            None if self.flavor == Flavor::Rust => return Err(self.syntax("} expected", 1)),
+            None => return Ok(None),
+        };
+        let body: String = self.chars[self.pos + 1..self.pos + len].iter().collect();
+        let mut nums = body.splitn(2, ',').map(|num| num.trim().parse::<usize>());
+        let count = match (nums.next(), nums.next()) {
+            (Some(Ok(min)), None) => Some((min, Some(min))),
+            (Some(Ok(min)), Some(Ok(max))) if min <= max => Some((min, Some(max))),
+            (Some(Ok(min)), Some(Err(_))) if body.trim_end().ends_with(',') => Some((min, None)),
+            _ => None,
+        };
+        match count {
+            Some(count) => {
+                self.pos += len + 1;
+                Ok(Some(count))
+            }
+            None if self.flavor == Flavor::Rust => Err(self.syntax("invalid repetition", len + 1)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, RegexError> {
+        let start = self.pos;
+        let char = self.peek().unwrap();
+        let node = match char {
+            '(' => return self.parse_group(),
+            '[' => self.parse_class()?,
+            '.' => {
+                self.pos += 1;
+                let ranges: Vec<Range> = match self.flavor {
+                    _ if self.flags.dot_all => vec![],
+                    Flavor::Ere => vec![],
+                    Flavor::EcmaScript => {
+                        vec![('\n', '\n'), ('\r', '\r'), ('\u{2028}', '\u{2029}')]
+                    }
+                    Flavor::Rust => vec![('\n', '\n')],
+                };
+                get_exc_chex(&ranges, Span::new(start, self.pos))
+            }
+            '*' | '+' | '?' => return Err(self.syntax("nothing to repeat", 1)),
+            '{' if self.flavor == Flavor::Rust => return Err(self.syntax("nothing to repeat", 1)),
+            '\\' => {
+                let item = self.parse_escape(false)?;
+                class_item_node(item, Span::new(start, self.pos))
+            }
+            _ => {
+                self.pos += 1;
+                get_inc_chex(&[(char, char)], Span::new(start, self.pos))
+            }
+        };
+
+        if self.flags.case_insensitive {
+            Ok(node.case_fold())
+        } else {
+            Ok(node)
+        }
+    }
+
+    /// `(?` に続くRustのフラグの長さと、その後の `:` か `)`
+    fn flag_group_len(&self) -> Option<(usize, char)> {
+        if self.flavor != Flavor::Rust || !self.starts_with("(?") {
+            return None;
+        }
+        let len = self.chars[self.pos + 2..]
+            .iter()
+            .position(|char| !(char.is_ascii_alphabetic() || *char == '-'))?;
+        match self.peek_at(len + 2) {
+            Some(end) if end == ':' || end == ')' => Some((len, end)),
+            _ => None,
+        }
+    }
+
+    /// `(` の後の `?is-u:` などを読んでフラグを変える
+    fn parse_flags(&mut self) -> Result<(), RegexError> {
+        let mut enable = true;
+        self.pos += 1;
+        while let Some(char) = self.peek() {
+            match char {
+                ':' | ')' => break,
+                '-' => enable = false,
+                'i' => self.flags.case_insensitive = enable,
+                's' => self.flags.dot_all = enable,
+                // Unicodeは既定で有効
+                'u' if enable => {}
+                _ => return Err(self.unsupported(&format!("flag {}", char), 1)),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    fn parse_group(&mut self) -> Result<Node, RegexError> {
+        let start = self.pos;
+        let flags = self.flags;
+        let mut lookaround: Option<(bool, bool)> = None;
+        if self.flag_group_len().is_some() {
+            // (?i:X)
+            self.pos += 1;
+            self.parse_flags()?;
+        } else if self.flavor != Flavor::Ere && self.peek_at(1) == Some('?') {
+            self.pos += 1;
+            let modifiers: [(&str, Option<(bool, bool)>); 5] = [
+                ("?:", None),
+                ("?=", Some((false, false))),
+                ("?!", Some((false, true))),
+                ("?<=", Some((true, false))),
+                ("?<!", Some((true, true))),
+            ];
+            if let Some((modifier, kind)) = modifiers
+                .iter()
+                .find(|(modifier, _)| self.starts_with(modifier))
+            {
+                if kind.is_some() && self.flavor == Flavor::Rust {
+                    return Err(self.unsupported("lookaround", modifier.len()));
+                }
+                lookaround = *kind;
+                self.pos += modifier.len();
+            } else if self.starts_with("?<") || self.starts_with("?P<") {
+                // 名前付きグループ
+                match self.chars[self.pos..].iter().position(|char| *char == '>') {
+                    Some(len) => self.pos += len + 1,
+                    None => return Err(self.syntax("> expected", 2)),
+                }
+            } else {
+                return Err(self.unsupported("group modifier", 2));
+            }
+        } else {
+            self.pos += 1;
+        }
+        let node = self.parse_alt(false)?;
+        self.expect(')')?;
+        self.flags = flags;
+        let span = Span::new(start, self.pos);
+
+        Ok(match lookaround {
+            Some((behind, negative)) => get_lookaround_node(node, behind, negative, span),
+            None => node,
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<Node, RegexError> {
+        let start = self.pos;
+        self.pos += 1;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut chex = Chex::new_blank();
+        let mut first = true;
+        loop {
+            let char = match self.peek() {
+                Some(char) => char,
+                None => return Err(self.syntax("] expected", 1)),
+            };
+            if char == ']' && !(first && self.flavor != Flavor::EcmaScript) {
+                self.pos += 1;
+                break;
+            }
+            first = false;
+            let item = self.parse_class_item()?;
+            let item = match item {
+                ClassItem::Char(from)
+                    if self.peek() == Some('-') && self.peek_at(1) != Some(']') =>
+                {
+                    self.pos += 1;
+                    match self.parse_class_item()? {
+                        ClassItem::Char(to) if from <= to => {
+                            Chex::from_ranges(vec![(from, to)], true)
+                        }
+                        _ => return Err(self.syntax("invalid range", 1)),
+                    }
+                }
+                ClassItem::Char(char) => Chex::new(vec![char], true),
+                ClassItem::Chex(chex) => chex,
+            };
+            chex = &chex | &item;
+        }
+        let chex = if negated { !&chex } else { chex };
+
+        Ok(class_item_node(
+            ClassItem::Chex(chex),
+            Span::new(start, self.pos),
+        ))
+    }
+
+    fn parse_class_item(&mut self) -> Result<ClassItem, RegexError> {
+        let char = match self.peek() {
+            Some(char) => char,
+            None => return Err(self.syntax("] expected", 1)),
+        };
+        if char == '[' && self.flavor != Flavor::EcmaScript {
+            match self.peek_at(1) {
+                Some(':') => {
+                    let rest: String = self.chars[self.pos + 2..].iter().collect();
+                    if let Some(len) = rest.find(":]") {
+                        let name = rest[..len].to_string();
+                        let len = name.chars().count() + 4;
+                        return match Chex::posix(&name) {
+                            Some(chex) => {
+                                self.pos += len;
+                                Ok(ClassItem::Chex(chex))
+                            }
+                            None => Err(self.syntax("unknown class name", len)),
+                        };
+                    }
+                }
+                Some('.') | Some('=') => {
+                    return Err(self.unsupported("collating element", 2));
+                }
+                _ if self.flavor == Flavor::Rust => {
+                    return Err(self.unsupported("nested class", 1));
+                }
+                _ => {}
+            }
+        }
+        if self.flavor == Flavor::Rust && ["&&", "--", "~~"].iter().any(|op| self.starts_with(op)) {
+            return Err(self.unsupported("class set operation", 2));
+        }
+        if char == '\\' && self.flavor != Flavor::Ere {
+            return self.parse_escape(true);
+        }
+        self.pos += 1;
+
+        Ok(ClassItem::Char(char))
+    }
+
+    /// `\` に続く文字
+    fn parse_escape(&mut self, in_class: bool) -> Result<ClassItem, RegexError> {
+        let start = self.pos;
+        self.pos += 1;
+        let char = match self.peek() {
+            Some(char) => char,
+            None => return Err(self.syntax("trailing \\", 1)),
+        };
+        self.pos += 1;
+        let escaped = |parser: &RegexParser| Span::new(start, parser.pos);
+        if char.is_ascii_digit() && char != '0' || (char == 'k' && self.peek() == Some('<')) {
+            return Err(RegexError::Backreference {
+                span: escaped(self),
+            });
+        }
+        if self.flavor == Flavor::Ere {
+            return Ok(ClassItem::Char(char));
+        }
+        let item = match char {
+            'd' | 'D' | 'w' | 'W' | 's' | 'S' => ClassItem::Chex(self.shorthand(char)),
+            'p' | 'P' => {
+                let name: String = if self.peek() == Some('{') {
+                    let len = self.chars[self.pos..]
+                        .iter()
+                        .position(|char| *char == '}')
+                        .ok_or_else(|| self.syntax("} expected", 1))?;
+                    let name = self.chars[self.pos + 1..self.pos + len].iter().collect();
+                    self.pos += len + 1;
+                    name
+                } else if self.flavor == Flavor::Rust && self.peek().is_some() {
+                    self.pos += 1;
+                    self.chars[self.pos - 1].to_string()
+                } else {
+                    return Err(self.syntax("{ expected", 1));
+                };
+                // ECMAScriptの General_Category=L のような書き方
+                let name = name.rsplit('=').next().unwrap().to_string();
+                match Chex::unicode(&name) {
+                    Some(chex) if char == 'p' => ClassItem::Chex(chex),
+                    Some(chex) => ClassItem::Chex(!&chex),
+                    None => {
+                        return Err(RegexError::Syntax {
+                            message: format!("unknown property {}", name),
+                            span: escaped(self),
+                        })
+                    }
+                }
+            }
+            'n' => ClassItem::Char('\n'),
+            't' => ClassItem::Char('\t'),
+            'r' => ClassItem::Char('\r'),
+            'f' => ClassItem::Char('\x0c'),
+            'v' => ClassItem::Char('\x0b'),
+            'a' if self.flavor == Flavor::Rust => ClassItem::Char('\x07'),
+            '0' if self.flavor == Flavor::EcmaScript => ClassItem::Char('\0'),
+            'b' if in_class && self.flavor == Flavor::EcmaScript => ClassItem::Char('\x08'),
+            'c' if self.flavor == Flavor::EcmaScript
+                && self.peek().is_some_and(|char| char.is_ascii_alphabetic()) =>
+            {
+                self.pos += 1;
+                ClassItem::Char((self.chars[self.pos - 1] as u8 % 32) as char)
+            }
+            'x' | 'u' | 'U' => ClassItem::Char(self.parse_hex(char)?),
+            'b' | 'B' | 'A' | 'z' | 'Z' | 'G' | '<' | '>' => {
+                return Err(RegexError::Unsupported {
+                    feature: "assertion".to_string(),
+                    span: escaped(self),
+                })
+            }
+            char if !char.is_alphanumeric() => ClassItem::Char(char),
+            _ => {
+                return Err(RegexError::Syntax {
+                    message: format!("unknown escape \\{}", char),
+                    span: escaped(self),
+                })
+            }
+        };
+
+        Ok(item)
+    }
+
+    /// `\d` `\w` `\s` とその否定
+    fn shorthand(&self, name: char) -> Chex {
+        let chex = match (self.flavor, name.to_ascii_lowercase()) {
+            // RustはUnicodeの定義
+            (Flavor::Rust, _) => {
+                let hir = regex_syntax::Parser::new()
+                    .parse(&format!("\\{}", name.to_ascii_lowercase()))
+                    .unwrap();
+                match hir.kind() {
+                    HirKind::Class(Class::Unicode(class)) => Chex::from_ranges(
+                        class
+                            .ranges()
+                            .iter()
+                            .map(|range| (range.start(), range.end()))
+                            .collect(),
+                        true,
+                    ),
+                    _ => unreachable!(),
+                }
+            }
+            (_, 's') => Chex::from_ranges(
+                vec![
+                    ('\t', '\r'),
+                    (' ', ' '),
+                    ('\u{a0}', '\u{a0}'),
+                    ('\u{1680}', '\u{1680}'),
+                    ('\u{2000}', '\u{200a}'),
+                    ('\u{2028}', '\u{2029}'),
+                    ('\u{202f}', '\u{202f}'),
+                    ('\u{205f}', '\u{205f}'),
+                    ('\u{3000}', '\u{3000}'),
+                    ('\u{feff}', '\u{feff}'),
+                ],
+                true,
+            ),
+            (_, name) => Chex::shorthand(name).unwrap(),
+        };
+        if name.is_ascii_uppercase() {
+            !&chex
+        } else {
+            chex
+        }
+    }
+
+    /// `\xHH` `\x{H..}` `\uHHHH` `\u{H..}` `\UHHHHHHHH`
+    fn parse_hex(&mut self, kind: char) -> Result<char, RegexError> {
+        let start = self.pos - 2;
+        let digits: String = if self.peek() == Some('{') {
+            let len = self.chars[self.pos..]
+                .iter()
+                .position(|char| *char == '}')
+                .ok_or_else(|| self.syntax("} expected", 1))?;
+            let digits = self.chars[self.pos + 1..self.pos + len].iter().collect();
+            self.pos += len + 1;
+            digits
+        } else {
+            let len = match kind {
+                'x' => 2,
+                'u' => 4,
+                _ => 8,
+            };
+            let digits: String = self.chars[self.pos..].iter().take(len).collect();
+            self.pos += digits.chars().count();
+            if digits.chars().count() != len {
+                return Err(RegexError::Syntax {
+                    message: format!("{} hex digits expected", len),
+                    span: Span::new(start, self.pos),
+                });
+            }
+            digits
+        };
+
+        // from_str_radixは先頭の `+` を受け付けるので、先に16進数字だけかを確かめる
+        Some(&digits)
+            .filter(|digits| {
+                !digits.is_empty() && digits.chars().all(|char| char.is_ascii_hexdigit())
+            })
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .and_then(std::char::from_u32)
+            .ok_or(RegexError::Syntax {
+                message: format!("invalid code point {}", digits),
+                span: Span::new(start, self.pos),
+            })
+    }
+}
+
+fn class_item_node(item: ClassItem, span: Span) -> Node {
+    let chex = match item {
+        ClassItem::Char(char) => Chex::new(vec![char], true),
+        ClassItem::Chex(chex) => chex,
+    };
+    if chex.include_flg {
+        get_inc_chex(&chex.ranges, span)
+    } else {
+        get_exc_chex(&chex.ranges, span)
+    }
+}
+
+/// 並びのうち空文字列に一致するもの
+fn only_empty(nodes: Vec<Node>) -> Node {
+    let node = get_concat_node(nodes);
+    let empty = get_empty_node(node.span());
+    get_and_node(&node, &empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::spex;
+
+    #[test]
+    fn regex_flavors() {
+        let spex1 = spex("ab|cd");
+        assert_eq!(spex1.to_regex(Flavor::Ere), "^(ab|cd)$");
+        assert_eq!(spex1.to_regex(Flavor::EcmaScript), "^(?:ab|cd)$");
+        assert_eq!(spex1.to_regex(Flavor::Rust), "^(?:ab|cd)$");
+        assert_eq!(spex("(a+|ab)&!a").to_regex(Flavor::Ere), "^(ab|aa+)$");
+        assert_eq!(spex("a(bc)*d?").to_regex(Flavor::Rust), "^a(?:bc)*d?$");
+        assert_eq!(spex(".a").to_regex(Flavor::EcmaScript), "^[\\s\\S]a$");
+        assert_eq!(spex("a&b").to_regex(Flavor::Ere), "a^");
+        assert_eq!(spex("a&b").to_regex(Flavor::Rust), "[^\\s\\S]");
+        assert_eq!(spex("!.+").to_regex(Flavor::Rust), "^$");
+    }
+
+    #[test]
+    fn regex_escape() {
+        assert_eq!(spex("a\\.b\\+/").to_regex(Flavor::Ere), "^a\\.b\\+/$");
+        assert_eq!(
+            spex("a\\.b\\+/").to_regex(Flavor::EcmaScript),
+            "^a\\.b\\+\\/$"
+        );
+        assert_eq!(spex("[^a]\t").to_regex(Flavor::Rust), "^[^a]\\x{9}$");
+        assert_eq!(spex("[\\]\\-\\^a]").to_regex(Flavor::Ere), "^[]a^-]$");
+        assert_eq!(
+            spex("[\\]\\-\\^a]").to_regex(Flavor::Rust),
+            "^[\\-\\]\\^a]$"
+        );
+        assert_eq!(spex("[\\^\\-]").to_regex(Flavor::Ere), "^[-^]$");
+        assert_eq!(spex("[Z-b]").to_regex(Flavor::Ere), "^[]Z\\_-b[^]$");
+    }
+
+    #[test]
+    fn regex_rust_parses() {
+        for src in [
+            "!(.*foo.*)",
+            "~>abc",
+            "\\d+(\\.\\d+)?",
+            "(?=.*\\d)[a-z0-9]{3}",
+            "[^\\-\\&\\~]+",
+            "((a[bc])+&!((ac)+))|a+",
+        ] {
+            let regex = spex(src).to_regex(Flavor::Rust);
+            assert!(
+                regex_syntax::Parser::new().parse(&regex).is_ok(),
+                "{} -> {}",
+                src,
+                regex
+            );
+        }
+    }
+
+    fn from(regex: &str, flavor: Flavor) -> Spex {
+        Spex::from_regex(regex, flavor).unwrap_or_else(|error| panic!("{}: {}", regex, error))
+    }
+
+    #[test]
+    fn import_search() {
+        for flavor in [Flavor::Ere, Flavor::EcmaScript, Flavor::Rust] {
+            assert!(from("abc", flavor) == spex(".*abc.*"));
+            assert!(from("^abc$", flavor) == spex("abc"));
+            assert!(from("^a|b$", flavor) == spex("a.*|.*b"));
+            assert!(from("^(ab|c){2,3}d?$", flavor) == spex("(ab|c){2,3}d?"));
+            assert!(from("^[a-c[:digit:]]+$", Flavor::Ere) == spex("[a-c0-9]+"));
+            assert!(from("^$", flavor) == Spex::empty_string());
+            assert!(from("a^b", flavor).blank());
+            assert!(from("a*^b$c*", flavor) == spex("b"));
+        }
+    }
+
+    #[test]
+    fn import_flavors() {
+        assert!(from("^a.b$", Flavor::Ere) == spex("a.b"));
+        assert!(from("^a.b$", Flavor::EcmaScript) == spex("a[^\n\r\u{2028}\u{2029}]b"));
+        assert!(from("^a.b$", Flavor::Rust) == spex("a[^\n]b"));
+        assert!(from("^(?s:.)$", Flavor::Rust) == spex("."));
+        assert!(from("^\\d+\\.\\x41\\u{3042}$", Flavor::EcmaScript) == spex("\\d+\\.Aあ"));
+        assert!(from("^\\d$", Flavor::Rust).is_match("٣"));
+        assert!(!from("^\\d$", Flavor::EcmaScript).is_match("٣"));
+        assert!(from("^a\\.$", Flavor::Ere) == spex("a\\."));
+        assert!(from("^[\\]$", Flavor::Ere) == spex("\\\\"));
+        assert!(from("^[]a]$", Flavor::Ere) == spex("[\\]a]"));
+        assert!(from("^[]a]$", Flavor::EcmaScript).blank());
+        assert!(from("^(?i)ab(?-i)c$", Flavor::Rust) == spex("[aA][bB]c"));
+        assert!(from("^(?:a+?)(?<x>b)(?P<y>c)$", Flavor::Rust) == spex("a+bc"));
+        assert!(from("^(?=.*\\d)\\w{3}$", Flavor::EcmaScript) == spex("(?=.*\\d)\\w{3}"));
+        assert!(from("^a{,2}$", Flavor::EcmaScript) == spex("a\\{,2\\}"));
+    }
+
+    #[test]
+    fn import_errors() {
+        assert!(matches!(
+            Spex::from_regex("(a)\\1", Flavor::Ere),
+            Err(RegexError::Backreference { span }) if span == Span::new(3, 5)
+        ));
+        assert!(matches!(
+            Spex::from_regex("(?<x>a)\\k<x>", Flavor::EcmaScript),
+            Err(RegexError::Backreference { .. })
+        ));
+        assert!(matches!(
+            Spex::from_regex("\\bword", Flavor::Rust),
+            Err(RegexError::Unsupported { .. })
+        ));
+        assert!(matches!(
+            Spex::from_regex("a(?=b)", Flavor::Rust),
+            Err(RegexError::Unsupported { .. })
+        ));
+        assert!(matches!(
+            Spex::from_regex("(a^b)", Flavor::Ere),
+            Err(RegexError::Unsupported { .. })
+        ));
+        assert!(matches!(
+            Spex::from_regex("(ab", Flavor::Ere),
+            Err(RegexError::Syntax { .. })
+        ));
+        for flavor in [Flavor::Ere, Flavor::EcmaScript, Flavor::Rust] {
+            for src in ["[a-", "[a", "[a-\\"] {
+                assert!(matches!(
+                    Spex::from_regex(src, flavor),
+                    Err(RegexError::Syntax { .. })
+                ));
+            }
+        }
+        assert_eq!(
+            Spex::from_regex("[a-", Flavor::Ere)
+                .unwrap_err()
+                .to_string(),
+            "3..3: ] expected"
+        );
+        for src in ["\\u{+41}", "\\x+4", "\\u+041", "\\x4", "\\u{}"] {
+            assert!(matches!(
+                Spex::from_regex(src, Flavor::Rust),
+                Err(RegexError::Syntax { .. })
+            ));
+        }
+        assert_eq!(
+            Spex::from_regex("\\U0000004", Flavor::Rust)
+                .unwrap_err()
+                .to_string(),
+            "0..9: 8 hex digits expected"
+        );
+        assert_eq!(
+            Spex::from_regex("a)", Flavor::Rust)
+                .unwrap_err()
+                .to_string(),
+            "1..2: unmatched )"
+        );
+        assert_eq!(
+            Spex::from_regex("x\\2", Flavor::EcmaScript)
+                .unwrap_err()
+                .to_string(),
+            "1..3: backreferences are not regular"
+        );
+    }
+
+    #[test]
+    fn import_round_trip() {
+        for src in [
+            "(a+|ab)&!a",
+            "!(.*foo.*)",
+            "~>abc",
+            "[^\\]\\-\\^a]+",
+            "[Z-b]*\t",
+            "\\d+(\\.\\d+)?",
+            "(.a)*",
+            "a&b",
+            "!.+",
+        ] {
+            let expected = spex(src);
+            for flavor in [Flavor::Ere, Flavor::EcmaScript, Flavor::Rust] {
+                let regex = expected.to_regex(flavor);
+                assert!(from(&regex, flavor) == expected, "{} -> {}", src, regex);
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! `serde` フィーチャーで使うJSONの形式(version 1)
+//!
+//! ```text
+//! Spex       {"version": 1, "accepts": [sid, ...], "transitions": [Transition, ...]}
+//! Transition {"from": sid, "to": sid, "chex": Chex}
+//! Chex       {"include": bool, "ranges": [["a", "z"], ...]}
+//! Kind       "Blank" | "Whole" | "Other"
+//! ```
+//!
+//! sidは整数で、0が開始状態。acceptsは昇順、transitionsは (from, to, chex) の順に並べる
+//! Chexのrangesは昇順・重複なし・隣接なしで、includeがfalseならrangesの補集合を表す
+//! 読み込むときは、すべての状態の遷移が重ならずに全ての文字を覆っているかを確かめ、
+//! 開始状態から到達できない状態を取り除く
+//! Kindは変種名の文字列で、SpexとChexのJSONには含めず、読み込むときに遷移から求め直す
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::iter::FromIterator;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::chex::{Chex, Range};
+use crate::spex::{Spex, Transition};
+
+/// 形式の版
+pub const VERSION: u32 = 1;
+
+/// 読み込んだ内容がオートマトンとして正しくないときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    Version {
+        found: u32,
+    },
+    /// 範囲の始まりが終わりより後ろにある
+    InvalidRange {
+        range: Range,
+    },
+    /// sid 0 の遷移がない
+    MissingStart,
+    /// 遷移のない状態への遷移
+    UnknownState {
+        sid: isize,
+    },
+    /// 遷移のない状態が受理状態になっている
+    UnknownAccept {
+        sid: isize,
+    },
+    /// 同じ文字で二つ以上の遷移ができる
+    Overlap {
+        sid: isize,
+    },
+    /// どの遷移にも当てはまらない文字がある
+    Incomplete {
+        sid: isize,
+    },
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::Version { found } => {
+                write!(f, "unsupported version {} (expected {})", found, VERSION)
+            }
+            SchemaError::InvalidRange { range } => {
+                write!(f, "invalid range {:?}-{:?}", range.0, range.1)
+            }
+            SchemaError::MissingStart => write!(f, "state 0 has no transitions"),
+            SchemaError::UnknownState { sid } => {
+                write!(f, "transition to state {} which has no transitions", sid)
+            }
+            SchemaError::UnknownAccept { sid } => write!(f, "unknown accepting state {}", sid),
+            SchemaError::Overlap { sid } => {
+                write!(f, "transitions from state {} overlap", sid)
+            }
+            SchemaError::Incomplete { sid } => {
+                write!(f, "transitions from state {} do not cover every char", sid)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Chexの形式
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChexData {
+    pub include: bool,
+    pub ranges: Vec<Range>,
+}
+
+/// Transitionの形式
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransitionData {
+    pub from: isize,
+    pub to: isize,
+    pub chex: ChexData,
+}
+
+/// Spexの形式。検証する前の内容をそのまま持つ
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpexData {
+    pub version: u32,
+    pub accepts: Vec<isize>,
+    pub transitions: Vec<TransitionData>,
+}
+
+impl From<&Chex> for ChexData {
+    fn from(chex: &Chex) -> Self {
+        Self {
+            include: chex.include_flg,
+            ranges: chex.ranges.clone(),
+        }
+    }
+}
+
+impl TryFrom<ChexData> for Chex {
+    type Error = SchemaError;
+
+    fn try_from(data: ChexData) -> Result<Self, SchemaError> {
+        if let Some(range) = data.ranges.iter().find(|(start, end)| start > end) {
+            return Err(SchemaError::InvalidRange { range: *range });
+        }
+
+        Ok(Chex::from_ranges(data.ranges, data.include))
+    }
+}
+
+impl From<&Transition> for TransitionData {
+    fn from(tn: &Transition) -> Self {
+        Self {
+            from: tn.fr_sid,
+            to: tn.to_sid,
+            chex: ChexData::from(&tn.chex),
+        }
+    }
+}
+
+impl TryFrom<TransitionData> for Transition {
+    type Error = SchemaError;
+
+    fn try_from(data: TransitionData) -> Result<Self, SchemaError> {
+        Ok(Transition::new(
+            data.from,
+            data.to,
+            Chex::try_from(data.chex)?,
+        ))
+    }
+}
+
+impl From<&Spex> for SpexData {
+    fn from(spex: &Spex) -> Self {
+        let mut accepts: Vec<isize> = spex.accepts_sids.iter().copied().collect();
+        accepts.sort_unstable();
+        let mut transitions: Vec<TransitionData> = spex
+            .tns_dict
+            .values()
+            .flatten()
+            .map(TransitionData::from)
+            .collect();
+        transitions.sort_by(|a, b| {
+            (a.from, a.to, &a.chex.ranges, a.chex.include).cmp(&(
+                b.from,
+                b.to,
+                &b.chex.ranges,
+                b.chex.include,
+            ))
+        });
+
+        Self {
+            version: VERSION,
+            accepts,
+            transitions,
+        }
+    }
+}
+
+impl TryFrom<SpexData> for Spex {
+    type Error = SchemaError;
+
+    fn try_from(data: SpexData) -> Result<Self, SchemaError> {
+        if data.version != VERSION {
+            return Err(SchemaError::Version {
+                found: data.version,
+            });
+        }
+        let tns = data
+            .transitions
+            .into_iter()
+            .map(Transition::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // 状態ごとに、遷移の文字集合が互いに素で合わせて全集合になるか
+        let mut covers: HashMap<isize, Chex> = HashMap::new();
+        for tn in &tns {
+            let cover = covers.entry(tn.fr_sid).or_insert_with(Chex::new_blank);
+            if !(&*cover & &tn.chex).blank() {
+                return Err(SchemaError::Overlap { sid: tn.fr_sid });
+            }
+            *cover = &*cover | &tn.chex;
+        }
+        if !covers.contains_key(&0) {
+            return Err(SchemaError::MissingStart);
+        }
+        if let Some(tn) = tns.iter().find(|tn| !covers.contains_key(&tn.to_sid)) {
+            return Err(SchemaError::UnknownState { sid: tn.to_sid });
+        }
+        let mut sids: Vec<&isize> = covers.keys().collect();
+        sids.sort_unstable();
+        if let Some(sid) = sids.into_iter().find(|sid| !covers[sid].whole()) {
+            return Err(SchemaError::Incomplete { sid: *sid });
+        }
+        if let Some(sid) = data.accepts.iter().find(|sid| !covers.contains_key(sid)) {
+            return Err(SchemaError::UnknownAccept { sid: *sid });
+        }
+
+        Ok(Spex::new(tns, HashSet::from_iter(data.accepts)).trim())
+    }
+}
+
+impl Serialize for Chex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ChexData::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Chex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Chex::try_from(ChexData::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for Transition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TransitionData::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Transition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Transition::try_from(TransitionData::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for Spex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SpexData::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Spex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Spex::try_from(SpexData::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::spex;
+    use crate::chex::Kind as ChexKind;
+    use crate::spex::Kind as SpexKind;
+
+    #[test]
+    fn schema_round_trip() {
+        for src in ["(a+|ab)&!a", "[^a-z]*\\d", "!.+", "a&b", ".*"] {
+            let spex1 = spex(src);
+            let json = serde_json::to_string(&spex1).unwrap();
+            assert_eq!(serde_json::from_str::<Spex>(&json).unwrap(), spex1);
+        }
+        let chex = Chex::from_ranges(vec![('a', 'c'), ('x', 'x')], false);
+        let json = serde_json::to_string(&chex).unwrap();
+        assert_eq!(json, r#"{"include":false,"ranges":[["a","c"],["x","x"]]}"#);
+        assert_eq!(serde_json::from_str::<Chex>(&json).unwrap(), chex);
+    }
+
+    #[test]
+    fn schema_kind() {
+        for kind in [SpexKind::Blank, SpexKind::Whole, SpexKind::Other] {
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(serde_json::from_str::<SpexKind>(&json).unwrap(), kind);
+        }
+        for kind in [ChexKind::Blank, ChexKind::Whole, ChexKind::Other] {
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(serde_json::from_str::<ChexKind>(&json).unwrap(), kind);
+        }
+        assert_eq!(
+            serde_json::to_string(&SpexKind::Whole).unwrap(),
+            r#""Whole""#
+        );
+        assert_eq!(spex(".*").kind, SpexKind::Whole);
+        let json = serde_json::to_string(&spex(".*")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Spex>(&json).unwrap().kind,
+            SpexKind::Whole
+        );
+    }
+
+    #[test]
+    fn schema_stable() {
+        let json = serde_json::to_string(&Spex::build_by_chex(&Chex::new(vec!['a'], true)));
+        assert_eq!(
+            json.unwrap(),
+            r#"{"version":1,"accepts":[1],"transitions":["#.to_string()
+                + r#"{"from":0,"to":1,"chex":{"include":true,"ranges":[["a","a"]]}},"#
+                + r#"{"from":0,"to":2,"chex":{"include":false,"ranges":[["a","a"]]}},"#
+                + r#"{"from":1,"to":2,"chex":{"include":false,"ranges":[]}},"#
+                + r#"{"from":2,"to":2,"chex":{"include":false,"ranges":[]}}]}"#
+        );
+    }
+
+    #[test]
+    fn schema_validation() {
+        let whole = r#"{"include":false,"ranges":[]}"#;
+        let load = |accepts: &str, transitions: &str| {
+            let json = format!(
+                r#"{{"version":1,"accepts":{},"transitions":[{}]}}"#,
+                accepts, transitions
+            );
+            let data: SpexData = serde_json::from_str(&json).unwrap();
+            Spex::try_from(data)
+        };
+        let tn = |from: isize, to: isize, chex: &str| {
+            format!(r#"{{"from":{},"to":{},"chex":{}}}"#, from, to, chex)
+        };
+        let a = r#"{"include":true,"ranges":[["a","a"]]}"#;
+        let not_a = r#"{"include":false,"ranges":[["a","a"]]}"#;
+
+        let ok = [tn(0, 1, a), tn(0, 1, not_a), tn(1, 1, whole)].join(",");
+        assert!(load("[1]", &ok).is_ok());
+        assert_eq!(
+            load("[1]", &tn(1, 1, whole)),
+            Err(SchemaError::MissingStart)
+        );
+        assert_eq!(
+            load("[1]", &[tn(0, 1, whole)].join(",")),
+            Err(SchemaError::UnknownState { sid: 1 })
+        );
+        assert_eq!(
+            load(
+                "[1]",
+                &[tn(0, 0, a), tn(0, 1, whole), tn(1, 1, whole)].join(",")
+            ),
+            Err(SchemaError::Overlap { sid: 0 })
+        );
+        assert_eq!(
+            load("[1]", &[tn(0, 1, a), tn(1, 1, whole)].join(",")),
+            Err(SchemaError::Incomplete { sid: 0 })
+        );
+        assert_eq!(load("[2]", &ok), Err(SchemaError::UnknownAccept { sid: 2 }));
+        assert_eq!(
+            load("[1]", &tn(0, 0, r#"{"include":true,"ranges":[["b","a"]]}"#)),
+            Err(SchemaError::InvalidRange { range: ('b', 'a') })
+        );
+        // 到達できない状態は取り除く
+        let dot = tn(0, 0, whole);
+        assert_eq!(
+            load("[0]", &[dot.clone(), tn(1, 1, whole)].join(",")),
+            Ok(spex(".*"))
+        );
+        assert_eq!(
+            load("[1]", &[dot, tn(1, 1, whole)].join(",")),
+            Ok(spex("!.*"))
+        );
+
+        let err = serde_json::from_str::<Spex>(r#"{"version":2,"accepts":[],"transitions":[]}"#)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unsupported version 2 (expected 1)");
+    }
+}
@@ -1,10 +1,19 @@
-use crate::chex::Chex;
-use std::collections::{HashMap, HashSet};
+use crate::chex::{Chex, Range};
+use crate::parser::{
+    get_concat_node, get_empty_node, get_exc_chex, get_inc_chex, get_optional_node, get_or_node,
+    get_repeat_node, get_star_node, Node, Span,
+};
+use crate::printer::to_source;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
 use std::iter::FromIterator;
 use std::ops::{BitAnd, BitOr, Not};
 
 type Sid = isize;
+/// 行き先の組ごとにまとめた遷移
+type GroupedTns = Vec<(usize, Vec<Range>)>;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Kind {
     /// 0
@@ -15,6 +24,106 @@ pub enum Kind {
     Other,
 }
 
+/// 図の向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    /// mermaidの `graph LR`、DOTの `rankdir=LR`
+    #[default]
+    LeftRight,
+    /// mermaidの `graph TD`、DOTの `rankdir=TB`
+    TopDown,
+}
+
+/// mermaidやDOTで書き出すときの指定
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    hide_dead: bool,
+    merge_edges: bool,
+    state_labels: bool,
+    direction: Direction,
+}
+
+impl RenderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 受理状態に到達できない状態とそこへの遷移を書かない
+    pub fn hide_dead(mut self, hide_dead: bool) -> Self {
+        self.hide_dead = hide_dead;
+        self
+    }
+
+    /// 同じ状態の組の間の遷移を一本にまとめる
+    pub fn merge_edges(mut self, merge_edges: bool) -> Self {
+        self.merge_edges = merge_edges;
+        self
+    }
+
+    /// 状態にsidを書く
+    pub fn state_labels(mut self, state_labels: bool) -> Self {
+        self.state_labels = state_labels;
+        self
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+}
+
+/// 文字列を読んだときの状態の移り変わり
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    /// 一文字ごとの(元の状態, 行き先の状態, 文字)
+    pub steps: Vec<(Sid, Sid, char)>,
+    pub accepted: bool,
+    /// 受理状態に到達できない状態に入った最初の遷移
+    /// なければ、受理しないときに受理状態から最後に出た遷移
+    pub fault: Option<usize>,
+}
+
+/// 図の中の状態をrunが通ったか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    None,
+    Passed,
+    /// Run::faultの遷移の行き先
+    Fault,
+}
+
+fn visit(run: Option<&Run>, sid: Sid) -> Visit {
+    let run = match run {
+        Some(run) => run,
+        None => return Visit::None,
+    };
+    if run.fault.is_some_and(|i| run.steps[i].1 == sid) {
+        Visit::Fault
+    } else if sid == 0 || run.steps.iter().any(|(_, to_sid, _)| *to_sid == sid) {
+        Visit::Passed
+    } else {
+        Visit::None
+    }
+}
+
+/// 図に描く遷移。stepsはrunがこの遷移を通った何文字目か
+struct Edge {
+    fr_sid: Sid,
+    to_sid: Sid,
+    chex: Chex,
+    steps: Vec<usize>,
+    fault: bool,
+}
+
+/// 遷移のラベルに付ける ` (1, 3)`
+fn step_numbers(steps: &[usize]) -> String {
+    if steps.is_empty() {
+        return String::new();
+    }
+    let steps: Vec<String> = steps.iter().map(|step| step.to_string()).collect();
+    format!(" ({})", steps.join(", "))
+}
+
 #[derive(Debug, Clone)]
 pub struct Spex {
     pub accepts_sids: HashSet<Sid>,
@@ -28,16 +137,12 @@ impl Spex {
 
         for tn in tn_list {
             let fr_sid = tn.fr_sid;
-            if !tns_dict.contains_key(&fr_sid) {
-                tns_dict.insert(fr_sid, vec![]);
-            }
-
-            tns_dict.get_mut(&fr_sid).unwrap().push(tn);
+            tns_dict.entry(fr_sid).or_default().push(tn);
         }
 
-        let kind = if accepts_sids.len() == 0 {
+        let kind = if accepts_sids.is_empty() {
             Kind::Blank // 空集合
-        } else if accepts_sids.len() == tns_dict.len() - 1 {
+        } else if accepts_sids.len() == tns_dict.len() {
             Kind::Whole // 全集合
         } else {
             Kind::Other // それ以外？
@@ -58,18 +163,23 @@ impl Spex {
         Self::buid_whole()
     }
 
+    /// 空文字列のみを受理する
+    pub fn empty_string() -> Self {
+        Self::new(
+            vec![
+                Transition::new(0, 1, Chex::new_whole()),
+                Transition::new(1, 1, Chex::new_whole()),
+            ],
+            HashSet::from_iter([0]),
+        )
+    }
+
     pub fn blank(&self) -> bool {
-        match self.kind {
-            Kind::Blank => true,
-            _ => false,
-        }
+        matches!(self.kind, Kind::Blank)
     }
 
     pub fn whole(&self) -> bool {
-        match self.kind {
-            Kind::Whole => true,
-            _ => false,
-        }
+        matches!(self.kind, Kind::Whole)
     }
 
     pub fn include(&self, other: &Self) -> bool {
@@ -84,6 +194,19 @@ impl Spex {
         }
     }
 
+    pub fn is_match(&self, input: &str) -> bool {
+        let mut sid = 0;
+        for char in input.chars() {
+            sid = self.tns_dict[&sid]
+                .iter()
+                .find(|tn| tn.chex.contains(char))
+                .unwrap()
+                .to_sid;
+        }
+
+        self.accepts_sids.contains(&sid)
+    }
+
     pub fn build_by_chex(chex: &Chex) -> Self {
         if chex.blank() {
             Self::new(
@@ -112,13 +235,22 @@ impl Spex {
                 Transition::new(0, 1, Chex::new_whole()),
                 Transition::new(1, 1, Chex::new_whole()),
             ],
-            HashSet::from_iter([1]),
+            HashSet::from_iter([0, 1]),
         )
     }
 
     pub fn concat(&self, other: &Self) -> Self {
+        self.concat_within(other, None).unwrap()
+    }
+
+    /// concatと同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn concat_within(&self, other: &Self, limit: Option<usize>) -> Option<Self> {
+        let mut sid_gen = SidGen::with_limit(limit);
         let mut new_tn_list: Vec<Transition> = vec![];
         let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+        if self.accepts_sids.contains(&0) && other.accepts_sids.contains(&0) {
+            new_accepts_sids.insert(0);
+        }
 
         Spex::calc_concat(
             0,
@@ -128,18 +260,27 @@ impl Spex {
             other,
             0,
             HashSet::new(),
-            &mut SidGen::new(),
+            &mut sid_gen,
             &mut HashMap::new(),
         );
 
-        Spex::new(new_tn_list, new_accepts_sids)
+        Spex::finish(new_tn_list, new_accepts_sids, &sid_gen)
     }
 
     pub fn repeat(&self) -> Self {
+        self.repeat_within(None).unwrap()
+    }
+
+    /// repeatと同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn repeat_within(&self, limit: Option<usize>) -> Option<Self> {
+        let mut sid_gen = SidGen::with_limit(limit);
         let mut new_tn_list: Vec<Transition> = vec![];
         let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
         let mut sids = HashSet::new();
         sids.insert(0);
+        if self.accepts_sids.contains(&0) {
+            new_accepts_sids.insert(0);
+        }
 
         Spex::calc_repeat(
             0,
@@ -147,481 +288,1268 @@ impl Spex {
             &mut new_accepts_sids,
             self,
             sids,
-            &mut SidGen::new(),
+            &mut sid_gen,
             &mut HashMap::new(),
         );
 
-        Spex::new(new_tn_list, new_accepts_sids)
+        Spex::finish(new_tn_list, new_accepts_sids, &sid_gen)
     }
 
-    pub fn mermaid(&self) -> String {
-        let mut ret = String::from("```mermaid\ngraph LR\n");
-        for sid in self.tns_dict.keys() {
-            match sid {
-                0 => ret += "    0(( ))\n",
-                -1 => ret += "    -1(( ))\n",
-                -2 => ret += "    -2(( ))\n",
-                _ => ret += &format!("    {}( )\n", sid),
-            }
-        }
+    /// 0回または1回
+    pub fn optional(&self) -> Self {
+        &Self::empty_string() | self
+    }
 
-        for sid in self.tns_dict.keys() {
-            match sid {
-                0 => ret += "    style 0 fill:#000,stroke-width:0px\n",
-                -1 => ret += "    style -1 fill:#adb5bd,stroke-width:0px\n",
-                -2 => ret += "    style -2 fill:#adb5bd,stroke:#dc3545,stroke-width:4px\n",
-                _ => {
-                    if self.accepts_sids.contains(sid) {
-                        ret += &format!("    style {} stroke:#dc3545,stroke-width:4px\n", sid)
-                    }
+    /// 0回以上の繰返し
+    pub fn star(&self) -> Self {
+        &Self::empty_string() | &self.repeat()
+    }
+
+    /// starと同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn star_within(&self, limit: Option<usize>) -> Option<Self> {
+        Self::empty_string().or_within(&self.repeat_within(limit)?, limit)
+    }
+
+    /// `|` と同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn or_within(&self, other: &Self, limit: Option<usize>) -> Option<Self> {
+        match (&self.kind, &other.kind) {
+            (Kind::Blank, _) | (_, Kind::Whole) => Some(other.clone()),
+            (Kind::Whole, _) | (_, Kind::Blank) => Some(self.clone()),
+            (Kind::Other, Kind::Other) => {
+                let mut sid_gen = SidGen::with_limit(limit);
+                let mut new_tn_list: Vec<Transition> = vec![];
+                let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+                if self.accepts_sids.contains(&0) || other.accepts_sids.contains(&0) {
+                    new_accepts_sids.insert(0);
                 }
-            }
-        }
+                Spex::calc_and_or(
+                    0,
+                    &mut new_tn_list,
+                    &mut new_accepts_sids,
+                    self,
+                    other,
+                    0,
+                    0,
+                    &mut sid_gen,
+                    &mut HashMap::new(),
+                    0,
+                );
 
-        for tns in self.tns_dict.values() {
-            for tn in tns {
-                ret += &format!("    {} -- \"{}\" --> {}\n", tn.fr_sid, tn.chex, tn.to_sid)
+                Spex::finish(new_tn_list, new_accepts_sids, &sid_gen)
             }
         }
-
-        ret += "```";
-
-        ret
     }
 
-    pub fn calc_and_or(
-        new_fr_sid: Sid,
-        new_tn_list: &mut Vec<Transition>,
-        new_accepts_sids: &mut HashSet<Sid>,
-        spex1: &Self,
-        spex2: &Self,
-        spex1_sid: Sid,
-        spex2_sid: Sid,
-        sid_gen: &mut SidGen,
-        sid_dict_by_skey: &mut HashMap<String, Sid>,
-        ope_kind: usize,
-    ) {
-        let mut chex_pattern = vec![Chex::new_whole()];
-        let mut tmp_chex_pattern: Vec<Chex> = vec![];
-        for ref target_chex in chex_pattern {
-            for tn in &spex1.tns_dict[&spex1_sid] {
-                let and_chex = target_chex & &tn.chex;
-                if !&and_chex.blank() {
-                    tmp_chex_pattern.push(and_chex);
+    /// `&` と同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn and_within(&self, other: &Self, limit: Option<usize>) -> Option<Self> {
+        match (&self.kind, &other.kind) {
+            (Kind::Blank, _) | (_, Kind::Whole) => Some(self.clone()),
+            (Kind::Whole, _) | (_, Kind::Blank) => Some(other.clone()),
+            (Kind::Other, Kind::Other) => {
+                let mut sid_gen = SidGen::with_limit(limit);
+                let mut new_tn_list: Vec<Transition> = vec![];
+                let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+                if self.accepts_sids.contains(&0) && other.accepts_sids.contains(&0) {
+                    new_accepts_sids.insert(0);
                 }
+                Spex::calc_and_or(
+                    0,
+                    &mut new_tn_list,
+                    &mut new_accepts_sids,
+                    self,
+                    other,
+                    0,
+                    0,
+                    &mut sid_gen,
+                    &mut HashMap::new(),
+                    1,
+                );
+
+                Spex::finish(new_tn_list, new_accepts_sids, &sid_gen)
             }
         }
-        chex_pattern = tmp_chex_pattern;
+    }
 
-        tmp_chex_pattern = vec![];
-        for ref target_chex in chex_pattern {
-            for tn in &spex2.tns_dict[&spex2_sid] {
-                let and_chex = target_chex & &tn.chex;
-                if !&and_chex.blank() {
-                    tmp_chex_pattern.push(and_chex);
-                }
-            }
+    /// min回以上max回以下の繰返し(maxがNoneなら上限なし)
+    pub fn bounded(&self, min: usize, max: Option<usize>) -> Self {
+        self.bounded_within(min, max, None).unwrap()
+    }
+
+    /// boundedと同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn bounded_within(
+        &self,
+        min: usize,
+        max: Option<usize>,
+        limit: Option<usize>,
+    ) -> Option<Self> {
+        if max == Some(0) {
+            return Some(Self::empty_string());
+        }
+        let mut sid_gen = SidGen::with_limit(limit);
+        let mut new_tn_list: Vec<Transition> = vec![];
+        let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+        let live_sids = self.live_sids();
+        let sids = self.calc_bounded_closure(vec![(0, 0)], min, max);
+        if min == 0 || self.bounded_accepts(&sids, min) {
+            new_accepts_sids.insert(0);
         }
-        chex_pattern = tmp_chex_pattern;
 
-        for target_chex in chex_pattern {
-            // 2-1
-            // 必ず見つかる
-            let next_spex1_sid = spex1.tns_dict[&spex1_sid]
-                .iter()
-                .find(|tn| tn.chex.include(&target_chex))
-                .unwrap()
-                .to_sid;
+        Spex::calc_bounded(
+            0,
+            &mut new_tn_list,
+            &mut new_accepts_sids,
+            self,
+            sids,
+            min,
+            max,
+            &live_sids,
+            &mut sid_gen,
+            &mut HashMap::new(),
+        );
 
-            let next_spex2_sid = spex2.tns_dict[&spex2_sid]
-                .iter()
-                .find(|tn| tn.chex.include(&target_chex))
-                .unwrap()
-                .to_sid;
+        Spex::finish(new_tn_list, new_accepts_sids, &sid_gen)
+    }
 
-            // 2-2
-            let skey = format!("{}/{}", next_spex1_sid, next_spex2_sid);
-            let new_to_sid: Sid;
-            if sid_dict_by_skey.contains_key(&skey) {
-                // 2-2-1
-                new_to_sid = sid_dict_by_skey[&skey];
-            } else {
-                // 2-2-2
-                // 2-2-2-1
-                new_to_sid = sid_gen.get();
-
-                // 2-2-2-2
-                sid_dict_by_skey.insert(skey, new_to_sid);
-                // 2-2-2-3
-                if ope_kind == 0 {
-                    if spex1.accepts_sids.contains(&next_spex1_sid)
-                        || spex2.accepts_sids.contains(&next_spex2_sid)
-                    {
-                        new_accepts_sids.insert(new_to_sid);
-                    }
-                } else if ope_kind == 1 {
-                    if spex1.accepts_sids.contains(&next_spex1_sid)
-                        && spex2.accepts_sids.contains(&next_spex2_sid)
-                    {
-                        new_accepts_sids.insert(new_to_sid);
-                    }
-                } else {
-                    unreachable!()
-                }
-
-                // 2-2-2-4
-                Self::calc_and_or(
-                    new_to_sid,
-                    new_tn_list,
-                    new_accepts_sids,
-                    spex1,
-                    spex2,
-                    next_spex1_sid,
-                    next_spex2_sid,
-                    sid_gen,
-                    sid_dict_by_skey,
-                    ope_kind,
-                );
-            }
-            // 2-3
-            new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex));
+    /// 構成が状態数の上限に達していなければSpexにする
+    fn finish(
+        new_tn_list: Vec<Transition>,
+        new_accepts_sids: HashSet<Sid>,
+        sid_gen: &SidGen,
+    ) -> Option<Self> {
+        if sid_gen.exhausted() {
+            None
+        } else {
+            Some(Spex::new(new_tn_list, new_accepts_sids))
         }
     }
 
-    pub fn calc_concat(
-        new_fr_sid: Sid,
-        new_tn_list: &mut Vec<Transition>,
-        new_accepts_sids: &mut HashSet<Sid>,
-        spex1: &Self,
-        spex2: &Self,
-        spex1_sid: Sid,
-        spex2_sids: HashSet<Sid>,
-        sid_gen: &mut SidGen,
-        sid_dict_by_skey: &mut HashMap<String, Sid>,
-    ) {
-        let mut chex_pattern = vec![Chex::new_whole()];
-        let mut tmp_chex_pattern: Vec<Chex> = vec![];
-        for ref target_chex in chex_pattern {
-            for tn in &spex1.tns_dict[&spex1_sid] {
-                let and_chex = target_chex & &tn.chex;
-                if !&and_chex.blank() {
-                    tmp_chex_pattern.push(and_chex);
+    /// 開始状態から到達可能な状態だけを残す
+    /// kindは状態の数で決めるので、到達できない状態があると正しくならない
+    pub(crate) fn trim(&self) -> Self {
+        let mut reachable: HashSet<Sid> = HashSet::from([0]);
+        let mut stack = vec![0];
+        while let Some(sid) = stack.pop() {
+            for tn in self.tns_dict.get(&sid).into_iter().flatten() {
+                if reachable.insert(tn.to_sid) {
+                    stack.push(tn.to_sid);
                 }
             }
         }
-        chex_pattern = tmp_chex_pattern;
-
-        if spex1.accepts_sids.contains(&spex1_sid) {
-            tmp_chex_pattern = vec![];
-            for ref target_chex in chex_pattern {
-                for tn in spex2.tns_dict.get(&0).unwrap() {
-                    let and_chex = target_chex & &tn.chex;
-                    if !&and_chex.blank() {
-                        tmp_chex_pattern.push(and_chex);
-                    }
-                }
-            }
-            chex_pattern = tmp_chex_pattern;
+        if reachable.len() == self.tns_dict.len() {
+            return self.clone();
         }
+        let tn_list: Vec<Transition> = self
+            .tns_dict
+            .iter()
+            .filter(|(sid, _)| reachable.contains(sid))
+            .flat_map(|(_, tns)| tns.iter().cloned())
+            .collect();
+        let accepts_sids = self
+            .accepts_sids
+            .intersection(&reachable)
+            .copied()
+            .collect();
 
-        for spex2_sid in &spex2_sids {
-            tmp_chex_pattern = vec![];
-            for ref target_chex in chex_pattern {
-                for tn in spex2.tns_dict.get(spex2_sid).unwrap() {
-                    let and_chex = target_chex & &tn.chex;
-                    if !&and_chex.blank() {
-                        tmp_chex_pattern.push(and_chex);
-                    }
+        Spex::new(tn_list, accepts_sids)
+    }
+
+    /// 受理状態に到達可能な状態
+    pub fn live_sids(&self) -> HashSet<Sid> {
+        let mut live_sids = self.accepts_sids.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (sid, tns) in &self.tns_dict {
+                if !live_sids.contains(sid) && tns.iter().any(|tn| live_sids.contains(&tn.to_sid)) {
+                    live_sids.insert(*sid);
+                    changed = true;
                 }
             }
-            chex_pattern = tmp_chex_pattern;
         }
 
-        // 2
-        //let mut next_spex1_sid: Sid;
-        for target_chex in chex_pattern {
-            // 2-1
-            let next_spex1_sid = spex1.tns_dict[&spex1_sid]
-                .iter()
-                .find(|tn| tn.chex.include(&target_chex))
-                .unwrap()
-                .to_sid;
-
-            let mut next_spex2_sids: HashSet<Sid> = HashSet::new();
+        live_sids
+    }
 
-            if spex1.accepts_sids.contains(&spex1_sid) {
-                // spex1が受理状態の場合は、spex2の最初の遷移を考慮する
-                for tn in spex2.tns_dict.get(&0).unwrap() {
-                    if tn.chex.include(&target_chex) {
-                        next_spex2_sids.insert(tn.to_sid);
-                        break;
-                    }
+    /// 受理する最短の文字列を一つ返す。空集合ならNone
+    pub fn example(&self) -> Option<String> {
+        let mut prevs: HashMap<Sid, (Sid, char)> = HashMap::new();
+        let mut queue: VecDeque<Sid> = VecDeque::from([0]);
+        let mut visited: HashSet<Sid> = HashSet::from([0]);
+        while let Some(sid) = queue.pop_front() {
+            if self.accepts_sids.contains(&sid) {
+                let mut chars = vec![];
+                let mut sid = sid;
+                while let Some(&(prev, char)) = prevs.get(&sid) {
+                    chars.push(char);
+                    sid = prev;
                 }
+                return Some(chars.into_iter().rev().collect());
             }
-            for spex2_sid in &spex2_sids {
-                for tn in spex2.tns_dict.get(spex2_sid).unwrap() {
-                    if tn.chex.include(&target_chex) {
-                        next_spex2_sids.insert(tn.to_sid);
-                        break;
+            for tn in self.tns_dict.get(&sid).into_iter().flatten() {
+                if let Some(char) = tn.chex.sample() {
+                    if visited.insert(tn.to_sid) {
+                        prevs.insert(tn.to_sid, (sid, char));
+                        queue.push_back(tn.to_sid);
                     }
                 }
             }
+        }
 
-            // 2-2
-            let skey = if next_spex2_sids.len() == 0 {
-                format!("{}/", next_spex1_sid)
-            } else {
-                let mut vec_sid: Vec<Sid> = next_spex2_sids.clone().into_iter().collect();
-                vec_sid.sort();
-                format!(
-                    "{}/{}",
-                    next_spex1_sid,
-                    vec_sid
-                        .iter()
-                        .map(|x| x.to_string())
-                        .collect::<Vec<String>>()
-                        .join("-")
-                )
-            };
+        None
+    }
 
-            let new_to_sid: Sid;
-            if sid_dict_by_skey.contains_key(&skey) {
-                // 2-2-1
-                new_to_sid = sid_dict_by_skey[&skey];
-            } else {
-                // 2-2-2
-                // 2-2-2-1
-                new_to_sid = sid_gen.get();
-                // 2-2-2-2
-                sid_dict_by_skey.insert(skey, new_to_sid);
-                // 2-2-2-3
-                for next_spex2_sid in &next_spex2_sids {
-                    if spex2.accepts_sids.contains(next_spex2_sid) {
-                        new_accepts_sids.insert(new_to_sid);
-                        break;
+    /// 受理する文字列が各位置の文字集合の並びで表せるなら、その並び
+    pub(crate) fn chain(&self) -> Option<Vec<Chex>> {
+        let spex = self.minimize();
+        let live_sids = spex.live_sids();
+        if !live_sids.contains(&0) {
+            return None;
+        }
+        let mut chexes = vec![];
+        let mut sid = 0;
+        loop {
+            let tns: Vec<&Transition> = spex
+                .tns_dict
+                .get(&sid)
+                .into_iter()
+                .flatten()
+                .filter(|tn| live_sids.contains(&tn.to_sid))
+                .collect();
+            if tns.is_empty() {
+                return Some(chexes);
+            }
+            if spex.accepts_sids.contains(&sid) || chexes.len() > spex.tns_dict.len() {
+                return None;
+            }
+            let to_sid = tns[0].to_sid;
+            if tns.iter().any(|tn| tn.to_sid != to_sid) {
+                return None;
+            }
+            chexes.push(
+                tns.iter()
+                    .fold(Chex::new_blank(), |chex, tn| &chex | &tn.chex),
+            );
+            sid = to_sid;
+        }
+    }
+
+    /// (完了した繰返し回数, 状態)の集合に、次の繰返しの開始状態を加える
+    fn calc_bounded_closure(
+        &self,
+        mut sids: Vec<(usize, Sid)>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Vec<(usize, Sid)> {
+        let mut i = 0;
+        while i < sids.len() {
+            let (count, sid) = sids[i];
+            if self.accepts_sids.contains(&sid) {
+                let next = match max {
+                    Some(max) if count + 1 < max => Some((count + 1, 0)),
+                    Some(_) => None,
+                    None => Some(((count + 1).min(min), 0)),
+                };
+                if let Some(next) = next {
+                    if !sids.contains(&next) {
+                        sids.push(next);
                     }
                 }
-                // 2-2-2-4
-                Self::calc_concat(
-                    new_to_sid,
-                    new_tn_list,
-                    new_accepts_sids,
-                    spex1,
-                    spex2,
-                    next_spex1_sid,
-                    next_spex2_sids,
-                    sid_gen,
-                    sid_dict_by_skey,
-                )
             }
-            new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex));
+            i += 1;
         }
+        sids.sort_unstable();
+
+        sids
     }
 
-    pub fn calc_repeat(
+    fn bounded_accepts(&self, sids: &[(usize, Sid)], min: usize) -> bool {
+        sids.iter()
+            .any(|(count, sid)| count + 1 >= min && self.accepts_sids.contains(sid))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn calc_bounded(
         new_fr_sid: Sid,
         new_tn_list: &mut Vec<Transition>,
         new_accepts_sids: &mut HashSet<Sid>,
         spex: &Self,
-        spex_sids: HashSet<Sid>,
+        spex_sids: Vec<(usize, Sid)>,
+        min: usize,
+        max: Option<usize>,
+        live_sids: &HashSet<Sid>,
         sid_gen: &mut SidGen,
         sid_dict_by_skey: &mut HashMap<String, Sid>,
     ) {
-        let mut chex_pattern = vec![Chex::new_whole()];
-
-        for spex_sid in spex_sids.iter() {
-            let mut tmp_chex_pattern: Vec<Chex> = vec![];
-            for target_chex in &chex_pattern {
-                for tn in &spex.tns_dict[spex_sid] {
-                    let and_chex = target_chex & &tn.chex;
-                    if !&and_chex.blank() {
-                        tmp_chex_pattern.push(and_chex);
-                    }
-                }
+        let mut stack = vec![(new_fr_sid, spex_sids)];
+        while let Some((new_fr_sid, spex_sids)) = stack.pop() {
+            if sid_gen.exhausted() {
+                break;
             }
-            chex_pattern = tmp_chex_pattern.clone();
-        }
-        for spex_sid in spex_sids.iter() {
-            if spex.accepts_sids.contains(&spex_sid) {
+            let mut chex_pattern = vec![Chex::new_whole()];
+            for (_, spex_sid) in spex_sids.iter() {
                 let mut tmp_chex_pattern: Vec<Chex> = vec![];
                 for target_chex in &chex_pattern {
-                    for tn in spex.tns_dict.get(&0).unwrap() {
+                    for tn in &spex.tns_dict[spex_sid] {
                         let and_chex = target_chex & &tn.chex;
                         if !&and_chex.blank() {
                             tmp_chex_pattern.push(and_chex);
                         }
                     }
                 }
-                chex_pattern = tmp_chex_pattern.clone();
-                break;
-            }
-        }
-        // 2
-        for target_chex in chex_pattern {
-            let mut next_spex_sids: HashSet<Sid> = HashSet::new();
-            for spex_sid in spex_sids.iter() {
-                if spex.accepts_sids.contains(spex_sid) {
-                    // 受理状態の場合は、最初の遷移を考慮する
-                    for tn in spex.tns_dict.get(&0).unwrap() {
-                        if tn.chex.include(&target_chex) {
-                            next_spex_sids.insert(tn.to_sid);
-                            break;
-                        }
-                    }
-                    break;
-                }
+                chex_pattern = tmp_chex_pattern;
             }
-            for spex_sid in spex_sids.iter() {
-                for tn in spex.tns_dict.get(&spex_sid).unwrap() {
-                    if tn.chex.include(&target_chex) {
-                        next_spex_sids.insert(tn.to_sid);
-                        break;
+
+            for target_chex in chex_pattern {
+                let mut next_spex_sids: Vec<(usize, Sid)> = vec![];
+                for (count, spex_sid) in spex_sids.iter() {
+                    let next_spex_sid = spex.tns_dict[spex_sid]
+                        .iter()
+                        .find(|tn| tn.chex.include(&target_chex))
+                        .unwrap()
+                        .to_sid;
+                    // 受理状態に到達できない状態は捨てる
+                    if live_sids.contains(&next_spex_sid)
+                        && !next_spex_sids.contains(&(*count, next_spex_sid))
+                    {
+                        next_spex_sids.push((*count, next_spex_sid));
                     }
                 }
-            }
-            let mut vec_sid: Vec<Sid> = next_spex_sids.clone().into_iter().collect();
-            vec_sid.sort();
-            let skey = vec_sid
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join("-");
+                let next_spex_sids = spex.calc_bounded_closure(next_spex_sids, min, max);
+                let skey = next_spex_sids
+                    .iter()
+                    .map(|(count, sid)| format!("{}:{}", count, sid))
+                    .collect::<Vec<String>>()
+                    .join("-");
 
-            let new_to_sid: Sid;
-            if sid_dict_by_skey.contains_key(&skey) {
-                new_to_sid = sid_dict_by_skey[&skey]
-            } else {
-                // 2-2-2
-                // 2-2-2-1
-                new_to_sid = sid_gen.get();
-                // 2-2-2-2
-                sid_dict_by_skey.insert(skey, new_to_sid);
-                // 2-2-2-3
-                for next_spex_sid in &next_spex_sids {
-                    if spex.accepts_sids.contains(next_spex_sid) {
+                let new_to_sid: Sid;
+                if let Some(&sid) = sid_dict_by_skey.get(&skey) {
+                    new_to_sid = sid;
+                } else {
+                    new_to_sid = sid_gen.get();
+                    sid_dict_by_skey.insert(skey, new_to_sid);
+                    if spex.bounded_accepts(&next_spex_sids, min) {
                         new_accepts_sids.insert(new_to_sid);
-                        break;
                     }
+                    stack.push((new_to_sid, next_spex_sids));
                 }
-                // 2-2-2-4
-                Self::calc_repeat(
-                    new_to_sid,
-                    new_tn_list,
-                    new_accepts_sids,
-                    spex,
-                    next_spex_sids,
-                    sid_gen,
-                    sid_dict_by_skey,
-                )
+                new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex))
             }
-            new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex))
         }
     }
-}
 
-impl PartialEq for Spex {
-    fn eq(&self, other: &Self) -> bool {
-        if self.kind == other.kind {
-            if self.kind != Kind::Other {
-                return true;
-            } else {
-                return self.include(other) && other.include(self);
-            }
-        } else {
-            return false;
-        }
+    /// spexに受理される文字列を部分文字列として含む文字列の集合
+    pub fn containing(spex: &Self) -> Self {
+        Self::searching(spex, true, None).unwrap()
     }
-}
 
-impl Not for &Spex {
-    type Output = Spex;
+    /// containingと同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn containing_within(spex: &Self, limit: Option<usize>) -> Option<Self> {
+        Self::searching(spex, true, limit)
+    }
 
-    fn not(self) -> Self::Output {
+    /// spexに受理される文字列で始まる文字列の集合
+    pub fn starting_with(spex: &Self) -> Self {
+        // 受理状態に達したら以降は何が続いても受理する
         let mut tn_list: Vec<Transition> = vec![];
-        let mut accepts_sids: HashSet<Sid> = HashSet::new();
-
-        for (sid, _trn) in &self.tns_dict {
-            if *sid != 0 && !self.accepts_sids.contains(&sid) {
-                accepts_sids.insert(*sid);
+        for (sid, tns) in &spex.tns_dict {
+            if spex.accepts_sids.contains(sid) {
+                tn_list.push(Transition::new(*sid, *sid, Chex::new_whole()));
+            } else {
+                tn_list.extend_from_slice(tns);
             }
-            tn_list.extend_from_slice(&self.tns_dict[&sid]);
         }
 
-        Spex::new(tn_list, accepts_sids)
+        // 受理状態の先にあった状態には到達できなくなる
+        Spex::new(tn_list, spex.accepts_sids.clone()).trim()
     }
-}
 
-impl BitOr for &Spex {
-    type Output = Spex;
+    /// spexに受理される文字列で終わる文字列の集合
+    pub fn ending_with(spex: &Self) -> Self {
+        Self::searching(spex, false, None).unwrap()
+    }
 
-    fn bitor(self, other: Self) -> Self::Output {
-        match self.kind {
-            Kind::Blank => other.clone(),
-            Kind::Whole => self.clone(),
-            Kind::Other => match other.kind {
-                Kind::Blank => self.clone(),
-                Kind::Whole => other.clone(),
-                Kind::Other => {
-                    let mut new_tn_list: Vec<Transition> = vec![];
-                    let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
-                    Spex::calc_and_or(
-                        0,
-                        &mut new_tn_list,
-                        &mut new_accepts_sids,
-                        &self,
-                        &other,
-                        0,
-                        0,
-                        &mut SidGen::new(),
-                        &mut HashMap::new(),
-                        0,
-                    );
-
-                    Spex::new(new_tn_list, new_accepts_sids)
-                }
-            },
-        }
+    /// ending_withと同じだが、状態数がlimitに達したらやめてNoneを返す
+    pub fn ending_with_within(spex: &Self, limit: Option<usize>) -> Option<Self> {
+        Self::searching(spex, false, limit)
     }
-}
+
+    /// 各位置からspexを読み始めた状態の集合を追う
+    /// absorbなら一度受理した後は何が続いても受理する
+    fn searching(spex: &Self, absorb: bool, limit: Option<usize>) -> Option<Self> {
+        if absorb && spex.accepts_sids.contains(&0) {
+            return Some(Spex::new_whole());
+        }
+        let mut sid_gen = SidGen::with_limit(limit);
+        let mut new_tn_list: Vec<Transition> = vec![];
+        let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+        let live_sids = spex.live_sids();
+        if spex.accepts_sids.contains(&0) {
+            new_accepts_sids.insert(0);
+        }
+
+        Spex::calc_searching(
+            0,
+            &mut new_tn_list,
+            &mut new_accepts_sids,
+            spex,
+            vec![0],
+            absorb,
+            &live_sids,
+            &mut sid_gen,
+            &mut HashMap::new(),
+        );
+
+        Spex::finish(new_tn_list, new_accepts_sids, &sid_gen)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn calc_searching(
+        new_fr_sid: Sid,
+        new_tn_list: &mut Vec<Transition>,
+        new_accepts_sids: &mut HashSet<Sid>,
+        spex: &Self,
+        spex_sids: Vec<Sid>,
+        absorb: bool,
+        live_sids: &HashSet<Sid>,
+        sid_gen: &mut SidGen,
+        sid_dict_by_skey: &mut HashMap<String, Sid>,
+    ) {
+        let mut stack = vec![(new_fr_sid, spex_sids)];
+        while let Some((new_fr_sid, spex_sids)) = stack.pop() {
+            if sid_gen.exhausted() {
+                break;
+            }
+            let mut chex_pattern = vec![Chex::new_whole()];
+            for spex_sid in spex_sids.iter() {
+                let mut tmp_chex_pattern: Vec<Chex> = vec![];
+                for target_chex in &chex_pattern {
+                    for tn in &spex.tns_dict[spex_sid] {
+                        let and_chex = target_chex & &tn.chex;
+                        if !&and_chex.blank() {
+                            tmp_chex_pattern.push(and_chex);
+                        }
+                    }
+                }
+                chex_pattern = tmp_chex_pattern;
+            }
+
+            for target_chex in chex_pattern {
+                // 次の位置から読み始める場合の開始状態を常に含める
+                let mut next_spex_sids: Vec<Sid> = vec![0];
+                for spex_sid in spex_sids.iter() {
+                    let next_spex_sid = spex.tns_dict[spex_sid]
+                        .iter()
+                        .find(|tn| tn.chex.include(&target_chex))
+                        .unwrap()
+                        .to_sid;
+                    if live_sids.contains(&next_spex_sid) {
+                        next_spex_sids.push(next_spex_sid);
+                    }
+                }
+                next_spex_sids.sort_unstable();
+                next_spex_sids.dedup();
+                let accepts = next_spex_sids
+                    .iter()
+                    .any(|sid| spex.accepts_sids.contains(sid));
+                let skey = if absorb && accepts {
+                    String::from("accept")
+                } else {
+                    next_spex_sids
+                        .iter()
+                        .map(|sid| sid.to_string())
+                        .collect::<Vec<String>>()
+                        .join("-")
+                };
+
+                let new_to_sid: Sid;
+                if let Some(&sid) = sid_dict_by_skey.get(&skey) {
+                    new_to_sid = sid;
+                } else {
+                    new_to_sid = sid_gen.get();
+                    sid_dict_by_skey.insert(skey, new_to_sid);
+                    if accepts {
+                        new_accepts_sids.insert(new_to_sid);
+                    }
+                    if absorb && accepts {
+                        new_tn_list.push(Transition::new(
+                            new_to_sid,
+                            new_to_sid,
+                            Chex::new_whole(),
+                        ));
+                    } else {
+                        stack.push((new_to_sid, next_spex_sids));
+                    }
+                }
+                new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex))
+            }
+        }
+    }
+
+    /// 同じ振る舞いをする状態をまとめた最小のDFA
+    pub fn minimize(&self) -> Self {
+        let mut sids: Vec<Sid> = self.tns_dict.keys().cloned().collect();
+        sids.sort_unstable();
+        let mut class_by_sid: HashMap<Sid, usize> = sids
+            .iter()
+            .map(|sid| (*sid, self.accepts_sids.contains(sid) as usize))
+            .collect();
+        let mut class_len = 0;
+        loop {
+            let mut class_by_key: HashMap<(usize, GroupedTns), usize> = HashMap::new();
+            let mut next_class_by_sid: HashMap<Sid, usize> = HashMap::new();
+            for sid in &sids {
+                let key = (class_by_sid[sid], self.grouped_tns(*sid, &class_by_sid));
+                let len = class_by_key.len();
+                next_class_by_sid.insert(*sid, *class_by_key.entry(key).or_insert(len));
+            }
+            class_by_sid = next_class_by_sid;
+            if class_by_key.len() == class_len {
+                break;
+            }
+            class_len = class_by_key.len();
+        }
+
+        // 開始状態の属する組を0にする
+        let start_class = class_by_sid[&0];
+        let new_sid = |class: usize| -> Sid {
+            if class == start_class {
+                0
+            } else if class < start_class {
+                class as Sid + 1
+            } else {
+                class as Sid
+            }
+        };
+        let mut new_tn_list: Vec<Transition> = vec![];
+        let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
+        let mut done: HashSet<usize> = HashSet::new();
+        for sid in &sids {
+            let class = class_by_sid[sid];
+            if !done.insert(class) {
+                continue;
+            }
+            if self.accepts_sids.contains(sid) {
+                new_accepts_sids.insert(new_sid(class));
+            }
+            for (to_class, ranges) in self.grouped_tns(*sid, &class_by_sid) {
+                new_tn_list.push(Transition::new(
+                    new_sid(class),
+                    new_sid(to_class),
+                    Chex::from_ranges(ranges, true),
+                ));
+            }
+        }
+
+        Spex::new(new_tn_list, new_accepts_sids)
+    }
+
+    /// 遷移を行き先の組ごとにまとめる
+    fn grouped_tns(
+        &self,
+        sid: Sid,
+        class_by_sid: &HashMap<Sid, usize>,
+    ) -> Vec<(usize, Vec<Range>)> {
+        let mut chex_by_class: HashMap<usize, Chex> = HashMap::new();
+        for tn in &self.tns_dict[&sid] {
+            let chex = chex_by_class
+                .entry(class_by_sid[&tn.to_sid])
+                .or_insert_with(Chex::new_blank);
+            *chex = &*chex | &tn.chex;
+        }
+        let mut ret: GroupedTns = chex_by_class
+            .into_iter()
+            .map(|(class, chex)| (class, chex.include_ranges()))
+            .collect();
+        ret.sort_unstable();
+
+        ret
+    }
+
+    /// 状態除去で、builder::spexに戻すと等しいspexになる式を作る
+    pub fn to_expression(&self) -> String {
+        match self.eliminate() {
+            Some(re) => to_source(&re.to_node()),
+            None => to_source(&get_inc_chex(&[], Span::default())),
+        }
+    }
+
+    /// 状態除去で得た式。空集合ならNone
+    /// 式が短くなるように、除去したときに増える式の長さが小さい状態から除く
+    pub(crate) fn eliminate(&self) -> Option<Re> {
+        let spex = self.minimize();
+        let live_sids = spex.live_sids();
+        if !live_sids.contains(&0) {
+            return None;
+        }
+
+        let mut edges: HashMap<(Sid, Sid), Re> = HashMap::new();
+        let add_edge = |edges: &mut HashMap<(Sid, Sid), Re>, key: (Sid, Sid), re: Re| {
+            let re = match edges.remove(&key) {
+                Some(prev) => Re::alt(prev, re),
+                None => re,
+            };
+            edges.insert(key, re);
+        };
+        add_edge(&mut edges, (RE_START, 0), Re::Eps);
+        let mut sids: Vec<Sid> = live_sids.into_iter().collect();
+        sids.sort_unstable();
+        for sid in &sids {
+            for tn in &spex.tns_dict[sid] {
+                if sids.contains(&tn.to_sid) {
+                    add_edge(&mut edges, (*sid, tn.to_sid), Re::Chex(tn.chex.clone()));
+                }
+            }
+            if spex.accepts_sids.contains(sid) {
+                add_edge(&mut edges, (*sid, RE_FINAL), Re::Eps);
+            }
+        }
+
+        while !sids.is_empty() {
+            let (i, _) = sids
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, sid)| elimination_weight(&edges, **sid))
+                .unwrap();
+            let sid = sids.remove(i);
+            let loop_re = edges.remove(&(sid, sid));
+            let ins = take_edges(&mut edges, |(_, to_sid)| to_sid == sid);
+            let outs = take_edges(&mut edges, |(fr_sid, _)| fr_sid == sid);
+            for ((fr_sid, _), in_re) in &ins {
+                for ((_, to_sid), out_re) in &outs {
+                    let mut re = in_re.clone();
+                    if let Some(loop_re) = &loop_re {
+                        re = Re::cat(re, Re::star(loop_re.clone()));
+                    }
+                    re = Re::cat(re, out_re.clone());
+                    add_edge(&mut edges, (*fr_sid, *to_sid), re);
+                }
+            }
+        }
+
+        edges.remove(&(RE_START, RE_FINAL))
+    }
+
+    /// inputを読んだときの状態の移り変わり
+    pub fn run(&self, input: &str) -> Run {
+        let live_sids = self.live_sids();
+        let mut steps = vec![];
+        let mut sid = 0;
+        for char in input.chars() {
+            let to_sid = self.tns_dict[&sid]
+                .iter()
+                .find(|tn| tn.chex.contains(char))
+                .unwrap()
+                .to_sid;
+            steps.push((sid, to_sid, char));
+            sid = to_sid;
+        }
+        let accepted = self.accepts_sids.contains(&sid);
+        let fault = match steps
+            .iter()
+            .position(|(_, to_sid, _)| !live_sids.contains(to_sid))
+        {
+            Some(i) => Some(i),
+            None if accepted => None,
+            None => steps.iter().rposition(|(fr_sid, to_sid, _)| {
+                self.accepts_sids.contains(fr_sid) && !self.accepts_sids.contains(to_sid)
+            }),
+        };
+
+        Run {
+            steps,
+            accepted,
+            fault,
+        }
+    }
+
+    pub fn mermaid(&self) -> String {
+        self.mermaid_with(&RenderOptions::default())
+    }
+
+    /// 開始状態を黒く塗り、受理状態の枠を赤くする。状態と遷移はsidの順に並べる
+    pub fn mermaid_with(&self, options: &RenderOptions) -> String {
+        self.render_mermaid(options, None)
+    }
+
+    /// inputを読んだときに通る状態と遷移を青く塗り、遷移に何文字目で通ったかを書く
+    /// Run::faultの遷移とその行き先はオレンジにする
+    pub fn mermaid_run(&self, input: &str, options: &RenderOptions) -> String {
+        self.render_mermaid(options, Some(&self.run(input)))
+    }
+
+    fn render_mermaid(&self, options: &RenderOptions, run: Option<&Run>) -> String {
+        let (sids, edges) = self.drawn(options.hide_dead, options.merge_edges, run);
+        let direction = match options.direction {
+            Direction::LeftRight => "LR",
+            Direction::TopDown => "TD",
+        };
+        let mut ret = format!("```mermaid\ngraph {}\n", direction);
+        for sid in &sids {
+            let label = if options.state_labels {
+                sid.to_string()
+            } else {
+                String::from(" ")
+            };
+            if *sid == 0 {
+                ret += &format!("    0(({}))\n", label);
+            } else {
+                ret += &format!("    {}({})\n", sid, label);
+            }
+        }
+
+        for sid in &sids {
+            let mut style = vec![];
+            match visit(run, *sid) {
+                _ if *sid == 0 => style.push("fill:#000"),
+                Visit::Fault => style.push("fill:#fd7e14"),
+                Visit::Passed => style.push("fill:#9ec5fe"),
+                Visit::None => {}
+            }
+            if *sid == 0 && options.state_labels {
+                style.push("color:#fff");
+            }
+            if self.accepts_sids.contains(sid) {
+                style.push("stroke:#dc3545,stroke-width:4px");
+            } else if *sid == 0 {
+                style.push("stroke-width:0px");
+            }
+            if !style.is_empty() {
+                ret += &format!("    style {} {}\n", sid, style.join(","));
+            }
+        }
+
+        let mut link_styles = String::new();
+        for (i, edge) in edges.iter().enumerate() {
+            let label = edge.chex.to_string().replace('"', "#quot;") + &step_numbers(&edge.steps);
+            ret += &format!("    {} -- \"{}\" --> {}\n", edge.fr_sid, label, edge.to_sid);
+            if edge.fault {
+                link_styles += &format!("    linkStyle {} stroke:#fd7e14,stroke-width:3px\n", i);
+            } else if !edge.steps.is_empty() {
+                link_styles += &format!("    linkStyle {} stroke:#0d6efd,stroke-width:3px\n", i);
+            }
+        }
+        ret += &link_styles;
+
+        ret += "```";
+
+        ret
+    }
+
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(&RenderOptions::default())
+    }
+
+    /// Graphvizの有向グラフ。mermaidと同じく開始状態を黒く塗り、受理状態は二重丸にする
+    pub fn to_dot_with(&self, options: &RenderOptions) -> String {
+        self.render_dot(options, None)
+    }
+
+    /// mermaid_runと同じく、inputを読んだときに通る状態と遷移を塗る
+    pub fn to_dot_run(&self, input: &str, options: &RenderOptions) -> String {
+        self.render_dot(options, Some(&self.run(input)))
+    }
+
+    fn render_dot(&self, options: &RenderOptions, run: Option<&Run>) -> String {
+        let (sids, edges) = self.drawn(options.hide_dead, options.merge_edges, run);
+        let direction = match options.direction {
+            Direction::LeftRight => "LR",
+            Direction::TopDown => "TB",
+        };
+        // ラベルを付けなければ状態の名前のsidが書かれる
+        let label = if options.state_labels {
+            ""
+        } else {
+            ", label=\"\""
+        };
+        let mut ret = format!(
+            "digraph spex {{\n    rankdir={};\n    node [shape=circle{}];\n",
+            direction, label
+        );
+        for sid in &sids {
+            let mut attrs = vec![];
+            match visit(run, *sid) {
+                _ if *sid == 0 => attrs.push("style=filled, fillcolor=black"),
+                Visit::Fault => attrs.push("style=filled, fillcolor=\"#fd7e14\""),
+                Visit::Passed => attrs.push("style=filled, fillcolor=\"#9ec5fe\""),
+                Visit::None => {}
+            }
+            if *sid == 0 && options.state_labels {
+                attrs.push("fontcolor=white");
+            }
+            if self.accepts_sids.contains(sid) {
+                attrs.push("shape=doublecircle");
+            }
+            if attrs.is_empty() {
+                ret += &format!("    {};\n", sid);
+            } else {
+                ret += &format!("    {} [{}];\n", sid, attrs.join(", "));
+            }
+        }
+
+        for edge in edges {
+            let label = edge
+                .chex
+                .to_string()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                + &step_numbers(&edge.steps);
+            let color = if edge.fault {
+                ", color=\"#fd7e14\", penwidth=2"
+            } else if !edge.steps.is_empty() {
+                ", color=\"#0d6efd\", penwidth=2"
+            } else {
+                ""
+            };
+            ret += &format!(
+                "    {} -> {} [label=\"{}\"{}];\n",
+                edge.fr_sid, edge.to_sid, label, color
+            );
+        }
+        ret += "}\n";
+
+        ret
+    }
+
+    /// 図に描く状態と遷移。どちらもsidの昇順
+    /// hide_deadなら受理状態に到達できない状態を除き、merge_edgesなら同じ状態の組の間の遷移をまとめる
+    /// runが通る状態は受理状態に到達できなくても描く
+    fn drawn(
+        &self,
+        hide_dead: bool,
+        merge_edges: bool,
+        run: Option<&Run>,
+    ) -> (Vec<Sid>, Vec<Edge>) {
+        let live_sids = self.live_sids();
+        let shown = |sid: &Sid| {
+            !hide_dead || *sid == 0 || live_sids.contains(sid) || visit(run, *sid) != Visit::None
+        };
+        let mut sids: Vec<Sid> = self.tns_dict.keys().copied().filter(shown).collect();
+        sids.sort_unstable();
+
+        let mut edges: Vec<Edge> = vec![];
+        for sid in &sids {
+            let mut tns: Vec<&Transition> = self.tns_dict[sid]
+                .iter()
+                .filter(|tn| shown(&tn.to_sid))
+                .collect();
+            tns.sort_by_key(|tn| (tn.to_sid, tn.chex.ranges.clone(), tn.chex.include_flg));
+            for tn in tns {
+                match edges.last_mut() {
+                    Some(edge)
+                        if merge_edges && edge.fr_sid == tn.fr_sid && edge.to_sid == tn.to_sid =>
+                    {
+                        edge.chex = &edge.chex | &tn.chex
+                    }
+                    _ => edges.push(Edge {
+                        fr_sid: tn.fr_sid,
+                        to_sid: tn.to_sid,
+                        chex: tn.chex.clone(),
+                        steps: vec![],
+                        fault: false,
+                    }),
+                }
+            }
+        }
+
+        let steps = run.map(|run| run.steps.as_slice()).unwrap_or_default();
+        for (i, (fr_sid, to_sid, char)) in steps.iter().enumerate() {
+            if let Some(edge) = edges.iter_mut().find(|edge| {
+                edge.fr_sid == *fr_sid && edge.to_sid == *to_sid && edge.chex.contains(*char)
+            }) {
+                edge.steps.push(i + 1);
+                edge.fault |= run.and_then(|run| run.fault) == Some(i);
+            }
+        }
+
+        (sids, edges)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn calc_and_or(
+        new_fr_sid: Sid,
+        new_tn_list: &mut Vec<Transition>,
+        new_accepts_sids: &mut HashSet<Sid>,
+        spex1: &Self,
+        spex2: &Self,
+        spex1_sid: Sid,
+        spex2_sid: Sid,
+        sid_gen: &mut SidGen,
+        sid_dict_by_skey: &mut HashMap<String, Sid>,
+        ope_kind: usize,
+    ) {
+        let mut stack = vec![(new_fr_sid, spex1_sid, spex2_sid)];
+        while let Some((new_fr_sid, spex1_sid, spex2_sid)) = stack.pop() {
+            if sid_gen.exhausted() {
+                break;
+            }
+            let mut chex_pattern = vec![Chex::new_whole()];
+            let mut tmp_chex_pattern: Vec<Chex> = vec![];
+            for ref target_chex in chex_pattern {
+                for tn in &spex1.tns_dict[&spex1_sid] {
+                    let and_chex = target_chex & &tn.chex;
+                    if !&and_chex.blank() {
+                        tmp_chex_pattern.push(and_chex);
+                    }
+                }
+            }
+            chex_pattern = tmp_chex_pattern;
+
+            tmp_chex_pattern = vec![];
+            for ref target_chex in chex_pattern {
+                for tn in &spex2.tns_dict[&spex2_sid] {
+                    let and_chex = target_chex & &tn.chex;
+                    if !&and_chex.blank() {
+                        tmp_chex_pattern.push(and_chex);
+                    }
+                }
+            }
+            chex_pattern = tmp_chex_pattern;
+
+            for target_chex in chex_pattern {
+                // 2-1
+                // 必ず見つかる
+                let next_spex1_sid = spex1.tns_dict[&spex1_sid]
+                    .iter()
+                    .find(|tn| tn.chex.include(&target_chex))
+                    .unwrap()
+                    .to_sid;
+
+                let next_spex2_sid = spex2.tns_dict[&spex2_sid]
+                    .iter()
+                    .find(|tn| tn.chex.include(&target_chex))
+                    .unwrap()
+                    .to_sid;
+
+                // 2-2
+                let skey = format!("{}/{}", next_spex1_sid, next_spex2_sid);
+                let new_to_sid: Sid;
+                if let Some(&sid) = sid_dict_by_skey.get(&skey) {
+                    // 2-2-1
+                    new_to_sid = sid;
+                } else {
+                    // 2-2-2
+                    // 2-2-2-1
+                    new_to_sid = sid_gen.get();
+
+                    // 2-2-2-2
+                    sid_dict_by_skey.insert(skey, new_to_sid);
+                    // 2-2-2-3
+                    if ope_kind == 0 {
+                        if spex1.accepts_sids.contains(&next_spex1_sid)
+                            || spex2.accepts_sids.contains(&next_spex2_sid)
+                        {
+                            new_accepts_sids.insert(new_to_sid);
+                        }
+                    } else if ope_kind == 1 {
+                        if spex1.accepts_sids.contains(&next_spex1_sid)
+                            && spex2.accepts_sids.contains(&next_spex2_sid)
+                        {
+                            new_accepts_sids.insert(new_to_sid);
+                        }
+                    } else {
+                        unreachable!()
+                    }
+
+                    // 2-2-2-4
+                    stack.push((new_to_sid, next_spex1_sid, next_spex2_sid));
+                }
+                // 2-3
+                new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex));
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn calc_concat(
+        new_fr_sid: Sid,
+        new_tn_list: &mut Vec<Transition>,
+        new_accepts_sids: &mut HashSet<Sid>,
+        spex1: &Self,
+        spex2: &Self,
+        spex1_sid: Sid,
+        spex2_sids: HashSet<Sid>,
+        sid_gen: &mut SidGen,
+        sid_dict_by_skey: &mut HashMap<String, Sid>,
+    ) {
+        let mut stack = vec![(new_fr_sid, spex1_sid, spex2_sids)];
+        while let Some((new_fr_sid, spex1_sid, spex2_sids)) = stack.pop() {
+            if sid_gen.exhausted() {
+                break;
+            }
+            let mut chex_pattern = vec![Chex::new_whole()];
+            let mut tmp_chex_pattern: Vec<Chex> = vec![];
+            for ref target_chex in chex_pattern {
+                for tn in &spex1.tns_dict[&spex1_sid] {
+                    let and_chex = target_chex & &tn.chex;
+                    if !&and_chex.blank() {
+                        tmp_chex_pattern.push(and_chex);
+                    }
+                }
+            }
+            chex_pattern = tmp_chex_pattern;
+
+            if spex1.accepts_sids.contains(&spex1_sid) {
+                tmp_chex_pattern = vec![];
+                for ref target_chex in chex_pattern {
+                    for tn in spex2.tns_dict.get(&0).unwrap() {
+                        let and_chex = target_chex & &tn.chex;
+                        if !&and_chex.blank() {
+                            tmp_chex_pattern.push(and_chex);
+                        }
+                    }
+                }
+                chex_pattern = tmp_chex_pattern;
+            }
+
+            for spex2_sid in &spex2_sids {
+                tmp_chex_pattern = vec![];
+                for ref target_chex in chex_pattern {
+                    for tn in spex2.tns_dict.get(spex2_sid).unwrap() {
+                        let and_chex = target_chex & &tn.chex;
+                        if !&and_chex.blank() {
+                            tmp_chex_pattern.push(and_chex);
+                        }
+                    }
+                }
+                chex_pattern = tmp_chex_pattern;
+            }
+
+            // 2
+            //let mut next_spex1_sid: Sid;
+            for target_chex in chex_pattern {
+                // 2-1
+                let next_spex1_sid = spex1.tns_dict[&spex1_sid]
+                    .iter()
+                    .find(|tn| tn.chex.include(&target_chex))
+                    .unwrap()
+                    .to_sid;
+
+                let mut next_spex2_sids: HashSet<Sid> = HashSet::new();
+
+                if spex1.accepts_sids.contains(&spex1_sid) {
+                    // spex1が受理状態の場合は、spex2の最初の遷移を考慮する
+                    for tn in spex2.tns_dict.get(&0).unwrap() {
+                        if tn.chex.include(&target_chex) {
+                            next_spex2_sids.insert(tn.to_sid);
+                            break;
+                        }
+                    }
+                }
+                for spex2_sid in &spex2_sids {
+                    for tn in spex2.tns_dict.get(spex2_sid).unwrap() {
+                        if tn.chex.include(&target_chex) {
+                            next_spex2_sids.insert(tn.to_sid);
+                            break;
+                        }
+                    }
+                }
+
+                // 2-2
+                let skey = if next_spex2_sids.is_empty() {
+                    format!("{}/", next_spex1_sid)
+                } else {
+                    let mut vec_sid: Vec<Sid> = next_spex2_sids.clone().into_iter().collect();
+                    vec_sid.sort();
+                    format!(
+                        "{}/{}",
+                        next_spex1_sid,
+                        vec_sid
+                            .iter()
+                            .map(|x| x.to_string())
+                            .collect::<Vec<String>>()
+                            .join("-")
+                    )
+                };
+
+                let new_to_sid: Sid;
+                if let Some(&sid) = sid_dict_by_skey.get(&skey) {
+                    // 2-2-1
+                    new_to_sid = sid;
+                } else {
+                    // 2-2-2
+                    // 2-2-2-1
+                    new_to_sid = sid_gen.get();
+                    // 2-2-2-2
+                    sid_dict_by_skey.insert(skey, new_to_sid);
+                    // 2-2-2-3
+                    for next_spex2_sid in &next_spex2_sids {
+                        if spex2.accepts_sids.contains(next_spex2_sid) {
+                            new_accepts_sids.insert(new_to_sid);
+                            break;
+                        }
+                    }
+                    // spex2が空文字列を受理する場合は、spex1の受理状態でも受理する
+                    if spex1.accepts_sids.contains(&next_spex1_sid)
+                        && spex2.accepts_sids.contains(&0)
+                    {
+                        new_accepts_sids.insert(new_to_sid);
+                    }
+                    // 2-2-2-4
+                    stack.push((new_to_sid, next_spex1_sid, next_spex2_sids));
+                }
+                new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex));
+            }
+        }
+    }
+
+    pub fn calc_repeat(
+        new_fr_sid: Sid,
+        new_tn_list: &mut Vec<Transition>,
+        new_accepts_sids: &mut HashSet<Sid>,
+        spex: &Self,
+        spex_sids: HashSet<Sid>,
+        sid_gen: &mut SidGen,
+        sid_dict_by_skey: &mut HashMap<String, Sid>,
+    ) {
+        let mut stack = vec![(new_fr_sid, spex_sids)];
+        while let Some((new_fr_sid, spex_sids)) = stack.pop() {
+            if sid_gen.exhausted() {
+                break;
+            }
+            let mut chex_pattern = vec![Chex::new_whole()];
+
+            for spex_sid in spex_sids.iter() {
+                let mut tmp_chex_pattern: Vec<Chex> = vec![];
+                for target_chex in &chex_pattern {
+                    for tn in &spex.tns_dict[spex_sid] {
+                        let and_chex = target_chex & &tn.chex;
+                        if !&and_chex.blank() {
+                            tmp_chex_pattern.push(and_chex);
+                        }
+                    }
+                }
+                chex_pattern = tmp_chex_pattern.clone();
+            }
+            for spex_sid in spex_sids.iter() {
+                if spex.accepts_sids.contains(spex_sid) {
+                    let mut tmp_chex_pattern: Vec<Chex> = vec![];
+                    for target_chex in &chex_pattern {
+                        for tn in spex.tns_dict.get(&0).unwrap() {
+                            let and_chex = target_chex & &tn.chex;
+                            if !&and_chex.blank() {
+                                tmp_chex_pattern.push(and_chex);
+                            }
+                        }
+                    }
+                    chex_pattern = tmp_chex_pattern.clone();
+                    break;
+                }
+            }
+            // 2
+            for target_chex in chex_pattern {
+                let mut next_spex_sids: HashSet<Sid> = HashSet::new();
+                for spex_sid in spex_sids.iter() {
+                    if spex.accepts_sids.contains(spex_sid) {
+                        // 受理状態の場合は、最初の遷移を考慮する
+                        for tn in spex.tns_dict.get(&0).unwrap() {
+                            if tn.chex.include(&target_chex) {
+                                next_spex_sids.insert(tn.to_sid);
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                }
+                for spex_sid in spex_sids.iter() {
+                    for tn in spex.tns_dict.get(spex_sid).unwrap() {
+                        if tn.chex.include(&target_chex) {
+                            next_spex_sids.insert(tn.to_sid);
+                            break;
+                        }
+                    }
+                }
+                let mut vec_sid: Vec<Sid> = next_spex_sids.clone().into_iter().collect();
+                vec_sid.sort();
+                let skey = vec_sid
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join("-");
+
+                let new_to_sid: Sid;
+                if let Some(&sid) = sid_dict_by_skey.get(&skey) {
+                    new_to_sid = sid;
+                } else {
+                    // 2-2-2
+                    // 2-2-2-1
+                    new_to_sid = sid_gen.get();
+                    // 2-2-2-2
+                    sid_dict_by_skey.insert(skey, new_to_sid);
+                    // 2-2-2-3
+                    for next_spex_sid in &next_spex_sids {
+                        if spex.accepts_sids.contains(next_spex_sid) {
+                            new_accepts_sids.insert(new_to_sid);
+                            break;
+                        }
+                    }
+                    // 2-2-2-4
+                    stack.push((new_to_sid, next_spex_sids));
+                }
+                new_tn_list.push(Transition::new(new_fr_sid, new_to_sid, target_chex))
+            }
+        }
+    }
+}
+
+impl Display for Spex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_expression())
+    }
+}
+
+impl PartialEq for Spex {
+    fn eq(&self, other: &Self) -> bool {
+        if self.kind == other.kind {
+            if self.kind != Kind::Other {
+                true
+            } else {
+                self.include(other) && other.include(self)
+            }
+        } else {
+            false
+        }
+    }
+}
+
+impl Not for &Spex {
+    type Output = Spex;
+
+    fn not(self) -> Self::Output {
+        let mut tn_list: Vec<Transition> = vec![];
+        let mut accepts_sids: HashSet<Sid> = HashSet::new();
+
+        for sid in self.tns_dict.keys() {
+            if !self.accepts_sids.contains(sid) {
+                accepts_sids.insert(*sid);
+            }
+            tn_list.extend_from_slice(&self.tns_dict[sid]);
+        }
+
+        Spex::new(tn_list, accepts_sids)
+    }
+}
+
+impl BitOr for &Spex {
+    type Output = Spex;
+
+    fn bitor(self, other: Self) -> Self::Output {
+        self.or_within(other, None).unwrap()
+    }
+}
 
 impl BitAnd for &Spex {
     type Output = Spex;
 
     fn bitand(self, other: Self) -> Self::Output {
-        match self.kind {
-            Kind::Blank => self.clone(),
-            Kind::Whole => other.clone(),
-            Kind::Other => match other.kind {
-                Kind::Blank => other.clone(),
-                Kind::Whole => self.clone(),
-                Kind::Other => {
-                    let mut new_tn_list: Vec<Transition> = vec![];
-                    let mut new_accepts_sids: HashSet<Sid> = HashSet::new();
-                    Spex::calc_and_or(
-                        0,
-                        &mut new_tn_list,
-                        &mut new_accepts_sids,
-                        &self,
-                        &other,
-                        0,
-                        0,
-                        &mut SidGen::new(),
-                        &mut HashMap::new(),
-                        1,
-                    );
-
-                    Spex::new(new_tn_list, new_accepts_sids)
-                }
-            },
-        }
+        self.and_within(other, None).unwrap()
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Transition {
-    fr_sid: Sid,
-    to_sid: Sid,
-    chex: Chex,
+    pub(crate) fr_sid: Sid,
+    pub(crate) to_sid: Sid,
+    pub(crate) chex: Chex,
 }
 
 impl Transition {
@@ -634,18 +1562,227 @@ impl Transition {
     }
 }
 
+/// 状態除去で使う、仮の開始状態と受理状態
+const RE_START: Sid = -1;
+const RE_FINAL: Sid = -2;
+
+/// 条件に合う辺を取り除いて返す(順序は辺の両端の昇順)
+fn take_edges(
+    edges: &mut HashMap<(Sid, Sid), Re>,
+    pred: impl Fn((Sid, Sid)) -> bool,
+) -> Vec<((Sid, Sid), Re)> {
+    let mut keys: Vec<(Sid, Sid)> = edges.keys().filter(|key| pred(**key)).cloned().collect();
+    keys.sort_unstable();
+    keys.into_iter()
+        .map(|key| (key, edges.remove(&key).unwrap()))
+        .collect()
+}
+
+/// 状態を除去したときに増える式の長さの目安
+fn elimination_weight(edges: &HashMap<(Sid, Sid), Re>, sid: Sid) -> usize {
+    let ins: Vec<usize> = edges
+        .iter()
+        .filter(|((fr_sid, to_sid), _)| *to_sid == sid && *fr_sid != sid)
+        .map(|(_, re)| re.size())
+        .collect();
+    let outs: Vec<usize> = edges
+        .iter()
+        .filter(|((fr_sid, to_sid), _)| *fr_sid == sid && *to_sid != sid)
+        .map(|(_, re)| re.size())
+        .collect();
+    let loop_size = edges.get(&(sid, sid)).map_or(0, Re::size);
+
+    ins.iter().sum::<usize>() * outs.len().saturating_sub(1)
+        + outs.iter().sum::<usize>() * ins.len().saturating_sub(1)
+        + loop_size * ins.len() * outs.len()
+}
+
+/// 状態除去の途中の式
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Re {
+    /// 空文字列
+    Eps,
+    Chex(Chex),
+    Cat(Vec<Re>),
+    Alt(Vec<Re>),
+    Star(Box<Re>),
+}
+
+impl Re {
+    fn cat(a: Re, b: Re) -> Re {
+        let mut items = vec![];
+        for re in [a, b] {
+            match re {
+                Re::Eps => {}
+                Re::Cat(inner) => items.extend(inner),
+                re => items.push(re),
+            }
+        }
+        match items.len() {
+            0 => Re::Eps,
+            1 => items.pop().unwrap(),
+            _ => Re::Cat(items),
+        }
+    }
+
+    /// 文字集合どうしはまとめ、同じ式は一つにする
+    fn alt(a: Re, b: Re) -> Re {
+        let mut items: Vec<Re> = vec![];
+        for re in [a, b] {
+            let inner = match re {
+                Re::Alt(inner) => inner,
+                re => vec![re],
+            };
+            for re in inner {
+                if let Re::Chex(chex) = &re {
+                    if let Some(Re::Chex(other)) =
+                        items.iter_mut().find(|item| matches!(item, Re::Chex(_)))
+                    {
+                        *other = &*other | chex;
+                        continue;
+                    }
+                }
+                if !items.contains(&re) {
+                    items.push(re);
+                }
+            }
+        }
+        if items.iter().any(|item| matches!(item, Re::Star(_))) {
+            items.retain(|item| item != &Re::Eps);
+        }
+        match items.len() {
+            1 => items.pop().unwrap(),
+            _ => Re::Alt(items),
+        }
+    }
+
+    fn star(re: Re) -> Re {
+        match re {
+            Re::Eps | Re::Star(_) => re,
+            Re::Alt(items) if items.contains(&Re::Eps) => {
+                let items: Vec<Re> = items.into_iter().filter(|item| item != &Re::Eps).collect();
+                Re::star(items.into_iter().reduce(Re::alt).unwrap())
+            }
+            re => Re::Star(Box::new(re)),
+        }
+    }
+
+    /// 連接の要素の x x* と x* x を (x, true) にまとめる
+    pub(crate) fn fuse_plus(items: &[Re]) -> Vec<(&Re, bool)> {
+        let mut ret = vec![];
+        let mut i = 0;
+        while i < items.len() {
+            match (&items[i], items.get(i + 1)) {
+                (Re::Star(star), Some(re)) | (re, Some(Re::Star(star))) if star.as_ref() == re => {
+                    ret.push((re, true));
+                    i += 2;
+                }
+                (re, _) => {
+                    ret.push((re, false));
+                    i += 1;
+                }
+            }
+        }
+
+        ret
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Re::Eps => 1,
+            Re::Chex(chex) => chex.str.chars().count(),
+            Re::Cat(items) => items.iter().map(Re::size).sum(),
+            Re::Alt(items) => items.iter().map(Re::size).sum::<usize>() + items.len() - 1,
+            Re::Star(re) => re.size() + 1,
+        }
+    }
+
+    fn to_node(&self) -> Node {
+        let span = Span::default();
+        match self {
+            Re::Eps => get_empty_node(span),
+            // 範囲の少ない方で書く
+            Re::Chex(chex) => {
+                let included = chex.include_ranges();
+                let excluded = (!chex).include_ranges();
+                if excluded.len() < included.len() {
+                    get_exc_chex(&excluded, span)
+                } else {
+                    get_inc_chex(&included, span)
+                }
+            }
+            Re::Cat(items) => {
+                let mut nodes: Vec<Node> = Re::fuse_plus(items)
+                    .into_iter()
+                    .map(|(re, plus)| {
+                        if plus {
+                            get_repeat_node(re.to_node(), span)
+                        } else {
+                            re.to_node()
+                        }
+                    })
+                    .collect();
+                if nodes.len() == 1 {
+                    nodes.pop().unwrap()
+                } else {
+                    get_concat_node(nodes)
+                }
+            }
+            Re::Alt(items) => {
+                let optional = items.contains(&Re::Eps);
+                let mut nodes: Vec<Node> = items
+                    .iter()
+                    .filter(|item| *item != &Re::Eps)
+                    .map(Re::to_node)
+                    .collect();
+                let last = nodes.pop().unwrap();
+                let node = nodes
+                    .into_iter()
+                    .rev()
+                    .fold(last, |right, left| get_or_node(&left, &right));
+                if optional {
+                    get_optional_node(node, span)
+                } else {
+                    node
+                }
+            }
+            Re::Star(re) => get_star_node(re.to_node(), span),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SidGen {
     pub sid: Sid,
+    limit: Option<Sid>,
+}
+
+impl Default for SidGen {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SidGen {
     pub fn new() -> Self {
-        Self { sid: 0 }
+        Self::with_limit(None)
+    }
+
+    /// 作る状態の数の上限をlimitにする
+    pub fn with_limit(limit: Option<usize>) -> Self {
+        Self {
+            sid: 0,
+            limit: limit.map(|limit| limit as Sid),
+        }
     }
 
     pub fn get(&mut self) -> isize {
         self.sid += 1;
         self.sid
     }
+
+    /// 状態数の上限を超えたか
+    pub fn exhausted(&self) -> bool {
+        self.limit.is_some_and(|limit| self.sid >= limit)
+    }
 }
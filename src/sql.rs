@@ -0,0 +1,470 @@
+use std::fmt::{self, Display};
+
+use crate::builder::build_spex;
+use crate::chex::{Chex, Kind};
+use crate::parser::{
+    get_bounded_node, get_concat_node, get_empty_node, get_exc_chex, get_inc_chex,
+    get_optional_node, get_or_node, get_repeat_node, get_star_node, Node, Span,
+};
+use crate::spex::Spex;
+
+/// SQLのパターンを読み書きするときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlError {
+    Syntax {
+        message: String,
+        span: Span,
+    },
+    /// LIKEのパターンでは書けない集合
+    NotRepresentable,
+}
+
+impl Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SqlError::Syntax { message, span } => {
+                write!(f, "{}..{}: {}", span.start, span.end, message)
+            }
+            SqlError::NotRepresentable => write!(f, "not representable as a LIKE pattern"),
+        }
+    }
+}
+
+impl std::error::Error for SqlError {}
+
+/// LIKEのパターンの一文字分
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LikeItem {
+    Char(char),
+    /// `_`
+    Any,
+}
+
+impl Spex {
+    /// `x LIKE pattern ESCAPE escape` が真になる文字列の集合
+    pub fn from_like(pattern: &str, escape: Option<char>) -> Result<Spex, SqlError> {
+        Ok(build_spex(parse_like(pattern, escape)?))
+    }
+
+    /// `x SIMILAR TO pattern ESCAPE escape` が真になる文字列の集合
+    pub fn from_similar(pattern: &str, escape: Option<char>) -> Result<Spex, SqlError> {
+        Ok(build_spex(parse_similar(pattern, escape)?))
+    }
+
+    /// 同じ集合を表すLIKEのパターン。`%` `_` とescapeはescapeを前に付けて書く
+    /// `%` `_` と文字の並びで書けなければNotRepresentable
+    pub fn to_like(&self, escape: Option<char>) -> Result<String, SqlError> {
+        // 最短の文字列の各位置が `_` か一文字かで骨組みが決まる
+        let len = self
+            .example()
+            .ok_or(SqlError::NotRepresentable)?
+            .chars()
+            .count();
+        let any = Spex::build_by_chex(&Chex::new_whole());
+        let items = (self & &any.bounded(len, Some(len)))
+            .chain()
+            .ok_or(SqlError::NotRepresentable)?
+            .iter()
+            .map(|chex| match chex.kind {
+                Kind::Whole => Ok(LikeItem::Any),
+                _ if chex.include_flg && chex.len == 1 => Ok(LikeItem::Char(chex.ranges[0].0)),
+                _ => Err(SqlError::NotRepresentable),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // すべての隙間に `%` を置き、含む関係が崩れない限り前から外していく
+        let mut percents = vec![true; len + 1];
+        for i in 0..=len {
+            percents[i] = false;
+            if !like_spex(&items, &percents).include(self) {
+                percents[i] = true;
+            }
+        }
+        if like_spex(&items, &percents) != *self {
+            return Err(SqlError::NotRepresentable);
+        }
+
+        let mut pattern = String::new();
+        for (i, percent) in percents.iter().enumerate() {
+            if *percent {
+                pattern.push('%');
+            }
+            match items.get(i) {
+                Some(LikeItem::Any) => pattern.push('_'),
+                Some(LikeItem::Char(char)) => {
+                    if matches!(char, '%' | '_') || Some(*char) == escape {
+                        pattern.push(escape.ok_or(SqlError::NotRepresentable)?);
+                    }
+                    pattern.push(*char);
+                }
+                None => {}
+            }
+        }
+
+        Ok(pattern)
+    }
+}
+
+/// 骨組みとpercents[i]なら位置iの前に `%` を置いたパターンの集合
+fn like_spex(items: &[LikeItem], percents: &[bool]) -> Spex {
+    let any = Spex::build_by_chex(&Chex::new_whole());
+    let mut spex = Spex::empty_string();
+    for (i, percent) in percents.iter().enumerate() {
+        if *percent {
+            spex = spex.concat(&any.star());
+        }
+        match items.get(i) {
+            Some(LikeItem::Any) => spex = spex.concat(&any),
+            Some(LikeItem::Char(char)) => {
+                spex = spex.concat(&Spex::build_by_chex(&Chex::new(vec![*char], true)))
+            }
+            None => {}
+        }
+    }
+
+    spex
+}
+
+/// LIKEのパターンを木にする
+/// `%` は任意の文字列、`_` は任意の一文字、escapeは次の文字そのもの
+pub fn parse_like(pattern: &str, escape: Option<char>) -> Result<Node, SqlError> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut nodes: Vec<Node> = vec![];
+    let mut pos = 0;
+    while pos < chars.len() {
+        let char = chars[pos];
+        let span = Span::new(pos, pos + 1);
+        let node = if Some(char) == escape {
+            let char = *chars.get(pos + 1).ok_or(SqlError::Syntax {
+                message: "pattern must not end with the escape character".to_string(),
+                span,
+            })?;
+            pos += 1;
+            get_inc_chex(&[(char, char)], Span::new(pos - 1, pos + 1))
+        } else {
+            match char {
+                '%' => get_star_node(get_exc_chex(&[], span), span),
+                '_' => get_exc_chex(&[], span),
+                _ => get_inc_chex(&[(char, char)], span),
+            }
+        };
+        nodes.push(node);
+        pos += 1;
+    }
+
+    Ok(match nodes.len() {
+        0 => get_empty_node(Span::default()),
+        1 => nodes.pop().unwrap(),
+        _ => get_concat_node(nodes),
+    })
+}
+
+/// SIMILAR TOのパターンを木にする
+/// LIKEの `%` `_` に加えて `|` `*` `+` `?` `{m,n}` `(...)` `[...]` を使う。`.` `^` `$` は文字そのもの
+pub fn parse_similar(pattern: &str, escape: Option<char>) -> Result<Node, SqlError> {
+    let mut parser = SimilarParser {
+        chars: pattern.chars().collect(),
+        pos: 0,
+        escape,
+    };
+    let node = parser.parse_alt()?;
+    match parser.peek() {
+        None => Ok(node),
+        Some(_) => Err(parser.syntax("unmatched )", 1)),
+    }
+}
+
+struct SimilarParser {
+    chars: Vec<char>,
+    pos: usize,
+    escape: Option<char>,
+}
+
+impl SimilarParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn syntax(&self, message: &str, len: usize) -> SqlError {
+        SqlError::Syntax {
+            message: message.to_string(),
+            span: Span::new(self.pos, (self.pos + len).min(self.chars.len())),
+        }
+    }
+
+    fn parse_alt(&mut self) -> Result<Node, SqlError> {
+        let mut nodes = vec![self.parse_concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            nodes.push(self.parse_concat()?);
+        }
+        let last = nodes.pop().unwrap();
+
+        Ok(nodes
+            .iter()
+            .rev()
+            .fold(last, |right, left| get_or_node(left, &right)))
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, SqlError> {
+        let start = self.pos;
+        let mut nodes: Vec<Node> = vec![];
+        while let Some(char) = self.peek() {
+            if char == '|' || char == ')' {
+                break;
+            }
+            nodes.push(self.parse_quantified()?);
+        }
+
+        Ok(match nodes.len() {
+            0 => get_empty_node(Span::new(start, self.pos)),
+            1 => nodes.pop().unwrap(),
+            _ => get_concat_node(nodes),
+        })
+    }
+
+    fn parse_quantified(&mut self) -> Result<Node, SqlError> {
+        let mut node = self.parse_atom()?;
+        loop {
+            let start = node.span().start;
+            node = match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    get_star_node(node, Span::new(start, self.pos))
+                }
+                Some('+') => {
+                    self.pos += 1;
+                    get_repeat_node(node, Span::new(start, self.pos))
+                }
+                Some('?') => {
+                    self.pos += 1;
+                    get_optional_node(node, Span::new(start, self.pos))
+                }
+                Some('{') => {
+                    let (min, max) = self.parse_count()?;
+                    get_bounded_node(node, min, max, Span::new(start, self.pos))
+                }
+                _ => return Ok(node),
+            };
+        }
+    }
+
+    /// `{n}` `{n,}` `{n,m}`
+    fn parse_count(&mut self) -> Result<(usize, Option<usize>), SqlError> {
+        let len = match self.chars[self.pos..].iter().position(|char| *char == '}') {
+            Some(len) => len,
+            None => return Err(self.syntax("} expected", 1)),
+        };
+        let body: String = self.chars[self.pos + 1..self.pos + len].iter().collect();
+        let mut nums = body.splitn(2, ',').map(|num| num.trim().parse::<usize>());
+        let count = match (nums.next(), nums.next()) {
+            (Some(Ok(min)), None) => (min, Some(min)),
+            (Some(Ok(min)), Some(Ok(max))) if min <= max => (min, Some(max)),
+            (Some(Ok(min)), Some(Err(_))) if body.trim_end().ends_with(',') => (min, None),
+            _ => return Err(self.syntax("invalid repetition", len + 1)),
+        };
+        self.pos += len + 1;
+
+        Ok(count)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, SqlError> {
+        let start = self.pos;
+        let char = self.peek().unwrap();
+        if Some(char) == self.escape {
+            let char = self.escaped()?;
+            return Ok(get_inc_chex(&[(char, char)], Span::new(start, self.pos)));
+        }
+        let node = match char {
+            '(' => {
+                self.pos += 1;
+                let node = self.parse_alt()?;
+                if self.peek() != Some(')') {
+                    return Err(self.syntax(") expected", 1));
+                }
+                self.pos += 1;
+                return Ok(node);
+            }
+            '[' => return self.parse_class(),
+            '%' => get_star_node(
+                get_exc_chex(&[], Span::new(start, start + 1)),
+                Span::new(start, start + 1),
+            ),
+            '_' => get_exc_chex(&[], Span::new(start, start + 1)),
+            '*' | '+' | '?' | '{' => return Err(self.syntax("nothing to repeat", 1)),
+            _ => get_inc_chex(&[(char, char)], Span::new(start, start + 1)),
+        };
+        self.pos += 1;
+
+        Ok(node)
+    }
+
+    /// escapeとその次の文字を読み、次の文字を返す
+    fn escaped(&mut self) -> Result<char, SqlError> {
+        match self.chars.get(self.pos + 1) {
+            Some(char) => {
+                self.pos += 2;
+                Ok(*char)
+            }
+            None => Err(self.syntax("pattern must not end with the escape character", 1)),
+        }
+    }
+
+    /// `[abc]` `[^a-z]` `[[:digit:]]`。先頭の `]` は文字
+    fn parse_class(&mut self) -> Result<Node, SqlError> {
+        let start = self.pos;
+        self.pos += 1;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut chex = Chex::new_blank();
+        let mut first = true;
+        loop {
+            let char = match self.peek() {
+                Some(char) => char,
+                None => {
+                    return Err(SqlError::Syntax {
+                        message: "] expected".to_string(),
+                        span: Span::new(start, self.pos),
+                    })
+                }
+            };
+            if char == ']' && !first {
+                self.pos += 1;
+                break;
+            }
+            first = false;
+
+            if let Some(posix) = self.parse_posix()? {
+                chex = &chex | &posix;
+                continue;
+            }
+            let lo = self.class_char()?;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let hi = self.class_char()?;
+                if lo > hi {
+                    return Err(SqlError::Syntax {
+                        message: "invalid range".to_string(),
+                        span: Span::new(self.pos - 3, self.pos),
+                    });
+                }
+                chex = &chex | &Chex::from_ranges(vec![(lo, hi)], true);
+            } else {
+                chex = &chex | &Chex::new(vec![lo], true);
+            }
+        }
+
+        let span = Span::new(start, self.pos);
+        Ok(if negated {
+            get_exc_chex(&chex.include_ranges(), span)
+        } else {
+            get_inc_chex(&chex.include_ranges(), span)
+        })
+    }
+
+    /// `[:name:]`
+    fn parse_posix(&mut self) -> Result<Option<Chex>, SqlError> {
+        if self.chars.get(self.pos..self.pos + 2) != Some(&['[', ':']) {
+            return Ok(None);
+        }
+        let end = match (self.pos + 2..self.chars.len().saturating_sub(1))
+            .find(|i| self.chars[*i] == ':' && self.chars[*i + 1] == ']')
+        {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let name: String = self.chars[self.pos + 2..end].iter().collect();
+        match Chex::posix(&name) {
+            Some(chex) => {
+                self.pos = end + 2;
+                Ok(Some(chex))
+            }
+            None => Err(self.syntax("unknown character class", end + 2 - self.pos)),
+        }
+    }
+
+    fn class_char(&mut self) -> Result<char, SqlError> {
+        let char = self.peek().unwrap();
+        if Some(char) == self.escape {
+            return self.escaped();
+        }
+        self.pos += 1;
+
+        Ok(char)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::spex;
+
+    fn like(pattern: &str) -> Spex {
+        Spex::from_like(pattern, Some('\\')).unwrap()
+    }
+
+    fn similar(pattern: &str) -> Spex {
+        Spex::from_similar(pattern, Some('\\')).unwrap()
+    }
+
+    #[test]
+    fn sql_like() {
+        assert_eq!(like("a%"), spex("a.*"));
+        assert_eq!(like("_b%c"), spex(".b.*c"));
+        assert_eq!(like("100\\%"), spex("100%"));
+        assert_eq!(like(""), spex("!.+"));
+        assert_eq!(Spex::from_like("a!_b", Some('!')).unwrap(), spex("a_b"));
+        assert_eq!(Spex::from_like("a\\_", None).unwrap(), spex("a\\\\."));
+        assert_eq!(
+            Spex::from_like("ab\\", Some('\\')),
+            Err(SqlError::Syntax {
+                message: "pattern must not end with the escape character".to_string(),
+                span: Span::new(2, 3),
+            })
+        );
+    }
+
+    #[test]
+    fn sql_similar() {
+        assert_eq!(similar("(a|b)+%"), spex("[ab]+.*"));
+        assert_eq!(similar("a.b_"), spex("a\\.b."));
+        assert_eq!(similar("[^0-9]{2,3}x?"), spex("[^0-9]{2,3}x?"));
+        assert_eq!(similar("[[:digit:]a]*"), spex("[0-9a]*"));
+        assert_eq!(similar("\\%|"), spex("%?"));
+        assert_eq!(
+            Spex::from_similar("(ab", Some('\\'))
+                .unwrap_err()
+                .to_string(),
+            "3..3: ) expected"
+        );
+        assert_eq!(
+            Spex::from_similar("a)", Some('\\'))
+                .unwrap_err()
+                .to_string(),
+            "1..2: unmatched )"
+        );
+    }
+
+    #[test]
+    fn sql_to_like() {
+        let to_like = |src: &str| spex(src).to_like(Some('\\'));
+        assert_eq!(to_like("a.*"), Ok("a%".to_string()));
+        assert_eq!(to_like(".*ab.*"), Ok("%ab%".to_string()));
+        assert_eq!(to_like(".+"), Ok("_%".to_string()));
+        assert_eq!(to_like(".*a.b"), Ok("%a_b".to_string()));
+        assert_eq!(to_like("50%_.*"), Ok("50\\%\\_%".to_string()));
+        assert_eq!(to_like("!.+"), Ok("".to_string()));
+        assert_eq!(to_like("a+"), Err(SqlError::NotRepresentable));
+        assert_eq!(to_like("[ab]"), Err(SqlError::NotRepresentable));
+        assert_eq!(to_like("a|b.*"), Err(SqlError::NotRepresentable));
+        assert_eq!(to_like("a&b"), Err(SqlError::NotRepresentable));
+        assert_eq!(spex("%").to_like(None), Err(SqlError::NotRepresentable));
+
+        for pattern in ["%a%b_", "a_%c", "%%x", "_"] {
+            let spex1 = like(pattern);
+            assert_eq!(like(&spex1.to_like(Some('\\')).unwrap()), spex1);
+        }
+    }
+}
@@ -1,16 +1,30 @@
-#[derive(Debug, Clone)]
+use std::fmt::{self, Display};
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Token {
-    SP_S, // 文字列集合_開始
-    SP_E, // 文字列集合_終了
-    AND,  // 文字列集合_論理積
-    OR,   // 文字列集合_論理和
-    INVT, // 文字列集合_否定
-    REPT, // 文字列集合_1文字以上の繰返し
-    CH_S, // 文字集合_開始
-    CH_E, // 文字集合_終了
-    WHOL, // 文字集合_全集合
-    DENY, // 文字集合_補集合
-    ESC,  // エスケープ
+    SP_S,  // 文字列集合_開始
+    SP_E,  // 文字列集合_終了
+    AND,   // 文字列集合_論理積
+    OR,    // 文字列集合_論理和
+    INVT,  // 文字列集合_否定
+    CONT,  // 文字列集合_部分文字列として含む
+    HEAD,  // 文字列集合_前方一致(CONTの直後のみ)
+    TAIL,  // 文字列集合_後方一致(CONTの直後のみ)
+    REPT,  // 文字列集合_1文字以上の繰返し
+    OPT,   // 文字列集合_0回または1回
+    STAR,  // 文字列集合_0回以上の繰返し
+    CNT_S, // 文字列集合_回数指定_開始
+    CNT_E, // 文字列集合_回数指定_終了
+    CH_S,  // 文字集合_開始
+    CH_E,  // 文字集合_終了
+    WHOL,  // 文字集合_全集合
+    DENY,  // 文字集合_補集合
+    RANGE, // 文字集合_範囲
+    DEF,   // 定義
+    SEP,   // 定義の区切り
+    ESC,   // エスケープ
+    CMNT,  // コメント(verboseモードのみ)
 }
 
 impl Token {
@@ -21,27 +35,214 @@ impl Token {
             Token::AND => '&',
             Token::OR => '|',
             Token::INVT => '!',
+            Token::CONT => '~',
+            Token::HEAD => '<',
+            Token::TAIL => '>',
             Token::REPT => '+',
+            Token::OPT => '?',
+            Token::STAR => '*',
+            Token::CNT_S => '{',
+            Token::CNT_E => '}',
             Token::CH_S => '[',
             Token::CH_E => ']',
             Token::WHOL => '.',
             Token::DENY => '^',
+            Token::RANGE => '-',
+            Token::DEF => '=',
+            Token::SEP => ';',
             Token::ESC => '\\',
+            Token::CMNT => '#',
         }
     }
 
-    pub fn escapes<'a>() -> [char; 10] {
+    pub fn all() -> [Token; 22] {
+        [
+            Token::SP_S,
+            Token::SP_E,
+            Token::AND,
+            Token::OR,
+            Token::INVT,
+            Token::CONT,
+            Token::HEAD,
+            Token::TAIL,
+            Token::REPT,
+            Token::OPT,
+            Token::STAR,
+            Token::CNT_S,
+            Token::CNT_E,
+            Token::CH_S,
+            Token::CH_E,
+            Token::WHOL,
+            Token::DENY,
+            Token::RANGE,
+            Token::DEF,
+            Token::SEP,
+            Token::ESC,
+            Token::CMNT,
+        ]
+    }
+
+    /// 単独で構文になり、文字として使うにはエスケープが要るトークン
+    pub fn escape_tokens() -> [Token; 18] {
+        [
+            Token::AND,
+            Token::OR,
+            Token::INVT,
+            Token::CONT,
+            Token::CH_S,
+            Token::CH_E,
+            Token::WHOL,
+            Token::DENY,
+            Token::RANGE,
+            Token::REPT,
+            Token::OPT,
+            Token::STAR,
+            Token::CNT_S,
+            Token::CNT_E,
+            Token::SP_S,
+            Token::SP_E,
+            Token::DEF,
+            Token::SEP,
+        ]
+    }
+
+    /// 文字集合の中でも構文になるトークン。ほかは文字集合の中では文字になる
+    pub fn chex_tokens() -> [Token; 5] {
         [
-            Token::AND.value(),
-            Token::OR.value(),
-            Token::INVT.value(),
-            Token::CH_S.value(),
-            Token::CH_E.value(),
-            Token::WHOL.value(),
-            Token::DENY.value(),
-            Token::REPT.value(),
-            Token::SP_S.value(),
-            Token::SP_E.value(),
+            Token::CH_S,
+            Token::CH_E,
+            Token::WHOL,
+            Token::DENY,
+            Token::RANGE,
         ]
     }
+
+    pub fn escapes() -> [char; 18] {
+        Token::escape_tokens().map(|token| token.value())
+    }
+
+    /// 文字をspexのソース表現に変換する
+    pub fn escape(char: char) -> String {
+        if char == Token::ESC.value() || Token::escapes().contains(&char) {
+            format!("{}{}", Token::ESC.value(), char)
+        } else {
+            char.to_string()
+        }
+    }
+
+    /// tokenizeで得たトークンを文字に戻す
+    /// 1文字にならないトークンならNone
+    pub fn unescape(token: &str) -> Option<char> {
+        let mut chars = token.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some(esc), Some(char), None) if esc == Token::ESC.value() => Some(char),
+            (Some(char), None, _) => Some(char),
+            _ => None,
+        }
+    }
+}
+
+/// Dialect::withで文字を置き換えられないときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum DialectError {
+    /// 英数字・空白・`,` `:` は構文に使えない
+    Reserved { char: char },
+    /// 他のトークンが使っている
+    Duplicate { char: char, token: Token },
+}
+
+impl Display for DialectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DialectError::Reserved { char } => write!(f, "{} cannot be a syntax char", char),
+            DialectError::Duplicate { char, token } => {
+                write!(f, "{} is already used by {:?}", char, token)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DialectError {}
+
+/// 構文に使う文字の表
+///
+/// tokenizeはこの表で読んだソースを既定の表のトークンに置き換えるので、
+/// パーサーや表示は既定の表だけを扱えばよい
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dialect {
+    values: Vec<(Token, char)>,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self {
+            values: Token::all()
+                .iter()
+                .map(|token| (*token, token.value()))
+                .collect(),
+        }
+    }
+}
+
+impl Dialect {
+    /// `&` `|` `!` の代わりに `∧` `∨` `¬` を使う
+    /// CSVのような `|` 区切りやシェルの `!` をエスケープせずに書ける
+    pub fn logical() -> Self {
+        Self::default()
+            .with(Token::AND, '∧')
+            .and_then(|dialect| dialect.with(Token::OR, '∨'))
+            .and_then(|dialect| dialect.with(Token::INVT, '¬'))
+            .expect("logical chars are valid")
+    }
+
+    /// tokenの文字を置き換える
+    /// 構文に使えない文字や、他のトークンが使っている文字ならErr
+    pub fn with(mut self, token: Token, char: char) -> Result<Self, DialectError> {
+        if char.is_alphanumeric() || char.is_whitespace() || char == ',' || char == ':' {
+            return Err(DialectError::Reserved { char });
+        }
+        if let Some((other, _)) = self
+            .values
+            .iter()
+            .find(|(other, value)| *other != token && *value == char)
+        {
+            return Err(DialectError::Duplicate {
+                char,
+                token: *other,
+            });
+        }
+        for (other, value) in self.values.iter_mut() {
+            if *other == token {
+                *value = char;
+            }
+        }
+
+        Ok(self)
+    }
+
+    pub fn value(&self, token: Token) -> char {
+        self.values
+            .iter()
+            .find(|(other, _)| *other == token)
+            .unwrap()
+            .1
+    }
+
+    /// 文字が表すトークン
+    pub fn token(&self, char: char) -> Option<Token> {
+        self.values
+            .iter()
+            .find(|(_, value)| *value == char)
+            .map(|(token, _)| *token)
+    }
+
+    /// 構文の一部として読んだ文字を既定の表の文字にする
+    /// 既定の表では構文だがこの表では構文でない文字はNone
+    pub fn to_default(&self, char: char) -> Option<char> {
+        match self.token(char) {
+            Some(token) => Some(token.value()),
+            None if Token::all().iter().any(|token| token.value() == char) => None,
+            None => Some(char),
+        }
+    }
 }